@@ -0,0 +1,385 @@
+//! Caches for embeddings, answers and indexed nodes
+
+use std::{
+    ops::Range,
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context as _, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use swiftide::{traits::EmbeddingModel, Embeddings};
+
+use crate::{
+    index::content_hash,
+    query::{QueryContext, QueryResult},
+};
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum CacheKind {
+    None,
+    Redis,
+}
+
+/// Prefix for the keys in the Redis cache, so a resumed index only skips files that were
+/// indexed with the same settings
+///
+/// Swiftide keys cached nodes by their path and content only. Without the chunk range and
+/// models in the prefix, changing either would silently skip every file indexed before. The
+/// overlap is only added if there is any, so caches from before overlap existed stay valid.
+pub fn cache_prefix(
+    collection_name: &str,
+    chunk_range: &Range<usize>,
+    chunk_overlap: usize,
+    embed_model: &str,
+    prompt_model: &str,
+) -> String {
+    let overlap = if chunk_overlap > 0 {
+        format!("+{chunk_overlap}")
+    } else {
+        String::new()
+    };
+
+    format!(
+        "{collection_name}:{}-{}{overlap}:{embed_model}:{prompt_model}",
+        chunk_range.start, chunk_range.end
+    )
+}
+
+/// Embeddings kept on disk across runs, keyed by the model and the sha256 of the text
+///
+/// Unlike the Redis cache, which skips files that were indexed already, this avoids paying for
+/// identical chunks again when indexing into another collection or without Redis.
+#[derive(Clone, Debug)]
+pub struct EmbeddingCache(sled::Db);
+
+impl EmbeddingCache {
+    pub fn open(path: &Path) -> Result<Self> {
+        let db = sled::open(path)
+            .with_context(|| format!("Failed to open embedding cache {}", path.display()))?;
+        Ok(Self(db))
+    }
+
+    pub fn clear(&self) -> Result<()> {
+        self.0.clear()?;
+        self.0.flush()?;
+        Ok(())
+    }
+
+    fn key(model: &str, text: &str) -> String {
+        format!("{model}:{}", content_hash(text))
+    }
+
+    fn get(&self, model: &str, text: &str) -> Result<Option<Vec<f32>>> {
+        Ok(self.0.get(Self::key(model, text))?.map(|bytes| {
+            bytes
+                .chunks_exact(4)
+                .map(|float| f32::from_le_bytes([float[0], float[1], float[2], float[3]]))
+                .collect()
+        }))
+    }
+
+    fn insert(&self, model: &str, text: &str, embedding: &[f32]) -> Result<()> {
+        let bytes = embedding
+            .iter()
+            .flat_map(|float| float.to_le_bytes())
+            .collect::<Vec<_>>();
+        self.0.insert(Self::key(model, text), bytes)?;
+        Ok(())
+    }
+}
+
+/// Answers kept on disk across runs, to not pay for asking the same question twice
+///
+/// Keyed by the question with its case and whitespace normalized, every setting that changes what
+/// is retrieved or how it is answered and the scope, which should change when the collections are
+/// reindexed so the answers from before are not served.
+#[derive(Clone, Debug)]
+pub struct QueryCache {
+    db: sled::Db,
+    scope: String,
+    /// Answers older than this are asked again
+    ttl: Option<Duration>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedAnswer<R> {
+    /// Seconds since the unix epoch
+    cached_at: u64,
+    result: R,
+}
+
+impl QueryCache {
+    pub fn open(path: &Path, scope: String, ttl: Option<Duration>) -> Result<Self> {
+        let db = sled::open(path)
+            .with_context(|| format!("Failed to open query cache {}", path.display()))?;
+        Ok(Self { db, scope, ttl })
+    }
+
+    pub(crate) fn key(&self, context: &QueryContext, question: &str) -> String {
+        let question = question
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_lowercase();
+        let path_filter = &context.path_filter;
+        let rerank = match (context.rerank, &context.cohere_rerank) {
+            (false, _) => "none",
+            (true, Some(cohere)) => cohere.model.as_str(),
+            (true, None) => "prompt model",
+        };
+        let settings = [
+            format!("question={question}"),
+            format!("prompt_model={}", context.prompt_model),
+            format!("top_k={}", context.top_k),
+            format!("language={}", context.language),
+            format!("query_mode={:?}", context.query_mode),
+            format!("num_subquestions={}", context.num_subquestions),
+            format!(
+                "path_filter={:?},{:?}",
+                path_filter.glob.as_ref().map(|glob| glob.glob().glob()),
+                path_filter.extension
+            ),
+            format!("rerank={rerank}"),
+            format!("min_score={:?}", context.min_score),
+            format!("dedup_threshold={}", context.dedup_threshold),
+            format!(
+                "max_context_tokens={:?}",
+                context
+                    .token_budget
+                    .as_ref()
+                    .map(|budget| budget.max_tokens)
+            ),
+            format!("on_empty={:?}", context.on_empty),
+            format!("answer_template={}", context.answer_template.0),
+            format!("verbatim_code={}", context.verbatim_code),
+            format!("answer_format={:?}", context.answer_format),
+            format!("max_answer_words={:?}", context.max_answer_words),
+            format!("explain={}", context.explain),
+        ];
+        content_hash(&format!("{}\n{}", self.scope, settings.join("\n")))
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Result<Option<QueryResult>> {
+        let Some(bytes) = self.db.get(key)? else {
+            return Ok(None);
+        };
+        let cached: CachedAnswer<QueryResult> = serde_json::from_slice(&bytes)?;
+
+        let age = Duration::from_secs(unix_seconds().saturating_sub(cached.cached_at));
+        if self.ttl.is_some_and(|ttl| age > ttl) {
+            self.db.remove(key)?;
+            return Ok(None);
+        }
+
+        Ok(Some(cached.result))
+    }
+
+    pub(crate) fn insert(&self, key: &str, result: &QueryResult) -> Result<()> {
+        let cached = CachedAnswer {
+            cached_at: unix_seconds(),
+            result,
+        };
+        self.db.insert(key, serde_json::to_vec(&cached)?)?;
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+fn unix_seconds() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[derive(Debug)]
+pub(crate) struct CachedEmbeddings {
+    pub(crate) inner: Arc<dyn EmbeddingModel>,
+    pub(crate) model: String,
+    pub(crate) cache: EmbeddingCache,
+}
+
+#[async_trait]
+impl EmbeddingModel for CachedEmbeddings {
+    async fn embed(&self, input: Vec<String>) -> Result<Embeddings> {
+        let mut embeddings = input
+            .iter()
+            .map(|text| self.cache.get(&self.model, text))
+            .collect::<Result<Vec<_>>>()?;
+
+        let missing = input
+            .iter()
+            .zip(&embeddings)
+            .filter(|(_, embedding)| embedding.is_none())
+            .map(|(text, _)| text.clone())
+            .collect::<Vec<_>>();
+        tracing::debug!(
+            hits = input.len() - missing.len(),
+            misses = missing.len(),
+            "Looked up embeddings in the cache"
+        );
+
+        if !missing.is_empty() {
+            let mut embedded = self.inner.embed(missing.clone()).await?.into_iter();
+            for (text, embedding) in input.iter().zip(&mut embeddings) {
+                if embedding.is_none() {
+                    let computed = embedded.next().context("Expected an embedding per text")?;
+                    self.cache.insert(&self.model, text, &computed)?;
+                    *embedding = Some(computed);
+                }
+            }
+            self.cache.0.flush_async().await?;
+        }
+
+        Ok(embeddings.into_iter().flatten().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use swiftide::{prompt::Prompt, traits::SimplePrompt};
+
+    use super::*;
+    use crate::{
+        AnswerFormat, AnswerTemplate, Languages, Llm, OnEmpty, PathFilter, QueryMode,
+        StreamingPrompt, DEFAULT_NUM_SUBQUESTIONS,
+    };
+
+    /// Computing cache keys never calls the LLM
+    #[derive(Debug)]
+    struct NoLlm;
+
+    #[async_trait]
+    impl EmbeddingModel for NoLlm {
+        async fn embed(&self, _input: Vec<String>) -> Result<Embeddings> {
+            unreachable!()
+        }
+    }
+
+    #[async_trait]
+    impl SimplePrompt for NoLlm {
+        async fn prompt(&self, _prompt: Prompt) -> Result<String> {
+            unreachable!()
+        }
+    }
+
+    #[async_trait]
+    impl StreamingPrompt for NoLlm {
+        async fn prompt_streaming(
+            &self,
+            _prompt: Prompt,
+            _on_token: &mut (dyn for<'a> FnMut(&'a str) + Send),
+        ) -> Result<String> {
+            unreachable!()
+        }
+    }
+
+    fn query_context(prompt_model: &str, top_k: u64) -> QueryContext {
+        QueryContext {
+            llm: Llm {
+                embed: Arc::new(NoLlm),
+                prompt: Arc::new(NoLlm),
+                stream: Arc::new(NoLlm),
+            },
+            prompt_model: prompt_model.to_string(),
+            stores: Vec::new(),
+            top_k,
+            language: Languages::Auto,
+            stream: false,
+            rerank: false,
+            dedup_threshold: 1.,
+            token_budget: None,
+            query_mode: QueryMode::Raw,
+            path_filter: PathFilter::new(None, None).unwrap(),
+            answer_template: AnswerTemplate::default(),
+            show_rewrite: false,
+            cohere_rerank: None,
+            explain: false,
+            num_subquestions: DEFAULT_NUM_SUBQUESTIONS,
+            min_score: None,
+            on_empty: OnEmpty::Refuse,
+            query_cache: None,
+            verbatim_code: false,
+            answer_format: AnswerFormat::Prose,
+            max_answer_words: None,
+        }
+    }
+
+    fn result(question: &str) -> QueryResult {
+        QueryResult {
+            question: question.to_string(),
+            subquestions: Vec::new(),
+            hypothetical_answer: None,
+            sources: Vec::new(),
+            answer: "Cached".to_string(),
+            context_found: true,
+            attributions: Vec::new(),
+            unverified_code: Vec::new(),
+            answer_json: None,
+        }
+    }
+
+    #[test]
+    fn query_cache_key_ignores_whitespace_and_case_but_not_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = QueryCache::open(&dir.path().join("a"), "code".to_string(), None).unwrap();
+        let other_scope =
+            QueryCache::open(&dir.path().join("b"), "docs".to_string(), None).unwrap();
+        let context = query_context("gpt-4o", 5);
+        let key = cache.key(&context, "What is  A?");
+
+        assert_eq!(key, cache.key(&context, " what is a?\n"));
+        assert_ne!(key, cache.key(&context, "What is B?"));
+        assert_ne!(
+            key,
+            cache.key(&query_context("gpt-4o-mini", 5), "What is A?")
+        );
+        assert_ne!(key, cache.key(&query_context("gpt-4o", 10), "What is A?"));
+        assert_ne!(key, other_scope.key(&context, "What is A?"));
+    }
+
+    #[test]
+    fn query_cache_forgets_answers_older_than_the_ttl() {
+        let dir = tempfile::tempdir().unwrap();
+        let ttl = Some(Duration::from_secs(60));
+        let cache = QueryCache::open(dir.path(), "code".to_string(), ttl).unwrap();
+
+        cache.insert("fresh", &result("What is a?")).unwrap();
+        let cached = cache.get("fresh").unwrap().unwrap();
+        assert_eq!(cached.answer, "Cached");
+
+        let stale = CachedAnswer {
+            cached_at: unix_seconds() - 61,
+            result: result("What is b?"),
+        };
+        cache
+            .db
+            .insert("stale", serde_json::to_vec(&stale).unwrap())
+            .unwrap();
+        assert!(cache.get("stale").unwrap().is_none());
+        assert!(!cache.db.contains_key("stale").unwrap());
+        assert!(cache.get("missing").unwrap().is_none());
+    }
+
+    #[test]
+    fn cache_prefix_includes_the_chunk_range_and_models() {
+        assert_eq!(
+            cache_prefix("code", &(100..2000), 0, "e", "p"),
+            "code:100-2000:e:p"
+        );
+        assert_ne!(
+            cache_prefix("code", &(100..2000), 0, "e", "p"),
+            cache_prefix("code", &(100..2000), 0, "other", "p")
+        );
+    }
+
+    #[test]
+    fn cache_prefix_adds_overlap_only_when_set() {
+        assert_eq!(
+            cache_prefix("code", &(100..2000), 50, "e", "p"),
+            "code:100-2000+50:e:p"
+        );
+    }
+}
@@ -0,0 +1,215 @@
+//! Checkpoints of the files an index stored, to resume a run that stopped
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{LineWriter, Write as _},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context as _, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use swiftide::{
+    indexing::{IndexingDefaults, IndexingStream, Node},
+    traits::{ChunkerTransformer, WithIndexingDefaults},
+};
+use tokio_stream::StreamExt as _;
+
+use crate::index::content_hash;
+
+/// The files whose chunks have all been stored, to resume an indexing run that was interrupted
+/// or crashed
+///
+/// Every file is appended to the checkpoint as a line of json once its last chunk is stored, so
+/// the checkpoint is usable whenever the run stops. Files are recognized by their path and the
+/// hash of their content, so files changed since are indexed again. Files with a chunk that
+/// failed are never recorded.
+pub struct Checkpoint {
+    done: HashSet<CheckpointEntry>,
+    writer: Mutex<LineWriter<File>>,
+    /// The content hash and the number of chunks still to be stored of the files being indexed
+    pending: Mutex<HashMap<PathBuf, (String, usize)>>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Debug)]
+struct CheckpointEntry {
+    path: PathBuf,
+    content_hash: String,
+}
+
+impl Checkpoint {
+    /// Reads the files recorded by earlier runs, if any, and appends to the file from there
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut done = HashSet::new();
+        if path.exists() {
+            let content = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read checkpoint {}", path.display()))?;
+            // The last line is cut off if the run crashed while writing it
+            done.extend(
+                content
+                    .lines()
+                    .filter_map(|line| serde_json::from_str::<CheckpointEntry>(line).ok()),
+            );
+            tracing::info!(files = done.len(), checkpoint = %path.display(), "Resuming, skipping the files stored already");
+        }
+
+        let file = File::options()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open checkpoint {}", path.display()))?;
+
+        Ok(Self {
+            done,
+            writer: Mutex::new(LineWriter::new(file)),
+            pending: Mutex::default(),
+        })
+    }
+
+    /// Whether the loaded file was stored by an earlier run and has not changed since
+    pub(crate) fn is_done(&self, file: &Node) -> bool {
+        self.done.contains(&CheckpointEntry {
+            path: file.path.clone(),
+            content_hash: content_hash(&file.chunk),
+        })
+    }
+
+    /// Starts tracking a file that was split in `chunks` chunks
+    pub(crate) fn chunked(&self, file: &Node, chunks: usize) {
+        let hash = content_hash(&file.chunk);
+        if chunks == 0 {
+            self.record(&file.path, hash);
+        } else {
+            self.pending
+                .lock()
+                .unwrap()
+                .insert(file.path.clone(), (hash, chunks));
+        }
+    }
+
+    /// Counts a stored chunk, recording its file once all of its chunks are stored
+    pub(crate) fn stored(&self, chunk: &Node) {
+        let mut pending = self.pending.lock().unwrap();
+        let Some((_, remaining)) = pending.get_mut(&chunk.path) else {
+            return;
+        };
+        *remaining -= 1;
+        if *remaining == 0 {
+            if let Some((hash, _)) = pending.remove(&chunk.path) {
+                drop(pending);
+                self.record(&chunk.path, hash);
+            }
+        }
+    }
+
+    fn record(&self, path: &Path, content_hash: String) {
+        let entry = CheckpointEntry {
+            path: path.to_path_buf(),
+            content_hash,
+        };
+        let written = serde_json::to_string(&entry)
+            .map_err(anyhow::Error::from)
+            .and_then(|line| Ok(writeln!(self.writer.lock().unwrap(), "{line}")?));
+        if let Err(err) = written {
+            tracing::warn!(error = %err, path = %path.display(), "Failed to record the file in the checkpoint");
+        }
+    }
+}
+
+/// Tells the [`Checkpoint`] how many chunks a file is split in, so it knows when all of them are
+/// stored
+#[derive(Debug)]
+pub(crate) struct CheckpointFiles<T> {
+    pub(crate) inner: T,
+    pub(crate) checkpoint: Option<Arc<Checkpoint>>,
+}
+
+#[async_trait]
+impl<T: ChunkerTransformer> ChunkerTransformer for CheckpointFiles<T> {
+    async fn transform_node(&self, node: Node) -> IndexingStream {
+        let Some(checkpoint) = &self.checkpoint else {
+            return self.inner.transform_node(node).await;
+        };
+
+        let file = node.clone();
+        let chunks = self
+            .inner
+            .transform_node(node)
+            .await
+            .collect::<Vec<_>>()
+            .await;
+        // Files with a failed chunk are never complete, so they are indexed again on resume
+        if chunks.iter().all(Result::is_ok) {
+            checkpoint.chunked(&file, chunks.len());
+        }
+
+        IndexingStream::iter(chunks)
+    }
+
+    fn concurrency(&self) -> Option<usize> {
+        self.inner.concurrency()
+    }
+
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+}
+
+impl<T: WithIndexingDefaults> WithIndexingDefaults for CheckpointFiles<T> {
+    fn with_indexing_defaults(&mut self, indexing_defaults: IndexingDefaults) {
+        self.inner.with_indexing_defaults(indexing_defaults);
+    }
+}
+
+impl std::fmt::Debug for Checkpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Checkpoint")
+            .field("done", &self.done.len())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checkpoint_records_files_once_all_chunks_are_stored() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("checkpoint.jsonl");
+        let mut file = Node::new("fn a() {}\n\nfn b() {}");
+        file.path = PathBuf::from("src/lib.rs");
+        let mut empty = Node::new("");
+        empty.path = PathBuf::from("src/empty.rs");
+        let mut chunk = Node::new("fn a() {}");
+        chunk.path.clone_from(&file.path);
+
+        let checkpoint = Checkpoint::open(&path).unwrap();
+        checkpoint.chunked(&file, 2);
+        checkpoint.chunked(&empty, 0);
+        checkpoint.stored(&chunk);
+        drop(checkpoint);
+        let checkpoint = Checkpoint::open(&path).unwrap();
+        assert!(!checkpoint.is_done(&file));
+        assert!(checkpoint.is_done(&empty));
+
+        checkpoint.chunked(&file, 2);
+        checkpoint.stored(&chunk);
+        checkpoint.stored(&chunk);
+        drop(checkpoint);
+        // A line cut off by a crash is skipped
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"path\":")
+            .unwrap();
+        let checkpoint = Checkpoint::open(&path).unwrap();
+        assert!(checkpoint.is_done(&file));
+
+        file.chunk.push_str("\n\nfn c() {}");
+        assert!(!checkpoint.is_done(&file));
+    }
+}
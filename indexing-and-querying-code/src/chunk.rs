@@ -0,0 +1,700 @@
+//! Languages and the chunkers splitting files by them
+
+use std::{ops::Range, path::Path, str::FromStr};
+
+use anyhow::{Context as _, Result};
+use async_trait::async_trait;
+use pulldown_cmark::{Event, Parser, Tag, TagEnd};
+use strum::IntoEnumIterator as _;
+use swiftide::{
+    indexing::{
+        transformers::{ChunkCode, ChunkMarkdown},
+        IndexingDefaults, IndexingStream, Node,
+    },
+    integrations::treesitter::SupportedLanguages,
+    traits::{ChunkerTransformer, WithIndexingDefaults},
+};
+use tokio_stream::StreamExt as _;
+
+/// Documentation indexed by default, chunked as markdown
+const DOCS_EXTENSIONS: [&str; 3] = ["md", "txt", "pdf"];
+
+/// The languages of the code to index, files in other languages are chunked as text
+#[derive(Clone, Copy, Debug)]
+pub enum Languages {
+    One(SupportedLanguages),
+    /// Every supported language, detected by the extension of each file
+    Auto,
+}
+
+impl Languages {
+    /// The supported language of a file by its extension, if it is one of these languages
+    pub(crate) fn of_path(self, path: &Path) -> Option<SupportedLanguages> {
+        let ext = path.extension()?;
+        self.iter().find(|language| {
+            language
+                .file_extensions()
+                .iter()
+                .any(|code_ext| ext == *code_ext)
+        })
+    }
+
+    /// The usual extension of files in the language, none for `Auto`
+    pub fn extension(self) -> Option<String> {
+        match self {
+            Languages::One(language) => language
+                .file_extensions()
+                .first()
+                .map(|ext| (*ext).to_string()),
+            Languages::Auto => None,
+        }
+    }
+
+    pub(crate) fn iter(self) -> impl Iterator<Item = SupportedLanguages> {
+        let one = match self {
+            Languages::One(language) => Some(language),
+            Languages::Auto => None,
+        };
+        SupportedLanguages::iter().filter(move |language| one.is_none_or(|one| one == *language))
+    }
+}
+
+/// The chunk size range in characters that suits code in the language
+///
+/// Terse languages say as much in fewer characters, so their chunks are kept smaller to stay
+/// focused, while verbose ones need larger chunks to hold a whole function. The minimum is the
+/// same for all, so the overlap fits in any of them.
+pub fn default_chunk_range(language: SupportedLanguages) -> Range<usize> {
+    match language {
+        SupportedLanguages::Rust => 50..1024,
+        SupportedLanguages::Python | SupportedLanguages::Ruby => 50..768,
+        SupportedLanguages::Typescript | SupportedLanguages::Javascript => 50..1536,
+    }
+}
+
+impl std::fmt::Display for Languages {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Languages::One(language) => language.fmt(f),
+            Languages::Auto => f.write_str("several languages"),
+        }
+    }
+}
+
+/// Parses the language, listing the supported languages if it is not one of them
+///
+/// `auto` selects every supported language.
+pub fn parse_language(language: &str) -> Result<Languages> {
+    if language.eq_ignore_ascii_case("auto") {
+        return Ok(Languages::Auto);
+    }
+
+    SupportedLanguages::from_str(language)
+        .map(Languages::One)
+        .map_err(|_err| {
+            let supported = SupportedLanguages::iter()
+                .map(|language| {
+                    format!(
+                        "  {} ({})",
+                        language.to_string().to_lowercase(),
+                        language.file_extensions().join(", ")
+                    )
+                })
+                .chain(["  auto (detected per file)".to_string()])
+                .collect::<Vec<_>>()
+                .join("\n");
+
+            anyhow::anyhow!("Unsupported language `{language}`, expected one of:\n{supported}")
+        })
+}
+
+/// The language of a single file by its extension, so it can be indexed without naming it
+pub fn language_of_file(path: &Path) -> Result<Languages> {
+    Languages::Auto
+        .of_path(path)
+        .map(Languages::One)
+        .with_context(|| {
+            // Typescript claims the Javascript extensions as well
+            let mut extensions = Vec::<String>::new();
+            for language in SupportedLanguages::iter() {
+                for ext in language.file_extensions() {
+                    if !extensions.iter().any(|known| known == ext) {
+                        extensions.push((*ext).to_string());
+                    }
+                }
+            }
+            let extensions = extensions.join(", ");
+            format!(
+                "{} is not in a supported language, pass --language or index a file with one of \
+                 the extensions {extensions}",
+                path.display()
+            )
+        })
+}
+
+/// The extensions of the files to index
+///
+/// Defaults to the code files of the language and the documentation formats, markdown, plain text
+/// and PDF. `overrides` replaces the defaults, `extra` and `docs` are added on top.
+pub fn resolve_extensions(
+    language: Languages,
+    extra: &[String],
+    docs: &[String],
+    overrides: Option<&[String]>,
+) -> Result<Vec<String>> {
+    let mut extensions = match overrides {
+        Some(overrides) => overrides.to_vec(),
+        None => language
+            .iter()
+            .flat_map(|language| {
+                language
+                    .file_extensions()
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+            })
+            .chain(DOCS_EXTENSIONS.map(str::to_string))
+            .collect(),
+    };
+    extensions.extend(extra.iter().chain(docs).cloned());
+
+    let mut extensions = extensions
+        .iter()
+        .map(|ext| ext.trim().trim_start_matches('.').to_string())
+        .filter(|ext| !ext.is_empty())
+        .collect::<Vec<_>>();
+    extensions.sort();
+    extensions.dedup();
+
+    if extensions.is_empty() {
+        anyhow::bail!(
+            "No file extensions to index, check --extensions, --extra-extensions and --docs-extensions"
+        );
+    }
+
+    Ok(extensions)
+}
+
+/// How markdown and other text is split into chunks
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MarkdownSplit {
+    /// In chunks of the chunk size, preferring to split at headings and paragraphs
+    #[default]
+    Size,
+    /// At every heading up to a level, only splitting sections larger than the chunk size by
+    /// size, and adding the headings a chunk falls under to its metadata
+    Heading,
+}
+
+/// Adds the first and last line of every chunk in its file to its metadata, as `start_line` and
+/// `end_line`, for citing chunks precisely
+///
+/// Chunkers keep no positions, so every chunk is looked up as is in the file it was split from.
+/// Chunks follow each other, so the search continues after the previous chunk. It starts over
+/// from the top if a chunk is not found there, and gives up on chunks not found at all.
+#[derive(Debug)]
+pub(crate) struct LineNumbers<T>(pub(crate) T);
+
+#[async_trait]
+impl<T: ChunkerTransformer> ChunkerTransformer for LineNumbers<T> {
+    async fn transform_node(&self, node: Node) -> IndexingStream {
+        let file = node.chunk.clone();
+        let chunks = self.0.transform_node(node).await.collect::<Vec<_>>().await;
+
+        let mut searched_until = 0;
+        IndexingStream::iter(chunks.into_iter().map(move |chunk| {
+            let mut chunk = chunk?;
+
+            let start = file
+                .get(searched_until..)
+                .and_then(|rest| rest.find(&chunk.chunk))
+                .map(|offset| searched_until + offset)
+                .or_else(|| file.find(&chunk.chunk));
+
+            if let Some(start) = start {
+                searched_until = start + chunk.chunk.len();
+
+                let start_line = file[..start].matches('\n').count() + 1;
+                let end_line = start_line + chunk.chunk.trim_end().matches('\n').count();
+                chunk.metadata.insert("start_line", start_line);
+                chunk.metadata.insert("end_line", end_line);
+            }
+
+            Ok(chunk)
+        }))
+    }
+
+    fn concurrency(&self) -> Option<usize> {
+        self.0.concurrency()
+    }
+
+    fn name(&self) -> &'static str {
+        self.0.name()
+    }
+}
+
+impl<T: WithIndexingDefaults> WithIndexingDefaults for LineNumbers<T> {
+    fn with_indexing_defaults(&mut self, indexing_defaults: IndexingDefaults) {
+        self.0.with_indexing_defaults(indexing_defaults);
+    }
+}
+
+/// Logs the number of chunks and tokens of every file once it is chunked, at debug level or at
+/// info level if `info` is set
+///
+/// Tokens are counted with the tokenizer of GPT-4, as an estimate of what the file costs to
+/// enrich and embed. Nothing is counted if the log would not be shown.
+#[derive(Debug)]
+pub(crate) struct LogPerFile<T> {
+    pub(crate) inner: T,
+    pub(crate) info: bool,
+}
+
+#[async_trait]
+impl<T: ChunkerTransformer> ChunkerTransformer for LogPerFile<T> {
+    async fn transform_node(&self, node: Node) -> IndexingStream {
+        let enabled = if self.info {
+            tracing::enabled!(tracing::Level::INFO)
+        } else {
+            tracing::enabled!(tracing::Level::DEBUG)
+        };
+
+        let path = node.path.clone();
+        let chunks = self.inner.transform_node(node).await;
+        if !enabled {
+            return chunks;
+        }
+
+        let chunks = chunks.collect::<Vec<_>>().await;
+        let bpe = tiktoken_rs::cl100k_base_singleton();
+        let tokens = chunks
+            .iter()
+            .flatten()
+            .map(|chunk| bpe.encode_with_special_tokens(&chunk.chunk).len())
+            .sum::<usize>();
+
+        let path = path.display();
+        let count = chunks.len();
+        if self.info {
+            tracing::info!(%path, chunks = count, tokens, "Chunked file");
+        } else {
+            tracing::debug!(%path, chunks = count, tokens, "Chunked file");
+        }
+
+        IndexingStream::iter(chunks)
+    }
+
+    fn concurrency(&self) -> Option<usize> {
+        self.inner.concurrency()
+    }
+
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+}
+
+impl<T: WithIndexingDefaults> WithIndexingDefaults for LogPerFile<T> {
+    fn with_indexing_defaults(&mut self, indexing_defaults: IndexingDefaults) {
+        self.inner.with_indexing_defaults(indexing_defaults);
+    }
+}
+
+/// Repeats the end of the previous chunk of the file at the start of every chunk, so a concept
+/// split across chunks is found in both
+///
+/// The repeated end starts at a line if it spans one, and the start line of the chunk moves up
+/// to where it starts. Swiftide's chunkers have no overlap of their own.
+#[derive(Debug)]
+pub(crate) struct Overlapping<T> {
+    pub(crate) inner: T,
+    /// Maximum number of characters repeated
+    pub(crate) overlap: usize,
+}
+
+#[async_trait]
+impl<T: ChunkerTransformer> ChunkerTransformer for Overlapping<T> {
+    async fn transform_node(&self, node: Node) -> IndexingStream {
+        let chunks = self.inner.transform_node(node).await;
+        if self.overlap == 0 {
+            return chunks;
+        }
+
+        let chunks = chunks.collect::<Vec<_>>().await;
+        let overlap = self.overlap;
+        let mut previous: Option<(String, Option<u64>)> = None;
+        IndexingStream::iter(chunks.into_iter().map(move |chunk| {
+            let mut chunk = chunk?;
+            let original = chunk.chunk.clone();
+
+            if let Some((previous_chunk, previous_end_line)) = previous.take() {
+                let start = previous_chunk
+                    .char_indices()
+                    .rev()
+                    .nth(overlap - 1)
+                    .map_or(0, |(index, _)| index);
+                let mut tail = &previous_chunk[start..];
+                if let Some(newline) = tail.find('\n') {
+                    if !tail[newline + 1..].trim().is_empty() {
+                        tail = &tail[newline + 1..];
+                    }
+                }
+                let tail = tail.trim_end();
+
+                if !tail.is_empty() {
+                    chunk.chunk = format!("{tail}\n{}", chunk.chunk);
+                    // Only chunks found in the file have lines to move
+                    if let (Some(end_line), true) = (
+                        previous_end_line,
+                        chunk.metadata.get("start_line").is_some(),
+                    ) {
+                        let start_line = end_line.saturating_sub(tail.matches('\n').count() as u64);
+                        chunk.metadata.insert("start_line", start_line);
+                    }
+                }
+            }
+
+            let end_line = chunk
+                .metadata
+                .get("end_line")
+                .and_then(serde_json::Value::as_u64);
+            previous = Some((original, end_line));
+
+            Ok(chunk)
+        }))
+    }
+
+    fn concurrency(&self) -> Option<usize> {
+        self.inner.concurrency()
+    }
+
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+}
+
+impl<T: WithIndexingDefaults> WithIndexingDefaults for Overlapping<T> {
+    fn with_indexing_defaults(&mut self, indexing_defaults: IndexingDefaults) {
+        self.inner.with_indexing_defaults(indexing_defaults);
+    }
+}
+
+/// Chunks code with the tree-sitter grammar of the language of each file
+#[derive(Debug)]
+pub(crate) struct ChunkCodeByLanguage(Vec<(SupportedLanguages, ChunkCode)>);
+
+impl ChunkCodeByLanguage {
+    pub(crate) fn new(
+        languages: Languages,
+        chunk_range: impl Fn(SupportedLanguages) -> Range<usize>,
+    ) -> Result<Self> {
+        Ok(Self(
+            languages
+                .iter()
+                .map(|language| {
+                    let chunker = ChunkCode::try_for_language_and_chunk_size(
+                        language,
+                        chunk_range(language),
+                    )?;
+                    Ok((language, chunker))
+                })
+                .collect::<Result<_>>()?,
+        ))
+    }
+}
+
+#[async_trait]
+impl ChunkerTransformer for ChunkCodeByLanguage {
+    async fn transform_node(&self, node: Node) -> IndexingStream {
+        // Only code in one of the languages is routed here
+        let chunker = self
+            .0
+            .iter()
+            .find(|(language, _)| Languages::One(*language).of_path(&node.path).is_some());
+
+        match chunker {
+            Some((_, chunker)) => chunker.transform_node(node).await,
+            None => IndexingStream::iter([Err(anyhow::anyhow!(
+                "No grammar to chunk {} with",
+                node.path.display()
+            ))]),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "ChunkCode"
+    }
+}
+
+impl WithIndexingDefaults for ChunkCodeByLanguage {}
+
+/// Chunks markdown by size, or at its headings up to a level first
+///
+/// Split at headings, sections within the chunk range are kept whole and only larger sections
+/// are chunked by size. Sections smaller than the range are dropped, like [`ChunkMarkdown`]
+/// drops small chunks. Every chunk of a section gets the headings it falls under as
+/// `heading_path`, like `Install > From source`.
+#[derive(Debug)]
+pub(crate) struct ChunkMarkdownSections {
+    chunk_range: Range<usize>,
+    heading_level: Option<usize>,
+    chunker: ChunkMarkdown,
+}
+
+impl ChunkMarkdownSections {
+    pub(crate) fn new(chunk_range: Range<usize>, heading_level: Option<usize>) -> Self {
+        Self {
+            chunker: ChunkMarkdown::from_chunk_range(chunk_range.clone()),
+            chunk_range,
+            heading_level,
+        }
+    }
+}
+
+#[async_trait]
+impl ChunkerTransformer for ChunkMarkdownSections {
+    async fn transform_node(&self, node: Node) -> IndexingStream {
+        let Some(heading_level) = self.heading_level else {
+            return self.chunker.transform_node(node).await;
+        };
+
+        let mut chunks = Vec::new();
+        for (headings, section) in markdown_sections(&node.chunk, heading_level) {
+            let section = section.trim();
+            let section_chunks = if section.len() > self.chunk_range.end {
+                let section_node = Node {
+                    chunk: section.to_string(),
+                    ..node.clone()
+                };
+                self.chunker
+                    .transform_node(section_node)
+                    .await
+                    .collect::<Vec<_>>()
+                    .await
+            } else if section.len() >= self.chunk_range.start {
+                vec![Ok(Node {
+                    chunk: section.to_string(),
+                    ..node.clone()
+                })]
+            } else {
+                Vec::new()
+            };
+
+            chunks.extend(section_chunks.into_iter().map(|chunk| {
+                let mut chunk = chunk?;
+                if !headings.is_empty() {
+                    chunk.metadata.insert("heading_path", headings.join(" > "));
+                }
+                Ok(chunk)
+            }));
+        }
+
+        IndexingStream::iter(chunks)
+    }
+
+    fn name(&self) -> &'static str {
+        "ChunkMarkdown"
+    }
+}
+
+impl WithIndexingDefaults for ChunkMarkdownSections {}
+
+/// Splits markdown before every heading up to `level`, into the sections and the headings each
+/// falls under
+///
+/// Only headings at the start of a line count, so headings nested in lists and quotes stay in
+/// their section.
+fn markdown_sections(markdown: &str, level: usize) -> Vec<(Vec<String>, &str)> {
+    let mut sections = Vec::new();
+    let mut headings: Vec<(usize, String)> = Vec::new();
+    let mut section_start = 0;
+    // Level, start and text of the heading being parsed
+    let mut heading: Option<(usize, usize, String)> = None;
+
+    for (event, range) in Parser::new(markdown).into_offset_iter() {
+        match event {
+            Event::Start(Tag::Heading {
+                level: heading_level,
+                ..
+            }) if heading_level as usize <= level
+                && (range.start == 0 || markdown[..range.start].ends_with('\n')) =>
+            {
+                heading = Some((heading_level as usize, range.start, String::new()));
+            }
+            Event::Text(text) | Event::Code(text) => {
+                if let Some((_, _, title)) = &mut heading {
+                    title.push_str(&text);
+                }
+            }
+            Event::End(TagEnd::Heading(_)) => {
+                if let Some((heading_level, start, title)) = heading.take() {
+                    sections.push((
+                        headings.iter().map(|(_, title)| title.clone()).collect(),
+                        &markdown[section_start..start],
+                    ));
+                    section_start = start;
+
+                    headings.retain(|(level, _)| *level < heading_level);
+                    headings.push((heading_level, title.trim().to_string()));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    sections.push((
+        headings.into_iter().map(|(_, title)| title).collect(),
+        &markdown[section_start..],
+    ));
+    sections
+}
+
+/// The lines recorded by [`LineNumbers`], if any
+pub(crate) fn line_range(node: &Node) -> Option<(u64, u64)> {
+    let line = |key: &str| node.metadata.get(key)?.as_u64();
+    line("start_line").zip(line("end_line"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_extensions_normalizes_and_dedups() {
+        let rust = parse_language("rust").unwrap();
+        let defaults = resolve_extensions(rust, &[], &[], None).unwrap();
+        assert!(defaults.contains(&"rs".to_string()));
+        assert!(defaults.contains(&"md".to_string()));
+
+        let overrides = [".py".to_string(), " ".to_string()];
+        let extensions =
+            resolve_extensions(rust, &["rs".to_string()], &[], Some(&overrides)).unwrap();
+        assert_eq!(extensions, vec!["py".to_string(), "rs".to_string()]);
+
+        assert!(resolve_extensions(rust, &[], &[], Some(&[])).is_err());
+    }
+
+    #[test]
+    fn markdown_sections_split_at_headings_up_to_level() {
+        let markdown = "intro\n# A\ntext\n## B\nmore\n- # Not a heading\n# C\nend";
+        assert_eq!(
+            markdown_sections(markdown, 2),
+            vec![
+                (vec![], "intro\n"),
+                (vec!["A".to_string()], "# A\ntext\n"),
+                (
+                    vec!["A".to_string(), "B".to_string()],
+                    "## B\nmore\n- # Not a heading\n"
+                ),
+                (vec!["C".to_string()], "# C\nend"),
+            ]
+        );
+        assert_eq!(markdown_sections(markdown, 1).len(), 3);
+    }
+
+    #[tokio::test]
+    async fn markdown_sections_keep_sections_whole_and_chunk_large_ones() {
+        let long = "This sentence is long. ".repeat(4);
+        let markdown = format!(
+            "# Install\nRun the installer now.\n## From source\nBuild with cargo.\n\
+             # Usage\nx\n# Long\n{long}"
+        );
+        let chunks = ChunkMarkdownSections::new(10..40, Some(2))
+            .transform_node(Node::new(markdown))
+            .await
+            .map(|chunk| {
+                let chunk = chunk.unwrap();
+                let heading_path = chunk.metadata.get("heading_path").cloned();
+                (
+                    chunk.chunk,
+                    heading_path.and_then(|path| path.as_str().map(str::to_string)),
+                )
+            })
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(
+            chunks[..2],
+            [
+                (
+                    "# Install\nRun the installer now.".to_string(),
+                    Some("Install".to_string())
+                ),
+                (
+                    "## From source\nBuild with cargo.".to_string(),
+                    Some("Install > From source".to_string())
+                ),
+            ]
+        );
+        assert!(chunks.len() > 3, "Expected the long section in chunks");
+        for (chunk, heading_path) in &chunks[2..] {
+            assert!(chunk.len() < 40, "{chunk}");
+            assert_eq!(heading_path.as_deref(), Some("Long"));
+        }
+    }
+
+    /// Splits files at blank lines
+    #[derive(Debug)]
+    struct Paragraphs;
+
+    #[async_trait]
+    impl ChunkerTransformer for Paragraphs {
+        async fn transform_node(&self, node: Node) -> IndexingStream {
+            let chunks = node
+                .chunk
+                .split("\n\n")
+                .map(|chunk| {
+                    let mut chunk = Node::new(chunk);
+                    chunk.path.clone_from(&node.path);
+                    Ok(chunk)
+                })
+                .collect::<Vec<_>>();
+            IndexingStream::iter(chunks)
+        }
+    }
+
+    async fn chunk(
+        chunker: impl ChunkerTransformer,
+        file: &str,
+    ) -> Vec<(String, Option<(u64, u64)>)> {
+        chunker
+            .transform_node(Node::new(file))
+            .await
+            .map(|chunk| {
+                let chunk = chunk.unwrap();
+                let lines = line_range(&chunk);
+                (chunk.chunk, lines)
+            })
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn line_numbers_follow_the_chunks() {
+        let chunks = chunk(LineNumbers(Paragraphs), "a\nb\n\nc\nd\n\ne").await;
+        assert_eq!(
+            chunks,
+            vec![
+                ("a\nb".to_string(), Some((1, 2))),
+                ("c\nd".to_string(), Some((4, 5))),
+                ("e".to_string(), Some((7, 7))),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn overlapping_repeats_the_last_line_and_moves_the_start_line() {
+        let overlapping = Overlapping {
+            inner: LineNumbers(Paragraphs),
+            overlap: 3,
+        };
+        let chunks = chunk(overlapping, "a\nb\n\nc\nd\n\ne").await;
+        assert_eq!(
+            chunks,
+            vec![
+                ("a\nb".to_string(), Some((1, 2))),
+                ("b\nc\nd".to_string(), Some((2, 5))),
+                ("d\ne".to_string(), Some((5, 7))),
+            ]
+        );
+    }
+}
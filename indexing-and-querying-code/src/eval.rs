@@ -0,0 +1,719 @@
+//! Evaluates the answers with Ragas
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context as _, Result};
+use async_trait::async_trait;
+use indoc::formatdoc;
+use serde::{Deserialize, Serialize};
+use swiftide::{
+    integrations::qdrant::Qdrant,
+    query::{
+        self,
+        answers::Simple,
+        evaluators::{self, ragas::EvaluationDataSet},
+        query_transformers,
+        search_strategies::SimilaritySingleEmbedding,
+        states, Query,
+    },
+    traits::Retrieve,
+};
+
+use crate::{
+    filter::PathFilter,
+    llm::{Llm, Shared},
+    query::{subquestions_transformer, QueryMode, ShowRewrite, CHUNK_SEPARATOR},
+    store::VectorStore,
+};
+
+/// Batches of generated questions without any new question before generation stops
+const MAX_FRUITLESS_BATCHES: usize = 3;
+
+/// Number of the most similar chunks used when none reach the minimum score of a
+/// [`MinScoreRetriever`]
+const MIN_SCORE_FALLBACK: u64 = 3;
+
+/// Clients and settings for evaluating the answers with Ragas
+pub struct EvalContext {
+    pub llm: Llm,
+    pub qdrant: Qdrant,
+    /// The same collection as `qdrant`, for searches that need the scores
+    pub store: Arc<dyn VectorStore>,
+    /// Name of the project, used to generate questions about it
+    pub dir_name: String,
+    pub lang: String,
+    pub top_k: u64,
+    /// How questions are transformed before retrieval when answering them
+    pub query_mode: QueryMode,
+    /// Print what every question is rewritten into to stderr before retrieving
+    pub show_rewrite: bool,
+    /// Number of additional questions generated with `QueryMode::Subquestions`
+    pub num_subquestions: usize,
+    /// Only chunks at least this similar describe the project questions are generated about
+    pub description_min_score: Option<f32>,
+    /// Number of questions answered at once, Swiftide answers as many as there are CPUs if unset
+    pub eval_concurrency: Option<usize>,
+}
+
+/// The pipeline answering the prompts for generating questions, retrieving with `retriever`
+fn generation_pipeline(
+    context: &EvalContext,
+    retriever: impl Retrieve<SimilaritySingleEmbedding> + Clone + 'static,
+) -> Result<query::Pipeline<'static, SimilaritySingleEmbedding, states::Answered>> {
+    let search_strategy: SimilaritySingleEmbedding<()> = SimilaritySingleEmbedding::default()
+        .with_top_k(context.top_k)
+        .to_owned();
+
+    Ok(query::Pipeline::from_search_strategy(search_strategy)
+        .then_transform_query(subquestions_transformer(
+            &context.llm,
+            context.num_subquestions,
+        )?)
+        .then_transform_query(query_transformers::Embed::from_client(Shared(
+            context.llm.embed.clone(),
+        )))
+        .then_retrieve(retriever)
+        .then_answer(Simple::from_client(Shared(context.llm.prompt.clone()))))
+}
+
+/// Retrieves the chunks at least `min_score` similar to the query, which Swiftide's Qdrant
+/// retriever cannot
+///
+/// If no chunk is similar enough, the [`MIN_SCORE_FALLBACK`] most similar are retrieved instead,
+/// with a warning, as answering from nothing would be worse.
+#[derive(Clone)]
+struct MinScoreRetriever {
+    store: Arc<dyn VectorStore>,
+    min_score: f32,
+}
+
+#[async_trait]
+impl Retrieve<SimilaritySingleEmbedding> for MinScoreRetriever {
+    async fn retrieve(
+        &self,
+        search_strategy: &SimilaritySingleEmbedding,
+        query: Query<states::Pending>,
+    ) -> Result<Query<states::Retrieved>> {
+        let embedding = query.embedding.clone().context("No embedding for query")?;
+        let retrieved = self
+            .store
+            .search(
+                embedding,
+                query.current(),
+                search_strategy.top_k(),
+                &PathFilter::default(),
+            )
+            .await?;
+
+        let best = retrieved.first().map(|chunk| chunk.score);
+        let mut similar = retrieved
+            .iter()
+            .filter(|chunk| chunk.score >= self.min_score)
+            .collect::<Vec<_>>();
+        if similar.is_empty() {
+            tracing::warn!(
+                min_score = self.min_score,
+                best,
+                "No chunk is similar enough, using the {MIN_SCORE_FALLBACK} most similar instead"
+            );
+            similar = retrieved
+                .iter()
+                .take(usize::try_from(MIN_SCORE_FALLBACK)?)
+                .collect();
+        }
+        tracing::debug!(
+            retrieved = retrieved.len(),
+            kept = similar.len(),
+            "Retrieved with a minimum score"
+        );
+
+        let documents = similar
+            .into_iter()
+            .map(|chunk| chunk.content.clone())
+            .collect();
+        Ok(query.retrieved_documents(documents))
+    }
+}
+
+/// The pipeline answering questions for evaluation, recording the answers in `ragas`
+fn eval_pipeline(
+    context: &EvalContext,
+    ragas: &evaluators::ragas::Ragas,
+) -> Result<query::Pipeline<'static, SimilaritySingleEmbedding, states::Answered>> {
+    let search_strategy: SimilaritySingleEmbedding<()> = SimilaritySingleEmbedding::default()
+        .with_top_k(context.top_k)
+        .to_owned();
+
+    let mut transformer =
+        context
+            .query_mode
+            .transformer(&context.llm, &context.lang, context.num_subquestions)?;
+    if context.show_rewrite {
+        transformer = Box::new(ShowRewrite(transformer));
+    }
+
+    Ok(query::Pipeline::from_search_strategy(search_strategy)
+        .evaluate_with(ragas.clone())
+        .then_transform_query(transformer)
+        .then_transform_query(query_transformers::Embed::from_client(Shared(
+            context.llm.embed.clone(),
+        )))
+        .then_retrieve(context.qdrant.clone())
+        .then_answer(Simple::from_client(Shared(context.llm.prompt.clone()))))
+}
+
+/// Answers all questions in the dataset and returns the Ragas evaluation of the answers
+pub async fn evaluate(
+    questions: EvaluationDataSet,
+    record_ground_truth: bool,
+    context: &EvalContext,
+) -> Result<evaluators::ragas::Ragas> {
+    // Create a new evaluator with prepared questions, either from the input file or the provided
+    // questions
+    let ragas = evaluators::ragas::Ragas::from_prepared_questions(questions);
+
+    // Run a query pipeline that answers all provided questions
+    let questions = ragas.questions().await;
+    match context.eval_concurrency {
+        None => {
+            eval_pipeline(context, &ragas)?.query_all(questions).await?;
+        }
+        Some(concurrency) => {
+            // Every worker has a pipeline of its own answering one question at a time, so at most
+            // `concurrency` questions are answered at once. Ragas matches the answers to the
+            // questions by their text, so the order they finish in does not matter.
+            let workers = concurrency.min(questions.len());
+            let queue = Arc::new(Mutex::new(VecDeque::from(questions)));
+            let mut tasks = tokio::task::JoinSet::new();
+            for _ in 0..workers {
+                let mut pipeline = eval_pipeline(context, &ragas)?;
+                let queue = Arc::clone(&queue);
+                tasks.spawn(async move {
+                    loop {
+                        let Some(question) = queue.lock().unwrap().pop_front() else {
+                            return Ok::<_, anyhow::Error>(());
+                        };
+                        pipeline.query_mut(question).await?;
+                    }
+                });
+            }
+            while let Some(result) = tasks.join_next().await {
+                result??;
+            }
+        }
+    }
+
+    // If the flag is set, record the answers as ground truth.
+    // Ragas needs to know the correct answers to evaluate certain metrics.
+    //
+    // Can also be set manually or have RAGAS handle it. There are pros and cons to each.
+    if record_ground_truth {
+        ragas.record_answers_as_ground_truth().await;
+    }
+
+    Ok(ragas)
+}
+
+/// A row of a Ragas dataset
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EvaluationRow {
+    pub question: String,
+    pub ground_truth: String,
+    #[serde(default)]
+    pub answer: String,
+    #[serde(default)]
+    pub contexts: Vec<String>,
+}
+
+/// The questions and ground truths of an evaluation as json, leaving out the answers and contexts
+///
+/// The json parses as an `EvaluationDataSet`, so a curated set of ground truths can be reused as
+/// the dataset of later evaluations.
+pub async fn ground_truth_dataset(evaluation: &evaluators::ragas::Ragas) -> Result<String> {
+    let mut rows = evaluation_rows(evaluation).await?;
+    for row in &mut rows {
+        row.answer.clear();
+        row.contexts.clear();
+    }
+
+    Ok(serde_json::to_string_pretty(&rows)?)
+}
+
+/// The rows of the evaluation as csv, with the contexts joined by blank lines, and a column per
+/// metric with `scores`
+///
+/// The score columns are prefixed with `judge_`, as they are estimates by the prompt model and
+/// not computed by Ragas. Scores range from 0 to 1, metrics a question was not scored on are left
+/// empty.
+pub fn to_csv(rows: &[EvaluationRow], scores: Option<&EvaluationScores>) -> Result<String> {
+    let metrics = if scores.is_some() { &METRICS[..] } else { &[] };
+
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(
+        ["question", "answer", "ground_truth", "contexts"]
+            .map(str::to_string)
+            .into_iter()
+            .chain(metrics.iter().map(|metric| format!("judge_{metric}"))),
+    )?;
+    for row in rows {
+        let question_scores = scores.and_then(|scores| scores.0.get(&row.question));
+        let metric_scores = metrics.iter().map(|metric| {
+            question_scores
+                .and_then(|scores| scores.get(*metric))
+                .map(|score| (score / 10.).to_string())
+                .unwrap_or_default()
+        });
+        writer.write_record(
+            [
+                row.question.clone(),
+                row.answer.clone(),
+                row.ground_truth.clone(),
+                row.contexts.join(CHUNK_SEPARATOR),
+            ]
+            .into_iter()
+            .chain(metric_scores),
+        )?;
+    }
+
+    Ok(String::from_utf8(writer.into_inner()?)?)
+}
+
+/// The metrics the prompt model scores, named like those of Ragas they only estimate
+const METRICS: [&str; 4] = [
+    "faithfulness",
+    "answer_relevancy",
+    "context_precision",
+    "context_recall",
+];
+
+/// The scores from 0 to 10 the prompt model gave to every question of an evaluation, by question
+#[derive(Debug, Default)]
+pub struct EvaluationScores(HashMap<String, HashMap<String, f64>>);
+
+impl EvaluationScores {
+    /// Averages every metric over the questions scored on it
+    pub fn summary(&self) -> EvaluationSummary {
+        let average = |metric: &str| {
+            let scores = self
+                .0
+                .values()
+                .filter_map(|scores| scores.get(metric))
+                .collect::<Vec<_>>();
+            (!scores.is_empty())
+                .then(|| scores.iter().copied().sum::<f64>() / scores.len() as f64 / 10.)
+        };
+
+        EvaluationSummary {
+            questions: self.0.len(),
+            faithfulness: average("faithfulness"),
+            answer_relevancy: average("answer_relevancy"),
+            context_precision: average("context_precision"),
+            context_recall: average("context_recall"),
+        }
+    }
+}
+
+/// Averages of the scores the prompt model judged the questions of an evaluation with, from 0 to 1
+///
+/// Swiftide records what Ragas needs, but not the scores, which Ragas computes in the notebook.
+/// These are estimates by the prompt model of the metrics of the same name, for a quick look
+/// without leaving the terminal, and not what Ragas would compute.
+#[derive(Debug, Default)]
+pub struct EvaluationSummary {
+    pub questions: usize,
+    /// Whether the answer is supported by the retrieved contexts
+    pub faithfulness: Option<f64>,
+    /// Whether the answer addresses the question
+    pub answer_relevancy: Option<f64>,
+    /// Whether the retrieved contexts are relevant to the question
+    pub context_precision: Option<f64>,
+    /// Whether the ground truth is supported by the retrieved contexts, needs ground truths
+    pub context_recall: Option<f64>,
+}
+
+impl std::fmt::Display for EvaluationSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Estimated by the prompt model as a judge, Ragas computes its metrics in the notebook"
+        )?;
+        writeln!(f, "{:<20} {:>7}", "metric", "average")?;
+        let scores = [
+            self.faithfulness,
+            self.answer_relevancy,
+            self.context_precision,
+            self.context_recall,
+        ];
+        for (metric, score) in METRICS.into_iter().zip(scores) {
+            match score {
+                Some(score) => writeln!(f, "{metric:<20} {score:>7.3}")?,
+                None => writeln!(f, "{metric:<20} {:>7}", "n/a")?,
+            }
+        }
+        write!(f, "{} questions", self.questions)
+    }
+}
+
+/// Has the prompt model score every answered question of the evaluation on the [`METRICS`]
+///
+/// At most `EvalContext::eval_concurrency` questions are scored at once. Context recall is only
+/// scored for questions with a ground truth. Questions the model fails to score are logged and
+/// left without scores.
+pub async fn score_evaluation(rows: &[EvaluationRow], context: &EvalContext) -> EvaluationScores {
+    let concurrency = context.eval_concurrency.unwrap_or_else(|| {
+        std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get)
+    });
+    let permits = Arc::new(tokio::sync::Semaphore::new(concurrency));
+
+    let mut scoring = tokio::task::JoinSet::new();
+    for row in rows {
+        let prompt = Arc::clone(&context.llm.prompt);
+        let ground_truth = if row.ground_truth.is_empty() {
+            String::new()
+        } else {
+            formatdoc!(
+                "
+                ## Ground truth
+                {}
+                ",
+                row.ground_truth
+            )
+        };
+        let score = formatdoc!(
+            r"
+            Score the answer to the question below on each of the following metrics, from 0 to 10.
+
+            * faithfulness: the claims in the answer are supported by the contexts
+            * answer_relevancy: the answer addresses the question
+            * context_precision: the contexts are relevant to the question
+            {recall}
+            Respond with one line per metric in the form `metric: score` and nothing else.
+
+            ## Question
+            {question}
+
+            ## Answer
+            {answer}
+
+            {ground_truth}
+            ## Contexts
+            {contexts}
+            ",
+            recall = if ground_truth.is_empty() {
+                ""
+            } else {
+                "* context_recall: the ground truth is supported by the contexts\n"
+            },
+            question = row.question,
+            answer = row.answer,
+            contexts = row.contexts.join(CHUNK_SEPARATOR),
+        );
+
+        let question = row.question.clone();
+        let permits = Arc::clone(&permits);
+        scoring.spawn(async move {
+            let _permit = permits.acquire_owned().await?;
+            let scores = prompt.prompt(score.into()).await;
+            Ok::<_, anyhow::Error>((question, scores))
+        });
+    }
+
+    let mut scores = EvaluationScores::default();
+    while let Some(scored) = scoring.join_next().await {
+        match scored
+            .map_err(anyhow::Error::from)
+            .and_then(|scored| scored)
+        {
+            Ok((question, Ok(response))) => {
+                scores
+                    .0
+                    .entry(question)
+                    .or_default()
+                    .extend(parse_scores(&response));
+            }
+            Ok((question, Err(err))) => {
+                tracing::warn!(error = %err, question, "Failed to score the answer");
+            }
+            Err(err) => tracing::warn!(error = %err, "Failed to score an answer"),
+        }
+    }
+
+    scores
+}
+
+/// The `metric: score` lines of a response, ignoring anything else
+fn parse_scores(response: &str) -> Vec<(String, f64)> {
+    response
+        .lines()
+        .filter_map(|line| {
+            let (metric, score) = line.split_once(':')?;
+            let metric = metric
+                .trim()
+                .trim_matches(['*', '`', '-', ' '])
+                .to_lowercase();
+            let score = score.trim().trim_matches('`').parse::<f64>().ok()?;
+            Some((metric, score.clamp(0., 10.)))
+        })
+        .collect()
+}
+
+/// The rows of the evaluation, sorted by question to keep the output stable between runs
+pub async fn evaluation_rows(evaluation: &evaluators::ragas::Ragas) -> Result<Vec<EvaluationRow>> {
+    let mut rows = serde_json::from_str::<Vec<EvaluationRow>>(&evaluation.to_json().await)?;
+    rows.sort_by(|a, b| a.question.cmp(&b.question));
+
+    Ok(rows)
+}
+
+/// A generated question with the answer the query pipeline gave to it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct QuestionAnswer {
+    pub question: String,
+    pub answer: String,
+}
+
+/// Answers the questions like [`evaluate`] does, to bootstrap a ground truth from generated
+/// questions
+///
+/// The answers are in the order of the questions, duplicate questions are answered once.
+pub async fn answer_questions(
+    questions: &[String],
+    context: &EvalContext,
+) -> Result<Vec<QuestionAnswer>> {
+    let evaluation = evaluate(questions.to_vec().into(), false, context).await?;
+    let mut answers = evaluation_rows(&evaluation)
+        .await?
+        .into_iter()
+        .map(|row| (row.question, row.answer))
+        .collect::<HashMap<_, _>>();
+
+    Ok(questions
+        .iter()
+        .filter_map(|question| {
+            let answer = answers.remove(question)?;
+            Some(QuestionAnswer {
+                question: question.clone(),
+                answer,
+            })
+        })
+        .collect())
+}
+
+/// Generates questions based on the indexed data until there are `num_questions`
+///
+/// Questions are requested `batch_size` at a time, as models tend to return fewer than asked for
+/// large amounts. Blank, duplicate and malformed lines are discarded. Generation continues from
+/// the given `questions`, and `save` is called with all questions so far after every batch, so an
+/// interrupted run can be resumed.
+pub async fn generate_questions(
+    context: &EvalContext,
+    num_questions: usize,
+    batch_size: usize,
+    mut questions: Vec<String>,
+    mut save: impl FnMut(&[String]) -> Result<()>,
+) -> Result<Vec<String>> {
+    if questions.len() >= num_questions {
+        return Ok(questions);
+    }
+
+    let mut pipeline = generation_pipeline(context, context.qdrant.clone())?;
+
+    let describe = format!(
+        "What is the {} project written in {} about? Provide an elaborate answer with examples.",
+        &context.dir_name, &context.lang
+    );
+    let project_description = match context.description_min_score {
+        Some(min_score) => {
+            let retriever = MinScoreRetriever {
+                store: context.store.clone(),
+                min_score,
+            };
+            generation_pipeline(context, retriever)?
+                .query_mut(describe)
+                .await?
+        }
+        None => pipeline.query_mut(describe).await?,
+    }
+    .answer()
+    .to_string();
+
+    println!("{}", &project_description);
+
+    // Models vary the capitalization of questions they repeat
+    let mut seen = questions
+        .iter()
+        .map(|question| question.to_lowercase())
+        .collect::<HashSet<_>>();
+    let mut fruitless_batches = 0;
+
+    while questions.len() < num_questions {
+        let batch = batch_size.min(num_questions - questions.len());
+        let asked = questions
+            .iter()
+            .map(|question| format!("* {question}"))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let answer = pipeline.query_mut(indoc::formatdoc! {"
+            Your goal is to generate {batch} questions about the given project description. Questions can be about the project, how different parts can be used, features, architecture, testing, dependencies, and so on.
+
+            # Requirements
+            * Only respond with the questions, separated by a new line with no other text.
+            * Questions should be varied and concise
+            * Provide a balance of technical questions, and questions that explore the meaning and
+                usage of the project
+            * Questions must be a single line, and each question should be separated by a newline.
+            * Questions can not include markdown
+            * Respond only with the list of questions
+            * Do not repeat any of the questions that have been asked already
+
+            # Example response
+
+            <question 1>?
+            <question 2>?
+
+            ---
+
+            # Project description
+            {project_description}
+
+            # Questions asked already
+            {asked}
+        "}).await?.answer().to_string();
+
+        let before = questions.len();
+        for question in answer.lines().filter_map(parse_question) {
+            if questions.len() < num_questions && seen.insert(question.to_lowercase()) {
+                questions.push(question);
+            }
+        }
+
+        tracing::info!(
+            generated = questions.len() - before,
+            total = questions.len(),
+            num_questions,
+            "Generated batch of questions"
+        );
+        save(&questions)?;
+
+        // Give up if the model keeps repeating itself instead of asking forever
+        if questions.len() == before {
+            fruitless_batches += 1;
+            if fruitless_batches == MAX_FRUITLESS_BATCHES {
+                tracing::warn!(
+                    total = questions.len(),
+                    num_questions,
+                    "No new questions in {MAX_FRUITLESS_BATCHES} batches, stopping"
+                );
+                break;
+            }
+        } else {
+            fruitless_batches = 0;
+        }
+    }
+
+    Ok(questions)
+}
+
+/// The question on a line of generated questions, `None` if the line is not a question
+///
+/// Models like to number or bullet their questions despite being asked not to, so leading list
+/// markers are removed.
+fn parse_question(line: &str) -> Option<String> {
+    let line = line.trim();
+    let question = match line.strip_prefix(['-', '*']) {
+        Some(question) => question,
+        None => {
+            // Numbered like `1.` or `1)`, without mistaking a question starting with a number
+            let unnumbered = line.trim_start_matches(|c: char| c.is_ascii_digit());
+            match unnumbered.strip_prefix(['.', ')']) {
+                Some(question) if unnumbered.len() < line.len() => question,
+                _ => line,
+            }
+        }
+    }
+    .trim();
+
+    (question.len() > 1 && question.ends_with('?')).then(|| question.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_question_strips_list_markers() {
+        assert_eq!(
+            parse_question("1. What is x?"),
+            Some("What is x?".to_string())
+        );
+        assert_eq!(parse_question(" - Why? "), Some("Why?".to_string()));
+        assert_eq!(
+            parse_question("2024 brought what?"),
+            Some("2024 brought what?".to_string())
+        );
+        assert_eq!(parse_question("Not a question"), None);
+        assert_eq!(parse_question("- ?"), None);
+    }
+
+    #[test]
+    fn to_csv_writes_a_column_per_metric_with_scores() {
+        let rows = [EvaluationRow {
+            question: "What is a?".to_string(),
+            ground_truth: "A letter".to_string(),
+            answer: String::new(),
+            contexts: vec!["a".to_string(), "b".to_string()],
+        }];
+        let scores = EvaluationScores(HashMap::from([(
+            "What is a?".to_string(),
+            HashMap::from([("faithfulness".to_string(), 8.)]),
+        )]));
+
+        assert_eq!(
+            to_csv(&rows, None).unwrap(),
+            "question,answer,ground_truth,contexts\nWhat is a?,,A letter,\"a\n\nb\"\n"
+        );
+        assert_eq!(
+            to_csv(&rows, Some(&scores)).unwrap(),
+            "question,answer,ground_truth,contexts,judge_faithfulness,judge_answer_relevancy,\
+             judge_context_precision,judge_context_recall\nWhat is a?,,A letter,\"a\n\nb\",0.8,,,\n"
+        );
+    }
+
+    #[test]
+    fn parse_scores_clamps_and_skips_other_lines() {
+        assert_eq!(
+            parse_scores("**Faithfulness**: 8\nanswer_relevancy: `12`\nThe context was: fine"),
+            vec![
+                ("faithfulness".to_string(), 8.),
+                ("answer_relevancy".to_string(), 10.)
+            ]
+        );
+    }
+
+    #[test]
+    fn summary_averages_the_judged_questions_per_metric() {
+        let scores = EvaluationScores(HashMap::from([
+            (
+                "What is a?".to_string(),
+                HashMap::from([("faithfulness".to_string(), 8.)]),
+            ),
+            (
+                "What is b?".to_string(),
+                HashMap::from([
+                    ("faithfulness".to_string(), 6.),
+                    ("answer_relevancy".to_string(), 10.),
+                ]),
+            ),
+        ]));
+
+        let summary = scores.summary();
+        assert_eq!(summary.questions, 2);
+        assert_eq!(summary.faithfulness, Some(0.7));
+        assert_eq!(summary.answer_relevancy, Some(1.));
+        assert_eq!(summary.context_recall, None);
+    }
+}
@@ -0,0 +1,191 @@
+//! Exports the indexed chunks to files
+
+use std::{
+    fs::File,
+    io::{LineWriter, Write as _},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context as _, Result};
+use arrow_array::{FixedSizeListArray, Float32Array, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema};
+use async_trait::async_trait;
+use parquet::arrow::ArrowWriter;
+use swiftide::{
+    indexing::{EmbeddedField, Node},
+    traits::{Transformer, WithIndexingDefaults},
+};
+
+/// Writes every node passing through as a line of json, to inspect what gets stored
+///
+/// Each line holds the `path`, `chunk`, `metadata` and `embedding_len` of a node.
+pub(crate) struct ExportJsonl {
+    writer: Mutex<LineWriter<File>>,
+}
+
+impl ExportJsonl {
+    pub(crate) fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create export file {}", path.display()))?;
+
+        Ok(Self {
+            writer: Mutex::new(LineWriter::new(file)),
+        })
+    }
+}
+
+#[async_trait]
+impl Transformer for ExportJsonl {
+    async fn transform_node(&self, node: Node) -> Result<Node> {
+        let embedding_len = node
+            .vectors
+            .as_ref()
+            .and_then(|vectors| vectors.values().map(Vec::len).max());
+
+        let line = serde_json::to_string(&serde_json::json!({
+            "path": node.path,
+            "chunk": node.chunk,
+            "metadata": node.metadata,
+            "embedding_len": embedding_len,
+        }))?;
+        writeln!(self.writer.lock().unwrap(), "{line}")?;
+
+        Ok(node)
+    }
+}
+
+impl WithIndexingDefaults for ExportJsonl {}
+
+/// Number of rows written to Parquet at once, so not all embeddings are kept in memory
+const PARQUET_BATCH_SIZE: usize = 1000;
+
+/// Writes the `path`, `chunk` and `embedding` of every node passing through to a Parquet file,
+/// to analyze the embeddings without Qdrant
+///
+/// Rows are written in batches, the file is only complete after [`ExportParquet::finish`].
+/// Clones write to the same file.
+#[derive(Clone)]
+pub(crate) struct ExportParquet(Arc<Mutex<ParquetState>>);
+
+struct ParquetState {
+    path: PathBuf,
+    /// Created with the first batch, as the size of the embeddings is part of the schema
+    writer: Option<ArrowWriter<File>>,
+    rows: Vec<(String, String, Vec<f32>)>,
+}
+
+impl ExportParquet {
+    pub(crate) fn create(path: &Path) -> Result<Self> {
+        Ok(Self(Arc::new(Mutex::new(ParquetState {
+            path: path.to_path_buf(),
+            writer: None,
+            rows: Vec::with_capacity(PARQUET_BATCH_SIZE),
+        }))))
+    }
+
+    /// Writes the remaining rows and the footer of the file
+    pub(crate) fn finish(&self) -> Result<()> {
+        let mut state = self.0.lock().unwrap();
+        state.write_batch()?;
+        if let Some(writer) = state.writer.take() {
+            writer.close()?;
+            tracing::info!(path = %state.path.display(), "Wrote embeddings to Parquet");
+        }
+
+        Ok(())
+    }
+}
+
+impl ParquetState {
+    fn write_batch(&mut self) -> Result<()> {
+        let Some(vector_size) = self.rows.first().map(|(_, _, embedding)| embedding.len()) else {
+            return Ok(());
+        };
+        let rows = std::mem::take(&mut self.rows);
+
+        let item = Arc::new(Field::new("item", DataType::Float32, false));
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("path", DataType::Utf8, false),
+            Field::new("chunk", DataType::Utf8, false),
+            Field::new(
+                "embedding",
+                DataType::FixedSizeList(Arc::clone(&item), i32::try_from(vector_size)?),
+                false,
+            ),
+        ]));
+
+        let mut values = Vec::with_capacity(rows.len() * vector_size);
+        for (path, _, embedding) in &rows {
+            anyhow::ensure!(
+                embedding.len() == vector_size,
+                "Expected an embedding of {vector_size} dimensions for {path}, got {}",
+                embedding.len()
+            );
+            values.extend_from_slice(embedding);
+        }
+        let batch = RecordBatch::try_new(
+            Arc::clone(&schema),
+            vec![
+                Arc::new(StringArray::from_iter_values(
+                    rows.iter().map(|(path, _, _)| path),
+                )),
+                Arc::new(StringArray::from_iter_values(
+                    rows.iter().map(|(_, chunk, _)| chunk),
+                )),
+                Arc::new(FixedSizeListArray::try_new(
+                    item,
+                    i32::try_from(vector_size)?,
+                    Arc::new(Float32Array::from(values)),
+                    None,
+                )?),
+            ],
+        )?;
+
+        let writer = match &mut self.writer {
+            Some(writer) => writer,
+            None => {
+                let file = File::create(&self.path).with_context(|| {
+                    format!("Failed to create Parquet file {}", self.path.display())
+                })?;
+                self.writer
+                    .insert(ArrowWriter::try_new(file, schema, None)?)
+            }
+        };
+        writer.write(&batch)?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Transformer for ExportParquet {
+    async fn transform_node(&self, node: Node) -> Result<Node> {
+        // Of the chunk only if it has named vectors
+        let embedding = node
+            .vectors
+            .as_ref()
+            .and_then(|vectors| {
+                vectors
+                    .get(&EmbeddedField::Chunk)
+                    .or_else(|| vectors.values().next())
+            })
+            .context("Expected the node to be embedded")?
+            .clone();
+
+        let mut state = self.0.lock().unwrap();
+        state.rows.push((
+            node.path.to_string_lossy().to_string(),
+            node.chunk.clone(),
+            embedding,
+        ));
+        if state.rows.len() >= PARQUET_BATCH_SIZE {
+            state.write_batch()?;
+        }
+        drop(state);
+
+        Ok(node)
+    }
+}
+
+impl WithIndexingDefaults for ExportParquet {}
@@ -0,0 +1,299 @@
+//! Filters on the paths of indexed and retrieved files
+
+use std::path::Path;
+
+use anyhow::{Context as _, Result};
+use globset::{Glob, GlobMatcher, GlobSet, GlobSetBuilder};
+
+/// Selects files by glob patterns matched against their path relative to the indexed root
+///
+/// Exclude wins over include. Without include patterns, every file that is not excluded is
+/// selected.
+#[derive(Clone)]
+pub struct FileFilter {
+    include: Option<GlobSet>,
+    /// The patterns of `include`, in the same order
+    include_patterns: Vec<String>,
+    exclude: GlobSet,
+    pub(crate) tests: Option<GlobSet>,
+}
+
+/// Paths of tests and generated code, skipped by [`FileFilter::excluding_tests`]
+const TEST_AND_GENERATED_GLOBS: &[&str] = &[
+    "**/test/**",
+    "**/tests/**",
+    "**/__tests__/**",
+    "**/*_test.go",
+    "**/*_test.py",
+    "**/test_*.py",
+    "**/*.test.*",
+    "**/*.spec.*",
+    "**/*.pb.go",
+    "**/*_pb2.py",
+    "**/*_generated.rs",
+    "**/*.generated.*",
+    "**/*.g.dart",
+    "**/*.min.js",
+];
+
+impl FileFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self> {
+        let include_set = if include.is_empty() {
+            None
+        } else {
+            Some(build_globset(include)?)
+        };
+
+        Ok(Self {
+            include: include_set,
+            include_patterns: include.to_vec(),
+            exclude: build_globset(exclude)?,
+            tests: None,
+        })
+    }
+
+    /// Also skips tests and generated code, recognised by their paths, whether they are included
+    /// or not
+    ///
+    /// Files are selected anyway when an include pattern names them more specifically than the
+    /// pattern that recognises them, like `tests/fixtures/**` does for `**/tests/**`.
+    pub fn excluding_tests(self) -> Result<Self> {
+        let globs = TEST_AND_GENERATED_GLOBS
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+
+        Ok(Self {
+            tests: Some(build_globset(&globs)?),
+            ..self
+        })
+    }
+
+    pub(crate) fn matches(&self, root: &Path, path: &Path) -> bool {
+        let relative = path.strip_prefix(root).unwrap_or(path);
+
+        if self.exclude.is_match(relative) {
+            return false;
+        }
+
+        self.include
+            .as_ref()
+            .is_none_or(|include| include.is_match(relative))
+            && !self.is_test(root, path)
+    }
+
+    /// Whether the file is skipped only because it looks like a test or generated code
+    pub(crate) fn is_test(&self, root: &Path, path: &Path) -> bool {
+        let relative = path.strip_prefix(root).unwrap_or(path);
+        let Some(tests) = &self.tests else {
+            return false;
+        };
+        if self.exclude.is_match(relative) {
+            return false;
+        }
+
+        let Some(test_specificity) = tests
+            .matches(relative)
+            .into_iter()
+            .map(|index| glob_specificity(TEST_AND_GENERATED_GLOBS[index]))
+            .max()
+        else {
+            return false;
+        };
+
+        match &self.include {
+            None => true,
+            // Files that are not included are skipped for that already
+            Some(include) => include
+                .matches(relative)
+                .into_iter()
+                .map(|index| glob_specificity(&self.include_patterns[index]))
+                .max()
+                .is_some_and(|specificity| specificity <= test_specificity),
+        }
+    }
+}
+
+fn build_globset(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern).with_context(|| format!("Invalid glob {pattern}"))?);
+    }
+    Ok(builder.build()?)
+}
+
+/// Restricts retrieval to chunks of files matching a glob and/or extension
+///
+/// The glob is matched against the stored path, which is the path the file was indexed from,
+/// so it usually needs a leading `**/`.
+#[derive(Clone, Debug, Default)]
+pub struct PathFilter {
+    pub(crate) glob: Option<GlobMatcher>,
+    pub(crate) extension: Option<String>,
+}
+
+impl PathFilter {
+    pub fn new(glob: Option<&str>, extension: Option<&str>) -> Result<Self> {
+        let glob = glob
+            .map(|pattern| {
+                Glob::new(pattern)
+                    .map(|glob| glob.compile_matcher())
+                    .with_context(|| format!("Invalid glob {pattern}"))
+            })
+            .transpose()?;
+
+        Ok(Self {
+            glob,
+            extension: extension.map(|extension| extension.trim_start_matches('.').to_string()),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.glob.is_none() && self.extension.is_none()
+    }
+
+    pub(crate) fn matches(&self, path: &str) -> bool {
+        let path = Path::new(path);
+
+        self.glob.as_ref().is_none_or(|glob| glob.is_match(path))
+            && self.extension.as_ref().is_none_or(|extension| {
+                path.extension()
+                    .is_some_and(|actual| actual == extension.as_str())
+            })
+    }
+
+    /// Substrings of every matching path, the longest literal part of the glob and the extension
+    ///
+    /// Qdrant cannot match globs, but it can match substrings, which narrows the search down to
+    /// (mostly) matching paths before the exact match is checked on the results.
+    pub(crate) fn required_substrings(&self) -> Vec<String> {
+        let literal = self
+            .glob
+            .as_ref()
+            .and_then(|glob| longest_literal(glob.glob().glob()))
+            .map(str::to_string);
+        let extension = self
+            .extension
+            .as_ref()
+            .map(|extension| format!(".{extension}"));
+
+        literal.into_iter().chain(extension).collect()
+    }
+}
+
+/// The longest part of a glob that every matching path contains literally, if any
+fn longest_literal(pattern: &str) -> Option<&str> {
+    glob_literals(pattern).max_by_key(|literal| literal.len())
+}
+
+/// How specific a glob is, by the number of characters of a path it matches literally
+fn glob_specificity(pattern: &str) -> usize {
+    glob_literals(pattern).map(str::len).sum()
+}
+
+/// The parts of a glob that every matching path contains literally
+///
+/// Alternatives and character classes match one of their parts, not their text, so nothing inside
+/// `{…}` or `[…]` is literal. A `**/` also matches no directory at all, so the separator after it
+/// is left out.
+fn glob_literals(pattern: &str) -> impl Iterator<Item = &str> {
+    let mut literals = Vec::new();
+    let mut depth = 0usize;
+    let mut start = 0;
+    for (index, char) in pattern.char_indices() {
+        match char {
+            '{' | '[' => {
+                if depth == 0 {
+                    literals.push(start..index);
+                }
+                depth += 1;
+            }
+            '}' | ']' if depth > 0 => {
+                depth -= 1;
+                start = index + 1;
+            }
+            '*' | '?' | '!' | '\\' | '}' | ']' if depth == 0 => {
+                literals.push(start..index);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    if depth == 0 {
+        literals.push(start..pattern.len());
+    }
+
+    literals
+        .into_iter()
+        .map(|range| {
+            let literal = &pattern[range.clone()];
+            if pattern[..range.start].ends_with("**") {
+                literal.trim_start_matches('/')
+            } else {
+                literal
+            }
+        })
+        .filter(|literal| !literal.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_filter_matches_alternatives() {
+        let filter = PathFilter::new(Some("**/*.{rs,toml}"), None).unwrap();
+        assert!(filter.matches("src/lib.rs"));
+        assert!(filter.matches("Cargo.toml"));
+        assert!(!filter.matches("README.md"));
+        for substring in filter.required_substrings() {
+            assert!("src/lib.rs".contains(&substring), "{substring}");
+            assert!("Cargo.toml".contains(&substring), "{substring}");
+        }
+    }
+
+    #[test]
+    fn path_filter_matches_extension() {
+        let filter = PathFilter::new(None, Some(".rs")).unwrap();
+        assert!(filter.matches("src/lib.rs"));
+        assert!(!filter.matches("src/lib.rsx"));
+        assert_eq!(filter.required_substrings(), vec![".rs".to_string()]);
+        assert!(PathFilter::new(None, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn longest_literal_skips_alternatives_and_classes() {
+        assert_eq!(longest_literal("**/{lib,main}.rs"), Some(".rs"));
+        assert_eq!(longest_literal("src/[a-z]*.rs"), Some("src/"));
+        assert_eq!(longest_literal("**/lib.rs"), Some("lib.rs"));
+        assert_eq!(longest_literal("**/*.{rs,toml}"), Some("."));
+        assert_eq!(longest_literal("{a,b}"), None);
+    }
+
+    #[test]
+    fn file_filter_skips_included_tests() {
+        let root = Path::new("/repo");
+        let filter = FileFilter::new(&["**/*.rs".to_string()], &[])
+            .unwrap()
+            .excluding_tests()
+            .unwrap();
+        assert!(filter.matches(root, Path::new("/repo/src/lib.rs")));
+        assert!(!filter.matches(root, Path::new("/repo/tests/qdrant.rs")));
+        assert!(!filter.matches(root, Path::new("/repo/src/schema_generated.rs")));
+        assert!(filter.is_test(root, Path::new("/repo/tests/qdrant.rs")));
+        assert!(!filter.is_test(root, Path::new("/repo/README.md")));
+    }
+
+    #[test]
+    fn file_filter_indexes_tests_included_more_specifically() {
+        let root = Path::new("/repo");
+        let include = ["src/**".to_string(), "tests/fixtures/**".to_string()];
+        let filter = FileFilter::new(&include, &[])
+            .unwrap()
+            .excluding_tests()
+            .unwrap();
+        assert!(filter.matches(root, Path::new("/repo/tests/fixtures/greeting.rs")));
+        assert!(!filter.matches(root, Path::new("/repo/tests/qdrant.rs")));
+        assert!(!filter.matches(root, Path::new("/repo/src/tests/mod.rs")));
+    }
+}
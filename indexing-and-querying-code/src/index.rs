@@ -0,0 +1,1228 @@
+//! Indexes sources into a store
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::IsTerminal as _,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{Context as _, Result};
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::Serialize;
+use sha2::{Digest as _, Sha256};
+use swiftide::{
+    indexing::{
+        loaders::FileLoader,
+        persist::MemoryStorage,
+        transformers::{Embed, MetadataQACode, MetadataQAText, SparseEmbed},
+        Node, Pipeline,
+    },
+    integrations::{redis::Redis, treesitter::SupportedLanguages},
+};
+use tiktoken_rs::CoreBPE;
+
+use crate::{
+    cache::cache_prefix,
+    checkpoint::{Checkpoint, CheckpointFiles},
+    chunk::{
+        default_chunk_range, ChunkCodeByLanguage, ChunkMarkdownSections, Languages, LineNumbers,
+        LogPerFile, MarkdownSplit, Overlapping,
+    },
+    export::{ExportJsonl, ExportParquet},
+    filter::FileFilter,
+    llm::{validate_vector_size, Llm, Price, Prices, Shared},
+    metadata::{EmbedMetadata, EmbedNamed, GitMetadata, MetadataTemplate, SelectMetadata},
+    source::{is_pdf, list_files, source_files, FileList, Source, Text},
+    store::{build_store, preflight, KeywordSparse, StoreConfig, StoreKind, VectorStore},
+    timing::{FailedStep, Phase, Timed, Timings},
+};
+
+/// How often to look for stored chunks that cannot be read back yet, and how long to wait
+/// between looks, as Qdrant may apply the last writes after indexing returned
+const CONFIRM_ATTEMPTS: u32 = 5;
+const CONFIRM_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The files to index and how to process them
+pub struct IndexOptions {
+    pub language: Languages,
+    pub sources: Vec<Source>,
+    pub extensions: Vec<String>,
+    /// Extensions that are chunked as markdown even if they are code extensions of the language
+    pub docs_extensions: Vec<String>,
+    pub filter: FileFilter,
+    pub chunk_range: Range<usize>,
+    /// Characters of the end of the previous chunk of a file repeated at the start of a chunk
+    pub chunk_overlap: usize,
+    pub code_concurrency: usize,
+    pub markdown_concurrency: usize,
+    /// Number of chunks embedded in a single request
+    pub embed_batch_size: usize,
+    pub export: Option<PathBuf>,
+    pub git_metadata: bool,
+    /// Print how long each phase took once indexing is done
+    pub timings: bool,
+    /// Remove the chunks of the files deleted since the ref of `Source::changed_since` from the
+    /// store
+    pub prune_deleted: bool,
+    /// Remove the chunks of the files under the sources that no longer exist from the store
+    pub prune: bool,
+    /// Write the nodes that failed to load, chunk, enrich or embed to this file as json
+    pub error_report: Option<PathBuf>,
+    /// Refuse to index more files than this, as every file costs LLM calls
+    pub max_files: Option<usize>,
+    /// Log the chunks and tokens of every file at info instead of debug level
+    pub log_per_file: bool,
+    /// Also write the path, chunk and embedding of every indexed chunk to this Parquet file
+    pub embeddings_parquet: Option<PathBuf>,
+    /// Index the files symlinks point to, as long as they are inside the source
+    pub follow_symlinks: bool,
+    /// Recorded with every chunk, see [`check_embed_model`](crate::check_embed_model)
+    pub embed_model: Option<String>,
+    /// Which metadata is embedded together with the chunks
+    pub embed_metadata: EmbedMetadata,
+    /// Stop at the first file that fails instead of leaving it out and indexing the rest
+    pub strict: bool,
+    /// Replaces Swiftide's prompt generating questions and answers for code chunks
+    pub qa_code_prompt: Option<MetadataTemplate>,
+    /// Replaces Swiftide's prompt generating questions and answers for markdown chunks
+    pub qa_text_prompt: Option<MetadataTemplate>,
+    /// Chunk code with the [`default_chunk_range`] of its language, leaving `chunk_range` for
+    /// everything else
+    pub language_chunk_ranges: bool,
+    /// Record the files whose chunks are all stored to this file, and skip the unchanged files
+    /// recorded in it by an earlier run, see [`Checkpoint`]
+    pub resume_from: Option<PathBuf>,
+    /// How markdown and other text is split into chunks
+    pub markdown_split: MarkdownSplit,
+    /// The deepest heading markdown is split at with [`MarkdownSplit::Heading`]
+    pub markdown_heading_level: usize,
+}
+
+impl IndexOptions {
+    /// The chunk size range for code in the language
+    fn code_chunk_range(&self, language: SupportedLanguages) -> Range<usize> {
+        if self.language_chunk_ranges {
+            default_chunk_range(language)
+        } else {
+            self.chunk_range.clone()
+        }
+    }
+
+    /// The languages whose code is chunked with another range than `chunk_range`
+    fn other_chunk_ranges(&self) -> BTreeMap<String, Range<usize>> {
+        self.language
+            .iter()
+            .map(|language| (language.to_string(), self.code_chunk_range(language)))
+            .filter(|(_, range)| *range != self.chunk_range)
+            .collect()
+    }
+}
+
+/// Builds the store and indexes the code into it, unless it is indexed already and that is
+/// allowed to be skipped
+///
+/// This is what the `index` command does, the returned store can be used to answer questions
+/// with [`query`](crate::query()). Nothing is indexed if indexing is skipped, so the stats are all zero then.
+pub async fn index(
+    options: &IndexOptions,
+    config: &StoreConfig,
+    llm: &Llm,
+) -> Result<(Box<dyn VectorStore>, IndexStats)> {
+    // Reindexing from scratch skips the cache, otherwise every node would be filtered out as
+    // already indexed. The memory store starts out empty on every run.
+    let redis_url = config
+        .redis_url
+        .as_deref()
+        .filter(|_| !config.force_reindex && !matches!(config.store, StoreKind::Memory));
+    if config.preflight {
+        preflight(config.store, redis_url).await?;
+    }
+
+    validate_vector_size(llm.embed.as_ref(), config.vector_size).await?;
+
+    let store = build_store(
+        config.store,
+        &config.collection_name,
+        config.vector_size,
+        config.collection,
+    )?;
+
+    if config.force_reindex {
+        anyhow::ensure!(
+            options.resume_from.is_none(),
+            "Cannot resume while reindexing from scratch, the files recorded as stored would be \
+             left out"
+        );
+        store.clear().await?;
+    }
+
+    if config.skip_if_indexed && store.is_indexed().await? {
+        tracing::info!(
+            collection_name = config.collection_name,
+            "Collection exists, skipping indexing"
+        );
+        return Ok((store, IndexStats::default()));
+    }
+
+    let cache = match redis_url {
+        Some(redis_url) => {
+            // Custom metadata prompts generate other metadata, so they get a cache of their own
+            let mut prompt_model = config.prompt_model.clone();
+            for template in [&options.qa_code_prompt, &options.qa_text_prompt]
+                .into_iter()
+                .flatten()
+            {
+                prompt_model.push('+');
+                prompt_model.push_str(&content_hash(&template.0)[..8]);
+            }
+
+            // So is embedding other metadata
+            let embed_model = match options.embed_metadata {
+                EmbedMetadata::Include => config.embed_model.clone(),
+                EmbedMetadata::Exclude => format!("{}+exclude", config.embed_model),
+                EmbedMetadata::ContentOnly => format!("{}+content-only", config.embed_model),
+            };
+
+            let mut prefix = cache_prefix(
+                &config.collection_name,
+                &options.chunk_range,
+                options.chunk_overlap,
+                &embed_model,
+                &prompt_model,
+            );
+            // Code chunked with the range of its language is chunked differently as well
+            for (language, range) in options.other_chunk_ranges() {
+                prefix.push_str(&format!(":{language}={}-{}", range.start, range.end));
+            }
+            // And so is markdown split at its headings
+            if options.markdown_split == MarkdownSplit::Heading {
+                prefix.push_str(&format!(":md-h{}", options.markdown_heading_level));
+            }
+            Some(Redis::try_from_url(redis_url, prefix)?)
+        }
+        None => None,
+    };
+
+    let stats = index_all(options, cache, config.show_progress, llm, store.as_ref()).await?;
+
+    if let Some(path) = &config.manifest {
+        Manifest::new(options, config, stats).write(path)?;
+    }
+
+    Ok((store, stats))
+}
+
+/// What a run of [`index_all`] indexed
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IndexStats {
+    /// Files of the sources, including those skipped because they were cached
+    pub files: usize,
+    /// Chunks stored in this run
+    pub chunks: usize,
+    /// Files that failed to load, chunk, enrich or embed, and were left out
+    pub errors: usize,
+    /// Chunks in the store once done, including those stored by earlier runs
+    pub points: u64,
+    /// Chunks removed because their file no longer exists, with `IndexOptions::prune`
+    pub pruned: usize,
+}
+
+/// Removes the chunks of the files under the sources that no longer exist on disk, returning
+/// how many chunks were removed
+///
+/// Only paths under the sources are checked, so chunks of other directories indexed into the
+/// same store are kept.
+async fn prune_missing(options: &IndexOptions, store: &dyn VectorStore) -> Result<usize> {
+    let roots = options
+        .sources
+        .iter()
+        .filter(|source| source.text.is_none())
+        .map(|source| source.path.as_path())
+        .collect::<Vec<_>>();
+
+    let mut stale = BTreeMap::<String, usize>::new();
+    for (path, _) in store.content_hashes().await? {
+        let file = Path::new(&path);
+        if roots.iter().any(|root| file.starts_with(root)) && !file.exists() {
+            *stale.entry(path).or_default() += 1;
+        }
+    }
+    if stale.is_empty() {
+        return Ok(0);
+    }
+
+    let chunks = stale.values().sum();
+    tracing::info!(
+        files = stale.len(),
+        chunks,
+        "Removing the chunks of files that no longer exist"
+    );
+    store
+        .delete_paths(&stale.into_keys().collect::<Vec<_>>())
+        .await?;
+
+    Ok(chunks)
+}
+
+/// How an index was built, to match a collection to the settings it was built with
+#[derive(Serialize, Debug)]
+pub struct Manifest {
+    pub paths: Vec<String>,
+    pub language: String,
+    pub extensions: Vec<String>,
+    pub chunk_range: Range<usize>,
+    /// Languages whose code was chunked with another range than `chunk_range`
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub code_chunk_ranges: BTreeMap<String, Range<usize>>,
+    pub chunk_overlap: usize,
+    /// The deepest heading markdown was split at, if it was split at headings
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub markdown_heading_level: Option<usize>,
+    pub embed_model: String,
+    pub prompt_model: String,
+    pub vector_size: u64,
+    pub collection_name: String,
+    pub files: usize,
+    pub chunks: usize,
+    /// When indexing finished, in RFC 3339
+    pub indexed_at: String,
+}
+
+impl Manifest {
+    /// When the manifest at `path` was written, if there is one
+    pub fn indexed_at(path: &Path) -> Result<Option<String>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let manifest: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read manifest {}", path.display()))?,
+        )?;
+        Ok(manifest["indexed_at"].as_str().map(str::to_string))
+    }
+
+    pub fn new(options: &IndexOptions, config: &StoreConfig, stats: IndexStats) -> Self {
+        Self {
+            paths: options
+                .sources
+                .iter()
+                .map(|source| source.origin.clone())
+                .collect(),
+            language: match options.language {
+                Languages::One(language) => language.to_string(),
+                Languages::Auto => "auto".to_string(),
+            },
+            extensions: options.extensions.clone(),
+            chunk_range: options.chunk_range.clone(),
+            code_chunk_ranges: options.other_chunk_ranges(),
+            chunk_overlap: options.chunk_overlap,
+            markdown_heading_level: (options.markdown_split == MarkdownSplit::Heading)
+                .then_some(options.markdown_heading_level),
+            embed_model: config.embed_model.clone(),
+            prompt_model: config.prompt_model.clone(),
+            vector_size: config.vector_size,
+            collection_name: config.collection_name.clone(),
+            files: stats.files,
+            chunks: stats.chunks,
+            indexed_at: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write manifest to {}", path.display()))?;
+        tracing::info!(path = %path.display(), "Wrote manifest");
+
+        Ok(())
+    }
+}
+
+/// Loads, chunks, enriches, embeds and stores all the code and markdown of the sources
+///
+/// The `chunk` and `metadata` features toggle chunking and the generated metadata, so their
+/// effect on the answers can be evaluated.
+#[tracing::instrument(skip_all)]
+pub async fn index_all(
+    options: &IndexOptions,
+    cache: Option<Redis>,
+    show_progress: bool,
+    llm: &Llm,
+    store: &dyn VectorStore,
+) -> Result<IndexStats> {
+    let paths = options
+        .sources
+        .iter()
+        .map(|source| source.origin.as_str())
+        .collect::<Vec<_>>();
+    tracing::info!(?paths, language=%options.language, "Indexing code");
+
+    let files = list_files(options);
+    if let Some(max_files) = options.max_files {
+        anyhow::ensure!(
+            files.len() <= max_files,
+            "Found {} files to index, more than the maximum of {max_files}. Narrow them down with \
+             --include, --exclude or --extensions, or raise --max-files or pass --yes if this is \
+             intended",
+            files.len()
+        );
+    }
+
+    let started = Instant::now();
+    let timings = Arc::new(Timings::default());
+    let errors = Arc::new(ErrorReport::default());
+
+    store.create_if_missing().await?;
+
+    if options.prune_deleted {
+        let deleted = options
+            .sources
+            .iter()
+            .flat_map(|source| &source.deleted)
+            .map(|path| path.to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        if !deleted.is_empty() {
+            tracing::info!(?deleted, "Removing the chunks of deleted files");
+            store.delete_paths(&deleted).await?;
+        }
+    }
+    let pruned = if options.prune {
+        prune_missing(options, store).await?
+    } else {
+        0
+    };
+
+    let progress = indexing_progress(files.len(), show_progress)?;
+    let mut listener = Interrupt::listen();
+    let interrupt = listener.interrupt.clone();
+
+    let checkpoint = options
+        .resume_from
+        .as_deref()
+        .map(Checkpoint::open)
+        .transpose()?
+        .map(Arc::new);
+    let (markdown, code) = chunk_all(options, cache, &timings, &interrupt, checkpoint.as_ref())?;
+    let mut code = code.filter(track_chunked(&progress));
+    let mut markdown = markdown.filter(track_chunked(&progress));
+
+    if cfg!(feature = "metadata") {
+        let mut qa_code = MetadataQACode::from_client(Shared(llm.prompt.clone()));
+        if let Some(template) = &options.qa_code_prompt {
+            qa_code.prompt_template(template.0.clone());
+        }
+        code = code.then(Timed::new(Phase::Metadata, &timings, qa_code.build()?));
+
+        // Generate questions and answers and them to the metadata of the node
+        let mut qa_text = MetadataQAText::from_client(Shared(llm.prompt.clone()));
+        if let Some(template) = &options.qa_text_prompt {
+            qa_text.prompt_template(template.0.clone());
+        }
+        markdown = markdown.then(Timed::new(Phase::Metadata, &timings, qa_text.build()?));
+    } else if options.qa_code_prompt.is_some() || options.qa_text_prompt.is_some() {
+        tracing::warn!("Built without the metadata feature, the metadata prompts are not used");
+    }
+
+    let mut pipeline = code.merge(markdown);
+
+    if options.git_metadata {
+        pipeline = pipeline.then(GitMetadata::default());
+    }
+
+    if store.named_vectors() {
+        pipeline = pipeline.then_in_batch(
+            options.embed_batch_size,
+            Timed::new(Phase::Embed, &timings, EmbedNamed(llm.embed.clone())),
+        );
+    } else {
+        pipeline = pipeline.then_in_batch(
+            options.embed_batch_size,
+            Timed::new(
+                Phase::Embed,
+                &timings,
+                SelectMetadata {
+                    inner: Embed::new(Shared(llm.embed.clone())),
+                    embed_metadata: options.embed_metadata,
+                },
+            ),
+        );
+    }
+    if store.hybrid() {
+        // The keywords of the same text as the dense embedding
+        pipeline = pipeline.then_in_batch(
+            options.embed_batch_size,
+            Timed::new(
+                Phase::Embed,
+                &timings,
+                SelectMetadata {
+                    inner: SparseEmbed::new(KeywordSparse),
+                    embed_metadata: options.embed_metadata,
+                },
+            ),
+        );
+    }
+    pipeline = pipeline.filter(errors.observe());
+    if !options.strict {
+        pipeline = pipeline.log_errors().filter_errors();
+    }
+
+    if let Some(embed_model) = options.embed_model.clone() {
+        pipeline = pipeline.then(move |mut node: Node| {
+            node.metadata.insert("embed_model", embed_model.clone());
+            Ok(node)
+        });
+    }
+
+    if let Some(export) = &options.export {
+        pipeline = pipeline.then(ExportJsonl::create(export)?);
+    }
+
+    let parquet = options
+        .embeddings_parquet
+        .as_deref()
+        .map(ExportParquet::create)
+        .transpose()?;
+    if let Some(parquet) = &parquet {
+        pipeline = pipeline.then(parquet.clone());
+    }
+
+    let stored = Arc::new(Mutex::new(HashSet::new()));
+    let run = pipeline
+        .then_store_with(Timed::new(Phase::Store, &timings, store.persist()))
+        .filter(track_stored(&progress))
+        .filter(collect_ids(&stored))
+        .filter(move |node| {
+            if let (Ok(node), Some(checkpoint)) = (node, &checkpoint) {
+                checkpoint.stored(node);
+            }
+            true
+        })
+        .run();
+    let result = tokio::select! {
+        result = run => result,
+        () = listener.stopped() => Err(anyhow::anyhow!(
+            "Indexing was stopped, index again to add the files that were left out"
+        )),
+    };
+    drop(listener);
+
+    progress.finish();
+
+    // Written even if storing failed, as the report may explain what went wrong
+    if let Some(error_report) = &options.error_report {
+        errors.write(error_report)?;
+    }
+    result?;
+
+    if let Some(parquet) = parquet {
+        parquet.finish()?;
+    }
+
+    let stored = std::mem::take(&mut *stored.lock().unwrap());
+    confirm_stored(store, &stored.into_iter().collect::<Vec<_>>()).await?;
+    if interrupt.requested() {
+        tracing::warn!("Indexing was interrupted, index again to add the files that were left out");
+    }
+
+    if options.timings {
+        timings.print_summary(started.elapsed());
+    }
+
+    // The progress bar counts the stored chunks even when it is hidden
+    let stats = IndexStats {
+        files: files.len(),
+        chunks: usize::try_from(progress.position())?,
+        errors: errors.len(),
+        points: store.count().await?,
+        pruned,
+    };
+    if stats.errors > 0 {
+        tracing::warn!(
+            chunks = stats.chunks,
+            points = stats.points,
+            pruned = stats.pruned,
+            failed = stats.errors,
+            "Finished indexing, leaving out the files that failed"
+        );
+    } else {
+        tracing::info!(
+            chunks = stats.chunks,
+            points = stats.points,
+            pruned = stats.pruned,
+            "Finished indexing"
+        );
+    }
+
+    // Querying an empty store answers every question with nothing found, which looks like
+    // retrieval is broken rather than indexing
+    anyhow::ensure!(
+        stats.points > 0,
+        "Nothing is in the store after indexing {} files, of which {} failed. Check the paths, \
+         --extensions and --include, and the error report for the files that failed",
+        stats.files,
+        stats.errors
+    );
+
+    Ok(stats)
+}
+
+/// Loads the code and markdown, filters cached nodes if a cache is given and chunks both
+///
+/// Returns the markdown and code pipelines separately so they can be processed further.
+fn chunk_all(
+    options: &IndexOptions,
+    cache: Option<Redis>,
+    timings: &Arc<Timings>,
+    interrupt: &Interrupt,
+    checkpoint: Option<&Arc<Checkpoint>>,
+) -> Result<(Pipeline, Pipeline)> {
+    let language = options.language;
+    let docs_extensions = options.docs_extensions.clone();
+
+    // Files are left out before the cache records them, so they are indexed on the next run
+    let interrupt = interrupt.clone();
+    let mut pipeline = load_all(options, timings)?.filter(move |_| !interrupt.requested());
+
+    if let Some(checkpoint) = checkpoint {
+        let checkpoint = Arc::clone(checkpoint);
+        pipeline = pipeline
+            .filter(move |node| node.as_ref().map_or(true, |node| !checkpoint.is_done(node)));
+    }
+
+    if let Some(cache) = cache {
+        pipeline = pipeline.filter_cached(cache);
+    }
+
+    let (markdown, code) = pipeline.split_by(move |node| {
+        // Any errors at this point we just pass to 'markdown'
+        let Ok(node) = node else { return true };
+
+        // On true we go 'markdown', on false we go 'code'. Anything that is not code in the
+        // languages, like markdown or extra extensions, is chunked as text.
+        let is_docs = node.path.extension().is_some_and(|ext| {
+            docs_extensions
+                .iter()
+                .any(|docs_ext| ext == docs_ext.as_str())
+        });
+        is_docs || language.of_path(&node.path).is_none()
+    });
+
+    // Code and markdown have very different latencies, so each branch gets its own concurrency.
+    // After merging, the concurrency of the code branch is used.
+    let mut code = code.with_concurrency(options.code_concurrency);
+    let mut markdown = markdown.with_concurrency(options.markdown_concurrency);
+
+    if cfg!(feature = "chunk") {
+        code = code
+            // Uses tree-sitter to extract best effort blocks of code. We still keep the minimum
+            // fairly high and double the chunk size
+            .then_chunk(Timed::new(
+                Phase::Chunk,
+                timings,
+                CheckpointFiles {
+                    inner: LogPerFile {
+                        inner: Overlapping {
+                            inner: LineNumbers(ChunkCodeByLanguage::new(language, |language| {
+                                options.code_chunk_range(language)
+                            })?),
+                            overlap: options.chunk_overlap,
+                        },
+                        info: options.log_per_file,
+                    },
+                    checkpoint: checkpoint.cloned(),
+                },
+            ));
+
+        markdown = markdown.then_chunk(Timed::new(
+            Phase::Chunk,
+            timings,
+            CheckpointFiles {
+                inner: LogPerFile {
+                    inner: Overlapping {
+                        inner: LineNumbers(ChunkMarkdownSections::new(
+                            options.chunk_range.clone(),
+                            match options.markdown_split {
+                                MarkdownSplit::Size => None,
+                                MarkdownSplit::Heading => Some(options.markdown_heading_level),
+                            },
+                        )),
+                        overlap: options.chunk_overlap,
+                    },
+                    info: options.log_per_file,
+                },
+                checkpoint: checkpoint.cloned(),
+            },
+        ));
+    } else if let Some(checkpoint) = checkpoint {
+        // Unchunked, every file is stored as a single chunk
+        let track = |checkpoint: Arc<Checkpoint>| {
+            move |node: Node| {
+                checkpoint.chunked(&node, 1);
+                Ok(node)
+            }
+        };
+        code = code.then(track(Arc::clone(checkpoint)));
+        markdown = markdown.then(track(Arc::clone(checkpoint)));
+    }
+
+    // Hashed right after chunking, so `verify` hashes exactly the chunks that are indexed
+    let hash = |mut node: Node| {
+        node.metadata
+            .insert("content_hash", content_hash(&node.chunk));
+        Ok(node)
+    };
+
+    Ok((markdown.then(hash), code.then(hash)))
+}
+
+/// Hex encoded sha256 of a chunk
+pub(crate) fn content_hash(chunk: &str) -> String {
+    Sha256::digest(chunk)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Loads the files of every source into a single pipeline
+///
+/// When indexing multiple sources, the origin of each node is added to its metadata as
+/// `source`, as the paths of git checkouts say nothing about where they came from.
+fn load_all(options: &IndexOptions, timings: &Arc<Timings>) -> Result<Pipeline> {
+    let tag_source = options.sources.len() > 1;
+
+    options
+        .sources
+        .iter()
+        .map(|source| {
+            // Only keep the files selected by --include and --exclude
+            let filter = options.filter.clone();
+            let root = source.path.clone();
+            let origin = source.origin.clone();
+
+            // Given files are loaded as they are, so they are selected up front. Swiftide's loader
+            // never follows symlinks, so the files are listed up front as well to follow them.
+            let pipeline = if let Some(text) = &source.text {
+                Pipeline::from_loader(Timed::new(
+                    Phase::Load,
+                    timings,
+                    Text(source.path.clone(), text.clone()),
+                ))
+            } else if source.files.is_some() || options.follow_symlinks {
+                Pipeline::from_loader(Timed::new(
+                    Phase::Load,
+                    timings,
+                    FileList(source_files(source, options)),
+                ))
+            } else {
+                let text_extensions = options
+                    .extensions
+                    .iter()
+                    .filter(|ext| *ext != "pdf")
+                    .collect::<Vec<_>>();
+                let pipeline = Pipeline::from_loader(Timed::new(
+                    Phase::Load,
+                    timings,
+                    FileLoader::new(&source.path).with_extensions(&text_extensions),
+                ));
+
+                // The file loader reads every file as text, so PDFs are loaded separately
+                if text_extensions.len() < options.extensions.len() {
+                    let pdfs = source_files(source, options)
+                        .into_iter()
+                        .filter(|path| is_pdf(path))
+                        .collect();
+                    pipeline.merge(Pipeline::from_loader(Timed::new(
+                        Phase::Load,
+                        timings,
+                        FileList(pdfs),
+                    )))
+                } else {
+                    pipeline
+                }
+            };
+
+            let mut pipeline = pipeline.filter(move |node| {
+                node.as_ref()
+                    .map_or(true, |node| filter.matches(&root, &node.path))
+            });
+
+            if tag_source {
+                pipeline = pipeline.then(move |mut node: Node| {
+                    node.metadata.insert("source", origin.clone());
+                    Ok(node)
+                });
+            }
+
+            pipeline
+        })
+        .reduce(Pipeline::merge)
+        .context("Expected at least one path to index")
+}
+
+/// Progress of the chunks being indexed, hidden if disabled or stdout is not a terminal
+///
+/// The number of chunks is only known after chunking, so the total grows while indexing.
+fn indexing_progress(files: usize, enabled: bool) -> Result<ProgressBar> {
+    if !enabled || !std::io::stdout().is_terminal() {
+        return Ok(ProgressBar::hidden());
+    }
+
+    Ok(ProgressBar::new(0)
+        .with_style(ProgressStyle::with_template(
+            "{msg} {wide_bar} {pos}/{len} chunks [{elapsed}]",
+        )?)
+        .with_message(format!("Indexing {files} files")))
+}
+
+/// Grows the total of the progress bar for every chunk passing through
+fn track_chunked(progress: &ProgressBar) -> impl Fn(&Result<Node>) -> bool {
+    let progress = progress.clone();
+    move |_| {
+        progress.inc_length(1);
+        true
+    }
+}
+
+/// Records the ids of the chunks passing through, which are the ids of their points in Qdrant
+fn collect_ids(ids: &Arc<Mutex<HashSet<String>>>) -> impl Fn(&Result<Node>) -> bool {
+    let ids = Arc::clone(ids);
+    move |node| {
+        if let Ok(node) = node {
+            ids.lock().unwrap().insert(node.id().to_string());
+        }
+        true
+    }
+}
+
+/// Checks that every stored chunk can be read back, waiting a little for writes still being
+/// applied, and warns about those that cannot
+///
+/// Swiftide only waits for Qdrant to apply writes in debug builds, so the last batch may not be
+/// visible yet when indexing is done.
+async fn confirm_stored(store: &dyn VectorStore, ids: &[String]) -> Result<()> {
+    let mut missing = store.missing(ids).await?;
+    for _ in 1..CONFIRM_ATTEMPTS {
+        if missing == 0 {
+            break;
+        }
+        tokio::time::sleep(CONFIRM_INTERVAL).await;
+        missing = store.missing(ids).await?;
+    }
+
+    if missing > 0 {
+        tracing::warn!(
+            stored = ids.len(),
+            missing,
+            "Not every chunk that was stored is in the store, the index is incomplete"
+        );
+    } else {
+        tracing::debug!(stored = ids.len(), "Confirmed every chunk is in the store");
+    }
+
+    Ok(())
+}
+
+/// Stops indexing on Ctrl-C without losing what is being indexed
+///
+/// New files are no longer passed on, while the chunks already on their way are still stored,
+/// including the last partial batch. A second Ctrl-C stops storing them as well, see
+/// [`InterruptListener::stopped`].
+#[derive(Clone, Debug, Default)]
+struct Interrupt(Arc<AtomicBool>);
+
+/// Number of indexing runs listening for Ctrl-C
+static LISTENING: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether an indexing run handles Ctrl-C, which anything else listening for it should ignore
+pub fn indexing_handles_interrupts() -> bool {
+    LISTENING.load(Ordering::SeqCst) > 0
+}
+
+impl Interrupt {
+    /// Listens for Ctrl-C until the listener is dropped
+    fn listen() -> InterruptListener {
+        let interrupt = Self::default();
+        let requested = Arc::clone(&interrupt.0);
+        LISTENING.fetch_add(1, Ordering::SeqCst);
+        let task = tokio::spawn(async move {
+            // Without a signal handler there is nothing to stop on
+            if tokio::signal::ctrl_c().await.is_err() {
+                return std::future::pending().await;
+            }
+            requested.store(true, Ordering::SeqCst);
+            tracing::warn!(
+                "Interrupted, storing what is being indexed, press Ctrl-C again to stop right away"
+            );
+            if tokio::signal::ctrl_c().await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        });
+
+        InterruptListener { interrupt, task }
+    }
+
+    fn requested(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Handles Ctrl-C for an indexing run, stops when dropped
+struct InterruptListener {
+    interrupt: Interrupt,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl InterruptListener {
+    /// Completes on the second Ctrl-C, to stop indexing right away
+    async fn stopped(&mut self) {
+        let _ = (&mut self.task).await;
+    }
+}
+
+impl Drop for InterruptListener {
+    fn drop(&mut self) {
+        self.task.abort();
+        LISTENING.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Advances the progress bar for every chunk passing through
+fn track_stored(progress: &ProgressBar) -> impl Fn(&Result<Node>) -> bool {
+    let progress = progress.clone();
+    move |_| {
+        progress.inc(1);
+        true
+    }
+}
+
+/// Compares the chunks of the sources to those in the store by their `content_hash`, without
+/// embedding anything
+///
+/// Every file in the store is compared, so files of other sources indexed into the same store
+/// show up as removed. Chunks indexed before their hashes were stored show up as changed.
+pub async fn verify(options: &IndexOptions, store: &dyn VectorStore) -> Result<Drift> {
+    let (markdown, code) = chunk_all(options, None, &Arc::default(), &Interrupt::default(), None)?;
+
+    let chunked = Arc::new(Mutex::new(HashMap::<String, Vec<String>>::new()));
+    let collect_hashes = {
+        let chunked = Arc::clone(&chunked);
+        move |node: Node| {
+            let hash = node
+                .metadata
+                .get("content_hash")
+                .and_then(|hash| hash.as_str())
+                .context("Expected a content hash")?
+                .to_string();
+            chunked
+                .lock()
+                .unwrap()
+                .entry(node.path.to_string_lossy().to_string())
+                .or_default()
+                .push(hash);
+            Ok(node)
+        }
+    };
+
+    code.merge(markdown)
+        .then(collect_hashes)
+        .then_store_with(MemoryStorage::default())
+        .run()
+        .await?;
+
+    let mut stored = HashMap::<String, Vec<String>>::new();
+    for (path, hash) in store.content_hashes().await? {
+        stored
+            .entry(path)
+            .or_default()
+            .push(hash.unwrap_or_default());
+    }
+
+    let chunked = std::mem::take(&mut *chunked.lock().unwrap());
+    let paths = chunked.keys().chain(stored.keys()).collect::<HashSet<_>>();
+
+    let mut drift = Drift {
+        files: BTreeMap::new(),
+        checked: paths.len(),
+    };
+    for path in paths {
+        let empty = Vec::new();
+        let chunked = chunked.get(path).unwrap_or(&empty);
+        let stored = stored.get(path).unwrap_or(&empty);
+
+        // Identical chunks can occur more than once in a file, so they are counted
+        let mut counts = HashMap::<&str, i64>::new();
+        for hash in chunked {
+            *counts.entry(hash).or_default() += 1;
+        }
+        for hash in stored {
+            *counts.entry(hash).or_default() -= 1;
+        }
+        let added = counts.values().filter(|count| **count > 0).sum::<i64>();
+        let removed = -counts.values().filter(|count| **count < 0).sum::<i64>();
+
+        if added > 0 || removed > 0 {
+            let kind = if stored.is_empty() {
+                DriftKind::Added
+            } else if chunked.is_empty() {
+                DriftKind::Removed
+            } else {
+                DriftKind::Changed
+            };
+            drift.files.insert(
+                path.clone(),
+                FileDrift {
+                    kind,
+                    added: added.unsigned_abs(),
+                    removed: removed.unsigned_abs(),
+                },
+            );
+        }
+    }
+
+    Ok(drift)
+}
+
+/// The files whose chunks differ between the sources and the store
+#[derive(Debug)]
+pub struct Drift {
+    pub files: BTreeMap<String, FileDrift>,
+    /// Number of files in the sources or the store
+    pub checked: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct FileDrift {
+    pub kind: DriftKind,
+    /// Chunks in the sources that are not in the store
+    pub added: u64,
+    /// Chunks in the store that are not in the sources
+    pub removed: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DriftKind {
+    /// Not indexed yet
+    Added,
+    /// Deleted from the sources
+    Removed,
+    Changed,
+}
+
+impl Drift {
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    fn count(&self, kind: DriftKind) -> usize {
+        self.files.values().filter(|file| file.kind == kind).count()
+    }
+}
+
+impl std::fmt::Display for Drift {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "No drift in {} files", self.checked);
+        }
+
+        for (path, file) in &self.files {
+            let kind = match file.kind {
+                DriftKind::Added => "added",
+                DriftKind::Removed => "removed",
+                DriftKind::Changed => "changed",
+            };
+            writeln!(
+                f,
+                "{kind:<8} {path} (+{} -{} chunks)",
+                file.added, file.removed
+            )?;
+        }
+        writeln!(f)?;
+        writeln!(
+            f,
+            "{} added, {} removed and {} changed of {} files",
+            self.count(DriftKind::Added),
+            self.count(DriftKind::Removed),
+            self.count(DriftKind::Changed),
+            self.checked
+        )
+    }
+}
+
+/// Counts the tokens of the chunks in a [`dry_run`], to estimate the cost of embedding them
+pub struct TokenEstimate {
+    embed_model: String,
+    bpe: &'static CoreBPE,
+    price: Option<Price>,
+}
+
+impl TokenEstimate {
+    /// Counts with the tokenizer of the model, falling back to the one of GPT-4 like
+    /// [`TokenBudget`](crate::TokenBudget)
+    pub fn new(embed_model: &str, prices: &Prices) -> Self {
+        Self {
+            embed_model: embed_model.to_string(),
+            bpe: tiktoken_rs::bpe_for_model(embed_model)
+                .unwrap_or_else(|_| tiktoken_rs::cl100k_base_singleton()),
+            price: prices.0.get(embed_model).copied(),
+        }
+    }
+
+    fn count(&self, text: &str) -> usize {
+        self.bpe.encode_with_special_tokens(text).len()
+    }
+}
+
+/// Runs the loading and chunking steps only and prints what would be indexed, with the tokens
+/// of every file if given a [`TokenEstimate`]
+///
+/// Does not call any LLM, so it is free to run on large repositories before committing to
+/// indexing them. The cache is skipped, as filtering on it would mark the nodes as cached.
+pub async fn dry_run(options: &IndexOptions, tokens: Option<TokenEstimate>) -> Result<()> {
+    let (markdown, code) = chunk_all(options, None, &Arc::default(), &Interrupt::default(), None)?;
+
+    let tokens = tokens.map(Arc::new);
+    let chunks = Arc::new(Mutex::new(BTreeMap::<PathBuf, (&str, usize, usize)>::new()));
+    let count_chunks = |kind: &'static str| {
+        let chunks = Arc::clone(&chunks);
+        let tokens = tokens.clone();
+        move |node: Node| {
+            let count = tokens
+                .as_ref()
+                .map(|tokens| tokens.count(&node.chunk))
+                .unwrap_or_default();
+
+            let mut chunks = chunks.lock().unwrap();
+            let file = chunks.entry(node.path.clone()).or_insert((kind, 0, 0));
+            file.1 += 1;
+            file.2 += count;
+            Ok(node)
+        }
+    };
+
+    code.then(count_chunks("code"))
+        .merge(markdown.then(count_chunks("markdown")))
+        .then_store_with(MemoryStorage::default())
+        .run()
+        .await?;
+
+    let chunks = chunks.lock().unwrap();
+    let width = chunks
+        .keys()
+        .map(|path| path.display().to_string().len())
+        .max()
+        .unwrap_or_default()
+        .max("PATH".len());
+
+    match &tokens {
+        Some(_) => {
+            println!("{:<width$}  {:<8}  {:>8}  TOKENS", "PATH", "TYPE", "CHUNKS");
+            for (path, (kind, count, tokens)) in chunks.iter() {
+                println!(
+                    "{:<width$}  {kind:<8}  {count:>8}  {tokens}",
+                    path.display().to_string()
+                );
+            }
+        }
+        None => {
+            println!("{:<width$}  {:<8}  CHUNKS", "PATH", "TYPE");
+            for (path, (kind, count, _)) in chunks.iter() {
+                println!("{:<width$}  {kind:<8}  {count}", path.display().to_string());
+            }
+        }
+    }
+
+    let total_chunks = chunks.values().map(|(_, count, _)| count).sum::<usize>();
+    println!();
+    println!("{} files, {total_chunks} chunks", chunks.len());
+    println!(
+        "Estimated embedding calls: {}",
+        total_chunks.div_ceil(options.embed_batch_size)
+    );
+
+    if let Some(estimate) = &tokens {
+        let total_tokens = chunks.values().map(|(_, _, tokens)| tokens).sum::<usize>();
+        println!("Embedding tokens: {total_tokens}");
+        match estimate.price {
+            Some(price) => println!(
+                "Estimated embedding cost: ${:.6} with {}",
+                total_tokens as f64 * price.input / 1000.,
+                estimate.embed_model
+            ),
+            None => println!(
+                "Estimated embedding cost: unknown, no price for {}, pass --prices",
+                estimate.embed_model
+            ),
+        }
+        // The questions and answers are generated while indexing, so they cannot be counted
+        if options.embed_metadata != EmbedMetadata::ContentOnly {
+            println!("The metadata embedded with the chunks adds to the tokens and cost");
+        }
+    }
+
+    Ok(())
+}
+
+/// Collects the errors passing through the pipeline, to write them to a report
+#[derive(Debug, Default)]
+struct ErrorReport {
+    errors: Mutex<Vec<ReportedError>>,
+}
+
+/// A file that failed to index, files failing together in a batch are reported separately
+#[derive(Serialize, Debug)]
+struct ReportedError {
+    /// Unknown if a file failed to load
+    path: Option<PathBuf>,
+    phase: Option<&'static str>,
+    error: String,
+}
+
+impl ErrorReport {
+    /// Records every error passing through, without filtering anything
+    fn observe(self: &Arc<Self>) -> impl Fn(&Result<Node>) -> bool {
+        let report = Arc::clone(self);
+        move |node| {
+            if let Err(err) = node {
+                report.record(err);
+            }
+            true
+        }
+    }
+
+    fn record(&self, err: &anyhow::Error) {
+        let error = format!("{err:#}");
+        let mut errors = self.errors.lock().unwrap();
+
+        match err.downcast_ref::<FailedStep>() {
+            Some(failed) => errors.extend(failed.paths.iter().map(|path| ReportedError {
+                path: Some(path.clone()),
+                phase: Some(failed.phase.name()),
+                error: error.clone(),
+            })),
+            None => errors.push(ReportedError {
+                path: None,
+                phase: None,
+                error,
+            }),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.errors.lock().unwrap().len()
+    }
+
+    fn write(&self, path: &Path) -> Result<()> {
+        let errors = self.errors.lock().unwrap();
+        std::fs::write(path, serde_json::to_string_pretty(&*errors)?)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        tracing::info!(errors = errors.len(), path = %path.display(), "Wrote error report");
+
+        Ok(())
+    }
+}
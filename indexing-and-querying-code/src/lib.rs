@@ -0,0 +1,55 @@
+//! Indexes code with Swiftide and answers questions about it
+//!
+//! The `indexing-and-querying-code` binary is a thin command line wrapper around this library.
+//! [`index`](index()) indexes the code described by [`IndexOptions`] into a store,
+//! [`query`](query()) answers a question from the stores in a [`QueryContext`]. Both take their
+//! LLM clients as an [`Llm`], built with [`build_llm`].
+
+mod cache;
+mod checkpoint;
+mod chunk;
+mod eval;
+mod export;
+mod filter;
+mod index;
+mod llm;
+mod metadata;
+mod query;
+mod source;
+mod store;
+mod telemetry;
+mod timing;
+
+pub use cache::{cache_prefix, CacheKind, EmbeddingCache, QueryCache};
+pub use checkpoint::Checkpoint;
+pub use chunk::{
+    default_chunk_range, language_of_file, parse_language, resolve_extensions, Languages,
+    MarkdownSplit,
+};
+pub use eval::{
+    answer_questions, evaluate, evaluation_rows, generate_questions, ground_truth_dataset,
+    score_evaluation, to_csv, EvalContext, EvaluationRow, EvaluationScores, EvaluationSummary,
+    QuestionAnswer,
+};
+pub use filter::{FileFilter, PathFilter};
+pub use index::{
+    dry_run, index, index_all, indexing_handles_interrupts, verify, Drift, DriftKind, FileDrift,
+    IndexOptions, IndexStats, Manifest, TokenEstimate,
+};
+pub use llm::{
+    build_llm, openai_api_key, validate_vector_size, vector_size_for_model, Azure, Backend, Cohere,
+    EmbedBackend, HttpOptions, Llm, Price, Prices, StreamingPrompt, Usage,
+};
+pub use metadata::{EmbedMetadata, MetadataTemplate};
+pub use query::{
+    converse, query, AnswerFormat, AnswerTemplate, Attribution, Citation, Conversation, OnEmpty,
+    QueryContext, QueryMode, QueryResult, TokenBudget, DEFAULT_NUM_SUBQUESTIONS,
+    MAX_NUM_SUBQUESTIONS,
+};
+pub use source::{sample_files, Source};
+pub use store::{
+    build_store, check_embed_model, preflight, CollectionConfig, KeywordSparse, MemoryVectorStore,
+    QdrantStore, Retrieved, SearchVector, StoreConfig, StoreKind, StoredChunk, VectorDistance,
+    VectorStore, EMBED_BATCH_SIZE, EMBED_TOKEN_BUDGET,
+};
+pub use telemetry::{init_tracing, TracingGuard};
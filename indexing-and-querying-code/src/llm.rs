@@ -0,0 +1,1270 @@
+//! LLM clients for prompting and embedding, and the wrappers that meter, limit and retry them
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{Context as _, Result};
+use async_openai::types::{
+    ChatCompletionRequestUserMessageArgs, ChatCompletionStreamOptions,
+    CreateChatCompletionRequestArgs, CreateEmbeddingRequestArgs,
+};
+use async_trait::async_trait;
+use ollama_rs::generation::completion::request::GenerationRequest;
+use serde::Deserialize;
+use swiftide::{
+    integrations::ollama::Ollama,
+    prompt::Prompt,
+    traits::{EmbeddingModel, SimplePrompt},
+    Embeddings,
+};
+use tokio_stream::StreamExt as _;
+
+use crate::{
+    cache::{CachedEmbeddings, EmbeddingCache},
+    query::TokenBudget,
+    store::Retrieved,
+};
+
+/// Upper bound for the wait between retries of a failed LLM call
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum Backend {
+    #[value(name = "openai")]
+    OpenAI,
+    Ollama,
+}
+
+impl Backend {
+    pub fn default_embed_model(self) -> &'static str {
+        match self {
+            Backend::OpenAI => "text-embedding-3-small",
+            Backend::Ollama => "nomic-embed-text",
+        }
+    }
+
+    pub fn default_index_prompt_model(self) -> &'static str {
+        match self {
+            Backend::OpenAI => "gpt-3.5-turbo",
+            Backend::Ollama => "llama3",
+        }
+    }
+
+    pub fn default_query_prompt_model(self) -> &'static str {
+        match self {
+            Backend::OpenAI => "gpt-4o",
+            Backend::Ollama => "llama3",
+        }
+    }
+
+    pub fn default_eval_prompt_model(self) -> &'static str {
+        match self {
+            Backend::OpenAI => "gpt-4o-mini",
+            Backend::Ollama => "llama3",
+        }
+    }
+}
+
+/// A backend for embeddings only, which can differ from the backend for prompts
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum EmbedBackend {
+    #[value(name = "openai")]
+    OpenAI,
+    Ollama,
+    Cohere,
+}
+
+impl EmbedBackend {
+    pub fn default_embed_model(self) -> &'static str {
+        match self {
+            EmbedBackend::OpenAI => Backend::OpenAI.default_embed_model(),
+            EmbedBackend::Ollama => Backend::Ollama.default_embed_model(),
+            EmbedBackend::Cohere => "embed-english-v3.0",
+        }
+    }
+
+    /// The backend that also serves prompts, Cohere is only used for embeddings
+    pub fn backend(self) -> Option<Backend> {
+        match self {
+            EmbedBackend::OpenAI => Some(Backend::OpenAI),
+            EmbedBackend::Ollama => Some(Backend::Ollama),
+            EmbedBackend::Cohere => None,
+        }
+    }
+}
+
+/// The embedding and prompt clients for a phase, independent of the backend that provides them
+#[derive(Clone)]
+pub struct Llm {
+    pub embed: Arc<dyn EmbeddingModel>,
+    pub prompt: Arc<dyn SimplePrompt>,
+    pub stream: Arc<dyn StreamingPrompt>,
+}
+
+impl Llm {
+    /// Wraps both clients so transient errors are retried up to `max_retries` times
+    ///
+    /// Streaming is not retried, as part of the answer might have been printed already.
+    pub fn with_retries(self, max_retries: u32) -> Llm {
+        Llm {
+            stream: self.stream,
+            embed: Arc::new(Retrying {
+                inner: self.embed,
+                max_retries,
+            }),
+            prompt: Arc::new(Retrying {
+                inner: self.prompt,
+                max_retries,
+            }),
+        }
+    }
+
+    /// Limits the requests in flight of both clients together to at most `max`, fewer while
+    /// they are rate limited
+    ///
+    /// Streaming is not limited, it is a single request per question.
+    pub fn with_adaptive_concurrency(self, max: usize) -> Llm {
+        let limit = Arc::new(AdaptiveLimit::new(max));
+        Llm {
+            stream: self.stream,
+            embed: Arc::new(Throttled {
+                inner: self.embed,
+                limit: Arc::clone(&limit),
+            }),
+            prompt: Arc::new(Throttled {
+                inner: self.prompt,
+                limit,
+            }),
+        }
+    }
+
+    /// Looks up embeddings in the cache before embedding, only the missing ones are embedded and
+    /// then added to it
+    pub fn with_embedding_cache(self, embed_model: &str, cache: EmbeddingCache) -> Llm {
+        Llm {
+            embed: Arc::new(CachedEmbeddings {
+                inner: self.embed,
+                model: embed_model.to_string(),
+                cache,
+            }),
+            ..self
+        }
+    }
+
+    /// Splits the batches to embed so no request has more than `max_tokens` tokens, counted with
+    /// the tokenizer of `embed_model`
+    pub fn with_embed_token_budget(self, embed_model: &str, max_tokens: usize) -> Llm {
+        Llm {
+            embed: Arc::new(TokenBatched {
+                inner: self.embed,
+                budget: TokenBudget::for_model(embed_model, max_tokens),
+            }),
+            ..self
+        }
+    }
+
+    /// Scales every embedding to length one, so the dot product of two of them is their cosine
+    pub fn with_normalized_embeddings(self) -> Llm {
+        Llm {
+            embed: Arc::new(Normalized(self.embed)),
+            ..self
+        }
+    }
+}
+
+/// Builds the clients for the backend, the tokens used with OpenAI are counted in `usage`
+///
+/// OpenAI is reached through Azure instead of its public api if `azure` is given. Without an
+/// `api_key` it is read from `OPENAI_API_KEY`, see [`openai_api_key`].
+#[allow(clippy::too_many_arguments)]
+pub fn build_llm(
+    backend: Backend,
+    ollama_url: &str,
+    azure: Option<&Azure>,
+    api_key: Option<&str>,
+    http: &HttpOptions,
+    embed_model: &str,
+    prompt_model: &str,
+    usage: &Arc<Usage>,
+) -> Result<Llm> {
+    Ok(match backend {
+        Backend::OpenAI => {
+            let http_client = http.client()?;
+            let proxy = http.proxy_display();
+            match azure {
+                Some(azure) => {
+                    let api_key = azure.api_key(api_key)?;
+                    build_openai(
+                        |model| {
+                            async_openai::Client::with_config(azure.config(model, &api_key))
+                                .with_http_client(http_client.clone())
+                        },
+                        proxy,
+                        embed_model,
+                        prompt_model,
+                        usage,
+                    )
+                }
+                None => {
+                    let config = async_openai::config::OpenAIConfig::new()
+                        .with_api_key(openai_api_key(api_key)?);
+                    let client =
+                        async_openai::Client::with_config(config).with_http_client(http_client);
+                    build_openai(|_| client.clone(), proxy, embed_model, prompt_model, usage)
+                }
+            }
+        }
+        Backend::Ollama => {
+            let client = ollama_rs::Ollama::try_new(ollama_url)?;
+            let ollama = Ollama::builder()
+                .client(client.clone())
+                .default_embed_model(embed_model)
+                .default_prompt_model(prompt_model)
+                .build()?;
+
+            Llm {
+                embed: Arc::new(ollama.clone()),
+                prompt: Arc::new(ollama),
+                stream: Arc::new(OllamaStream {
+                    client,
+                    model: prompt_model.to_string(),
+                }),
+            }
+        }
+    })
+}
+
+/// The given api key, or `OPENAI_API_KEY` from the environment
+///
+/// Fails with how to set the key when there is none, instead of with the error OpenAI returns on
+/// the first request.
+pub fn openai_api_key(api_key: Option<&str>) -> Result<String> {
+    if let Some(api_key) = api_key.filter(|api_key| !api_key.is_empty()) {
+        return Ok(api_key.to_string());
+    }
+
+    match std::env::var("OPENAI_API_KEY") {
+        Ok(api_key) if !api_key.is_empty() => Ok(api_key),
+        _ => anyhow::bail!(
+            "No OpenAI api key found. Set OPENAI_API_KEY, for example with \
+             `export OPENAI_API_KEY=sk-...` or a line `OPENAI_API_KEY=sk-...` in a .env file, or \
+             pass --api-key"
+        ),
+    }
+}
+
+/// How to reach OpenAI from networks that only allow it through a proxy
+#[derive(Clone, Debug, Default)]
+pub struct HttpOptions {
+    /// Url of the proxy to send every request through
+    pub proxy: Option<String>,
+    /// Pem file with a certificate to trust on top of the system ones, for proxies inspecting TLS
+    pub ca_cert: Option<PathBuf>,
+}
+
+impl HttpOptions {
+    fn client(&self) -> Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder();
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(
+                reqwest::Proxy::all(proxy).with_context(|| format!("Invalid proxy {proxy}"))?,
+            );
+        }
+        if let Some(path) = &self.ca_cert {
+            let pem = std::fs::read(path)
+                .with_context(|| format!("Failed to read CA certificate {}", path.display()))?;
+            let certificate = reqwest::Certificate::from_pem(&pem)
+                .with_context(|| format!("Invalid CA certificate {}", path.display()))?;
+            builder = builder.add_root_certificate(certificate);
+        }
+
+        Ok(builder.build()?)
+    }
+
+    /// The proxy without the password it may contain, to mention in errors
+    fn proxy_display(&self) -> Option<String> {
+        let proxy = self.proxy.as_deref()?;
+        Some(match reqwest::Url::parse(proxy) {
+            Ok(mut url) => {
+                let _ = url.set_password(None);
+                url.to_string()
+            }
+            Err(_) => proxy.to_string(),
+        })
+    }
+}
+
+/// Builds the OpenAI clients, with `client` returning the client to reach a model with
+fn build_openai<C>(
+    client: impl Fn(&str) -> async_openai::Client<C>,
+    proxy: Option<String>,
+    embed_model: &str,
+    prompt_model: &str,
+    usage: &Arc<Usage>,
+) -> Llm
+where
+    C: async_openai::config::Config + std::fmt::Debug + Send + Sync + 'static,
+{
+    Llm {
+        embed: Arc::new(MeteredOpenAI {
+            client: client(embed_model),
+            model: embed_model.to_string(),
+            usage: Arc::clone(usage),
+            proxy: proxy.clone(),
+        }),
+        prompt: Arc::new(MeteredOpenAI {
+            client: client(prompt_model),
+            model: prompt_model.to_string(),
+            usage: Arc::clone(usage),
+            proxy: proxy.clone(),
+        }),
+        stream: Arc::new(OpenAIStream {
+            client: client(prompt_model),
+            model: prompt_model.to_string(),
+            usage: Arc::clone(usage),
+            proxy,
+        }),
+    }
+}
+
+/// An Azure OpenAI resource, which serves every model from a deployment of its own
+#[derive(Clone, Debug)]
+pub struct Azure {
+    /// Base url of the resource, like `https://<resource>.openai.azure.com`
+    pub endpoint: String,
+    pub api_version: String,
+    /// Deployment of each model, models without one are expected to be deployed by their name
+    pub deployments: HashMap<String, String>,
+}
+
+impl Azure {
+    /// The api key from `AZURE_OPENAI_API_KEY`, falling back to [`openai_api_key`]
+    fn api_key(&self, api_key: Option<&str>) -> Result<String> {
+        match std::env::var("AZURE_OPENAI_API_KEY") {
+            Ok(api_key) if !api_key.is_empty() => Ok(api_key),
+            _ => openai_api_key(api_key).map_err(|_| {
+                anyhow::anyhow!(
+                    "No Azure OpenAI api key found. Set AZURE_OPENAI_API_KEY, in the environment \
+                     or a .env file, or pass --api-key"
+                )
+            }),
+        }
+    }
+
+    fn config(&self, model: &str, api_key: &str) -> async_openai::config::AzureConfig {
+        let deployment = self.deployments.get(model).map_or(model, String::as_str);
+        async_openai::config::AzureConfig::new()
+            .with_api_base(&self.endpoint)
+            .with_api_version(&self.api_version)
+            .with_deployment_id(deployment)
+            .with_api_key(api_key)
+    }
+}
+
+/// Qdrant needs to know the dimensions of the vectors up front, which depend on the model
+pub fn vector_size_for_model(embed_model: &str) -> Result<u64> {
+    Ok(match embed_model {
+        "text-embedding-3-small" | "text-embedding-ada-002" => 1536,
+        "text-embedding-3-large" => 3072,
+        "nomic-embed-text" => 768,
+        "mxbai-embed-large" => 1024,
+        "all-minilm" => 384,
+        "embed-english-v3.0" | "embed-multilingual-v3.0" => 1024,
+        "embed-english-light-v3.0" | "embed-multilingual-light-v3.0" => 384,
+        other => anyhow::bail!(
+            "Unknown vector size for embedding model {other}, please provide it with --vector-size"
+        ),
+    })
+}
+
+/// Embeds a short probe to verify the model returns vectors of the expected size
+///
+/// Qdrant only complains about mismatched dimensions when storing, which is after all the
+/// expensive work has been done.
+pub async fn validate_vector_size(embed: &dyn EmbeddingModel, expected: u64) -> Result<()> {
+    let actual = embed
+        .embed(vec!["vector size probe".to_string()])
+        .await
+        .context("Failed to embed vector size probe")?
+        .pop()
+        .context("Expected embedding")?
+        .len();
+
+    if actual as u64 != expected {
+        anyhow::bail!(
+            "Embedding model returns vectors of size {actual}, but the vector size is {expected}"
+        );
+    }
+
+    Ok(())
+}
+
+/// Prompts that can hand out the answer while it is being generated
+///
+/// Swiftide's clients only return complete answers, so this talks to the backends directly.
+#[async_trait]
+pub trait StreamingPrompt: Send + Sync {
+    /// Calls `on_token` for every piece of the answer as it arrives and returns the full answer
+    async fn prompt_streaming(
+        &self,
+        prompt: Prompt,
+        on_token: &mut (dyn for<'a> FnMut(&'a str) + Send),
+    ) -> Result<String>;
+}
+
+struct OpenAIStream<C: async_openai::config::Config> {
+    client: async_openai::Client<C>,
+    model: String,
+    usage: Arc<Usage>,
+    proxy: Option<String>,
+}
+
+#[async_trait]
+impl<C> StreamingPrompt for OpenAIStream<C>
+where
+    C: async_openai::config::Config + std::fmt::Debug + Send + Sync,
+{
+    async fn prompt_streaming(
+        &self,
+        prompt: Prompt,
+        on_token: &mut (dyn for<'a> FnMut(&'a str) + Send),
+    ) -> Result<String> {
+        let request = CreateChatCompletionRequestArgs::default()
+            .model(&self.model)
+            .messages([ChatCompletionRequestUserMessageArgs::default()
+                .content(prompt.render().await?)
+                .build()?
+                .into()])
+            // The usage is sent in a last chunk without choices
+            .stream_options(ChatCompletionStreamOptions {
+                include_usage: true,
+            })
+            .build()?;
+
+        let mut stream = self
+            .client
+            .chat()
+            .create_stream(request)
+            .await
+            .with_context(|| request_failed(self.proxy.as_deref()))?;
+        let mut answer = String::new();
+        while let Some(response) = stream.next().await {
+            let response = response?;
+            for choice in response.choices {
+                if let Some(content) = choice.delta.content {
+                    on_token(&content);
+                    answer.push_str(&content);
+                }
+            }
+            if let Some(usage) = response.usage {
+                self.usage
+                    .record(&self.model, usage.prompt_tokens, usage.completion_tokens);
+            }
+        }
+
+        Ok(answer)
+    }
+}
+
+/// Calls OpenAI like Swiftide's client does, but counts the tokens used in the responses
+#[derive(Debug)]
+struct MeteredOpenAI<C: async_openai::config::Config> {
+    client: async_openai::Client<C>,
+    model: String,
+    usage: Arc<Usage>,
+    /// Mentioned when a request fails, as the proxy is often what is in the way
+    proxy: Option<String>,
+}
+
+fn request_failed(proxy: Option<&str>) -> String {
+    match proxy {
+        Some(proxy) => format!("Request to OpenAI through proxy {proxy} failed"),
+        None => "Request to OpenAI failed".to_string(),
+    }
+}
+
+#[async_trait]
+impl<C> EmbeddingModel for MeteredOpenAI<C>
+where
+    C: async_openai::config::Config + std::fmt::Debug + Send + Sync,
+{
+    async fn embed(&self, input: Vec<String>) -> Result<Embeddings> {
+        let request = CreateEmbeddingRequestArgs::default()
+            .model(&self.model)
+            .input(&input)
+            .build()?;
+
+        let response = self
+            .client
+            .embeddings()
+            .create(request)
+            .await
+            .with_context(|| request_failed(self.proxy.as_deref()))?;
+        self.usage
+            .record(&self.model, response.usage.prompt_tokens, 0);
+
+        Ok(response
+            .data
+            .into_iter()
+            .map(|embedding| embedding.embedding)
+            .collect())
+    }
+}
+
+#[async_trait]
+impl<C> SimplePrompt for MeteredOpenAI<C>
+where
+    C: async_openai::config::Config + std::fmt::Debug + Send + Sync,
+{
+    async fn prompt(&self, prompt: Prompt) -> Result<String> {
+        let request = CreateChatCompletionRequestArgs::default()
+            .model(&self.model)
+            .messages([ChatCompletionRequestUserMessageArgs::default()
+                .content(prompt.render().await?)
+                .build()?
+                .into()])
+            .build()?;
+
+        let response = self
+            .client
+            .chat()
+            .create(request)
+            .await
+            .with_context(|| request_failed(self.proxy.as_deref()))?;
+        if let Some(usage) = response.usage {
+            self.usage
+                .record(&self.model, usage.prompt_tokens, usage.completion_tokens);
+        }
+
+        response
+            .choices
+            .into_iter()
+            .next()
+            .and_then(|choice| choice.message.content)
+            .context("Expected content in response")
+    }
+}
+
+/// Tokens used per model, summed over all calls to OpenAI
+///
+/// Shared by all clients, so it covers indexing, querying and evaluating alike. Ollama runs
+/// locally and is not counted.
+#[derive(Debug, Default)]
+pub struct Usage {
+    models: Mutex<BTreeMap<String, TokenCount>>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct TokenCount {
+    input: u64,
+    output: u64,
+}
+
+impl Usage {
+    fn record(&self, model: &str, input: u32, output: u32) {
+        let mut models = self.models.lock().unwrap();
+        let count = models.entry(model.to_string()).or_default();
+        count.input += u64::from(input);
+        count.output += u64::from(output);
+    }
+
+    /// Prints the tokens used per model and what they cost according to the prices
+    pub fn print_summary(&self, prices: &Prices) {
+        let models = self.models.lock().unwrap();
+
+        eprintln!();
+        eprintln!("Estimated cost, prices are per 1k tokens and may be outdated");
+        eprintln!(
+            "{:<24}  {:>12}  {:>12}  {:>10}",
+            "MODEL", "INPUT", "OUTPUT", "COST"
+        );
+        let mut total = 0.;
+        for (model, count) in models.iter() {
+            let cost = prices.0.get(model).map(|price| {
+                (count.input as f64 * price.input + count.output as f64 * price.output) / 1000.
+            });
+            total += cost.unwrap_or_default();
+
+            eprintln!(
+                "{model:<24}  {:>12}  {:>12}  {:>10}",
+                count.input,
+                count.output,
+                cost.map_or("unknown".to_string(), |cost| format!("${cost:.4}")),
+            );
+        }
+        eprintln!(
+            "{:<24}  {:>12}  {:>12}  {:>10}",
+            "total",
+            "",
+            "",
+            format!("${total:.4}")
+        );
+    }
+}
+
+/// Dollars per 1k tokens for each model
+#[derive(Clone, Debug, Deserialize)]
+pub struct Prices(pub(crate) HashMap<String, Price>);
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct Price {
+    pub input: f64,
+    #[serde(default)]
+    pub output: f64,
+}
+
+impl Prices {
+    /// The default prices, overridden by those in a toml file with a table per model, like
+    ///
+    /// ```toml
+    /// [gpt-4o-mini]
+    /// input = 0.00015
+    /// output = 0.0006
+    /// ```
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let overrides = toml::from_str::<Prices>(&content)
+            .with_context(|| format!("Failed to parse prices in {}", path.display()))?;
+
+        let mut prices = Prices::default();
+        prices.0.extend(overrides.0);
+        Ok(prices)
+    }
+}
+
+impl Default for Prices {
+    fn default() -> Self {
+        Prices(HashMap::from(
+            [
+                ("gpt-3.5-turbo", 0.0005, 0.0015),
+                ("gpt-4o", 0.0025, 0.01),
+                ("gpt-4o-mini", 0.000_15, 0.0006),
+                ("text-embedding-3-small", 0.000_02, 0.),
+                ("text-embedding-3-large", 0.000_13, 0.),
+                ("text-embedding-ada-002", 0.0001, 0.),
+                ("embed-english-v3.0", 0.0001, 0.),
+                ("embed-multilingual-v3.0", 0.0001, 0.),
+            ]
+            .map(|(model, input, output)| (model.to_string(), Price { input, output })),
+        ))
+    }
+}
+
+const COHERE_API_URL: &str = "https://api.cohere.com/v2";
+
+/// Client for a model of Cohere, for embeddings or reranking
+#[derive(Clone, Debug)]
+pub struct Cohere {
+    http: reqwest::Client,
+    api_key: String,
+    pub(crate) model: String,
+    /// What the texts are embedded as, their embeddings are only comparable to those of the
+    /// other type
+    input_type: &'static str,
+    usage: Arc<Usage>,
+}
+
+#[derive(Deserialize)]
+struct CohereEmbeddings {
+    embeddings: CohereFloats,
+    meta: Option<CohereMeta>,
+}
+
+#[derive(Deserialize)]
+struct CohereFloats {
+    float: Embeddings,
+}
+
+#[derive(Deserialize)]
+struct CohereMeta {
+    billed_units: Option<CohereBilledUnits>,
+}
+
+#[derive(Deserialize)]
+struct CohereBilledUnits {
+    input_tokens: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct CohereRanking {
+    results: Vec<CohereRank>,
+}
+
+#[derive(Deserialize)]
+struct CohereRank {
+    index: usize,
+}
+
+impl Cohere {
+    /// Without an `api_key` it is read from `COHERE_API_KEY`. Texts are embedded as documents to
+    /// search, see [`Cohere::for_queries`]
+    pub fn new(
+        api_key: Option<&str>,
+        model: &str,
+        http: &HttpOptions,
+        usage: &Arc<Usage>,
+    ) -> Result<Self> {
+        let api_key = match api_key.filter(|api_key| !api_key.is_empty()) {
+            Some(api_key) => api_key.to_string(),
+            None => std::env::var("COHERE_API_KEY")
+                .ok()
+                .filter(|api_key| !api_key.is_empty())
+                .context(
+                    "No Cohere api key found. Set COHERE_API_KEY, in the environment or a .env \
+                     file, or pass --cohere-api-key",
+                )?,
+        };
+
+        Ok(Self {
+            http: http.client()?,
+            api_key,
+            model: model.to_string(),
+            input_type: "search_document",
+            usage: Arc::clone(usage),
+        })
+    }
+
+    /// Embeds texts as queries to search the documents with, instead of as documents
+    pub fn for_queries(self) -> Self {
+        Self {
+            input_type: "search_query",
+            ..self
+        }
+    }
+
+    async fn post<T: serde::de::DeserializeOwned>(
+        &self,
+        endpoint: &str,
+        body: serde_json::Value,
+    ) -> Result<T> {
+        let response = self
+            .http
+            .post(format!("{COHERE_API_URL}/{endpoint}"))
+            .bearer_auth(&self.api_key)
+            .json(&body)
+            .send()
+            .await
+            .context("Request to Cohere failed")?;
+
+        // The status goes in the error, so rate limits are retried
+        let status = response.status();
+        if !status.is_success() {
+            anyhow::bail!(
+                "Cohere returned {status}: {}",
+                response.text().await.unwrap_or_default()
+            );
+        }
+
+        Ok(response.json().await?)
+    }
+
+    /// Orders the chunks by the relevance Cohere rates them at and keeps the `top_k` most
+    /// relevant ones
+    pub async fn rerank(
+        &self,
+        question: &str,
+        chunks: Vec<Retrieved>,
+        top_k: u64,
+    ) -> Result<Vec<Retrieved>> {
+        let candidates = chunks.len();
+        let ranking: CohereRanking = self
+            .post(
+                "rerank",
+                serde_json::json!({
+                    "model": self.model,
+                    "query": question,
+                    "documents": chunks
+                        .iter()
+                        .map(|chunk| format!("{}\n{}", chunk.path, chunk.content))
+                        .collect::<Vec<_>>(),
+                    "top_n": top_k,
+                }),
+            )
+            .await?;
+
+        let mut chunks = chunks.into_iter().map(Some).collect::<Vec<_>>();
+        let kept = ranking
+            .results
+            .into_iter()
+            .filter_map(|rank| chunks.get_mut(rank.index).and_then(Option::take))
+            .collect::<Vec<_>>();
+
+        tracing::info!(
+            candidates,
+            kept = kept.len(),
+            dropped = candidates - kept.len(),
+            "Reranked retrieved chunks with Cohere"
+        );
+
+        Ok(kept)
+    }
+}
+
+#[async_trait]
+impl EmbeddingModel for Cohere {
+    async fn embed(&self, input: Vec<String>) -> Result<Embeddings> {
+        let response: CohereEmbeddings = self
+            .post(
+                "embed",
+                serde_json::json!({
+                    "model": self.model,
+                    "texts": input,
+                    "input_type": self.input_type,
+                    "embedding_types": ["float"],
+                }),
+            )
+            .await?;
+
+        if let Some(input_tokens) = response
+            .meta
+            .and_then(|meta| meta.billed_units)
+            .and_then(|billed_units| billed_units.input_tokens)
+        {
+            self.usage.record(&self.model, input_tokens, 0);
+        }
+
+        Ok(response.embeddings.float)
+    }
+}
+
+struct OllamaStream {
+    client: ollama_rs::Ollama,
+    model: String,
+}
+
+#[async_trait]
+impl StreamingPrompt for OllamaStream {
+    async fn prompt_streaming(
+        &self,
+        prompt: Prompt,
+        on_token: &mut (dyn for<'a> FnMut(&'a str) + Send),
+    ) -> Result<String> {
+        let request = GenerationRequest::new(self.model.clone(), prompt.render().await?);
+
+        let mut stream = self.client.generate_stream(request).await?;
+        let mut answer = String::new();
+        while let Some(responses) = stream.next().await {
+            for response in responses? {
+                on_token(&response.response);
+                answer.push_str(&response.response);
+            }
+        }
+
+        Ok(answer)
+    }
+}
+
+/// L2-normalizes the embeddings of the wrapped model
+#[derive(Debug)]
+struct Normalized(Arc<dyn EmbeddingModel>);
+
+#[async_trait]
+impl EmbeddingModel for Normalized {
+    async fn embed(&self, input: Vec<String>) -> Result<Embeddings> {
+        let mut embeddings = self.0.embed(input).await?;
+        for embedding in &mut embeddings {
+            let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+            if norm > f32::EPSILON {
+                embedding.iter_mut().for_each(|x| *x /= norm);
+            }
+        }
+
+        Ok(embeddings)
+    }
+}
+
+/// Embeds a batch in as many requests as needed to keep each within the token budget
+///
+/// Batches have a fixed number of chunks, so a batch of large chunks can exceed the token limit
+/// of a single request. Chunks are packed in order, a chunk over the budget on its own is sent
+/// alone.
+struct TokenBatched {
+    inner: Arc<dyn EmbeddingModel>,
+    budget: TokenBudget,
+}
+
+impl std::fmt::Debug for TokenBatched {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TokenBatched")
+            .field("inner", &self.inner)
+            .field("max_tokens", &self.budget.max_tokens)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl EmbeddingModel for TokenBatched {
+    async fn embed(&self, input: Vec<String>) -> Result<Embeddings> {
+        let mut requests = vec![Vec::new()];
+        let mut tokens = 0;
+        for text in input {
+            let count = self.budget.count(&text);
+            if tokens > 0 && tokens + count > self.budget.max_tokens {
+                requests.push(Vec::new());
+                tokens = 0;
+            }
+            if count > self.budget.max_tokens {
+                tracing::warn!(
+                    tokens = count,
+                    max_tokens = self.budget.max_tokens,
+                    "Chunk exceeds the embedding token budget on its own"
+                );
+            }
+
+            tokens += count;
+            requests.last_mut().expect("Expected a request").push(text);
+        }
+
+        if requests.len() > 1 {
+            tracing::debug!(
+                requests = requests.len(),
+                "Split batch to fit the embedding token budget"
+            );
+        }
+
+        let mut embeddings = Vec::new();
+        for request in requests {
+            embeddings.extend(self.inner.embed(request).await?);
+        }
+        Ok(embeddings)
+    }
+}
+
+/// Limits the requests in flight, adapting the limit to rate limits (AIMD)
+///
+/// The limit is halved on every rate limited request and grows by one for every `limit`
+/// successful requests, so it settles just below what the API key allows. It starts at `max`
+/// and never exceeds it.
+#[derive(Debug)]
+struct AdaptiveLimit {
+    state: Mutex<LimitState>,
+    released: tokio::sync::Notify,
+    max: usize,
+}
+
+#[derive(Debug)]
+struct LimitState {
+    limit: f64,
+    in_flight: usize,
+}
+
+impl AdaptiveLimit {
+    fn new(max: usize) -> Self {
+        Self {
+            state: Mutex::new(LimitState {
+                limit: max as f64,
+                in_flight: 0,
+            }),
+            released: tokio::sync::Notify::new(),
+            max,
+        }
+    }
+
+    async fn acquire(&self) -> LimitPermit<'_> {
+        loop {
+            // Created before checking, so a release in between is not missed
+            let released = self.released.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if (state.in_flight as f64) < state.limit.floor() {
+                    state.in_flight += 1;
+                    return LimitPermit {
+                        limit: self,
+                        rate_limited: false,
+                    };
+                }
+            }
+            released.await;
+        }
+    }
+
+    fn release(&self, rate_limited: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight -= 1;
+        if rate_limited {
+            state.limit = (state.limit / 2.).max(1.);
+            tracing::debug!(limit = state.limit, "Rate limited, lowering concurrency");
+        } else {
+            state.limit = (state.limit + 1. / state.limit).min(self.max as f64);
+        }
+        drop(state);
+
+        self.released.notify_waiters();
+    }
+}
+
+/// A request in flight, released when dropped so cancelled requests free their slot as well
+struct LimitPermit<'a> {
+    limit: &'a AdaptiveLimit,
+    rate_limited: bool,
+}
+
+impl Drop for LimitPermit<'_> {
+    fn drop(&mut self) {
+        self.limit.release(self.rate_limited);
+    }
+}
+
+/// Waits for a slot in the adaptive limit before every call to the wrapped model
+#[derive(Debug)]
+struct Throttled<T: ?Sized> {
+    inner: Arc<T>,
+    limit: Arc<AdaptiveLimit>,
+}
+
+impl<T: ?Sized> Throttled<T> {
+    async fn throttle<R>(&self, call: impl Future<Output = Result<R>>) -> Result<R> {
+        let mut permit = self.limit.acquire().await;
+        let result = call.await;
+        permit.rate_limited = result.as_ref().is_err_and(is_rate_limited);
+
+        result
+    }
+}
+
+#[async_trait]
+impl EmbeddingModel for Throttled<dyn EmbeddingModel> {
+    async fn embed(&self, input: Vec<String>) -> Result<Embeddings> {
+        self.throttle(self.inner.embed(input)).await
+    }
+}
+
+#[async_trait]
+impl SimplePrompt for Throttled<dyn SimplePrompt> {
+    async fn prompt(&self, prompt: Prompt) -> Result<String> {
+        self.throttle(self.inner.prompt(prompt)).await
+    }
+}
+
+/// Retries the wrapped model with exponential backoff and jitter on transient errors
+///
+/// Large indexing runs easily hit rate limits, which would otherwise abort the whole pipeline.
+#[derive(Debug)]
+struct Retrying<T: ?Sized> {
+    inner: Arc<T>,
+    max_retries: u32,
+}
+
+impl<T: ?Sized> Retrying<T> {
+    async fn retry<R, F>(&self, mut call: impl FnMut() -> F) -> Result<R>
+    where
+        F: Future<Output = Result<R>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match call().await {
+                Err(err) if attempt < self.max_retries && is_retryable(&err) => {
+                    let backoff = Duration::from_millis(500 * 2_u64.pow(attempt)).min(MAX_BACKOFF);
+                    let backoff = backoff.mul_f64(rand::random::<f64>() * 0.5 + 0.75);
+                    attempt += 1;
+
+                    tracing::warn!(?err, attempt, ?backoff, "Retrying after transient error");
+                    tokio::time::sleep(backoff).await;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl EmbeddingModel for Retrying<dyn EmbeddingModel> {
+    async fn embed(&self, input: Vec<String>) -> Result<Embeddings> {
+        self.retry(|| self.inner.embed(input.clone())).await
+    }
+}
+
+#[async_trait]
+impl SimplePrompt for Retrying<dyn SimplePrompt> {
+    async fn prompt(&self, prompt: Prompt) -> Result<String> {
+        self.retry(|| self.inner.prompt(prompt.clone())).await
+    }
+}
+
+/// Rate limits, timeouts and server errors are worth retrying, anything else is not
+fn is_retryable(err: &anyhow::Error) -> bool {
+    let err = format!("{err:#}").to_lowercase();
+
+    [
+        "rate limit",
+        "rate_limit",
+        "429",
+        "timed out",
+        "timeout",
+        "connection",
+        "overloaded",
+        "server_error",
+        "service unavailable",
+        "bad gateway",
+    ]
+    .iter()
+    .any(|marker| err.contains(marker))
+}
+
+fn is_rate_limited(err: &anyhow::Error) -> bool {
+    let err = format!("{err:#}").to_lowercase();
+
+    ["rate limit", "rate_limit", "429"]
+        .iter()
+        .any(|marker| err.contains(marker))
+}
+
+/// Swiftide transformers take their model by value. This forwards to a shared trait object so
+/// the same client can be handed to multiple transformers, regardless of the backend.
+#[derive(Debug)]
+pub(crate) struct Shared<T: ?Sized>(pub(crate) Arc<T>);
+
+#[async_trait]
+impl EmbeddingModel for Shared<dyn EmbeddingModel> {
+    async fn embed(&self, input: Vec<String>) -> Result<Embeddings> {
+        self.0.embed(input).await
+    }
+}
+
+#[async_trait]
+impl SimplePrompt for Shared<dyn SimplePrompt> {
+    async fn prompt(&self, prompt: Prompt) -> Result<String> {
+        self.0.prompt(prompt).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use anyhow::anyhow;
+
+    use super::*;
+
+    /// Embeds every text as its length and records the requests
+    #[derive(Debug, Default)]
+    struct Requests(Mutex<Vec<Vec<String>>>);
+
+    #[async_trait]
+    impl EmbeddingModel for Requests {
+        async fn embed(&self, input: Vec<String>) -> Result<Embeddings> {
+            let embeddings = input.iter().map(|text| vec![text.len() as f32]).collect();
+            self.0.lock().unwrap().push(input);
+            Ok(embeddings)
+        }
+    }
+
+    #[tokio::test]
+    async fn token_batched_splits_batches_over_the_budget() {
+        let requests = Arc::new(Requests::default());
+        let budget = TokenBudget::for_model("text-embedding-3-small", 0);
+        let large = "a ".repeat(10);
+        let max_tokens = budget.count("a") * 2;
+        assert!(budget.count(&large) > max_tokens);
+        let batched = TokenBatched {
+            inner: requests.clone(),
+            budget: TokenBudget::for_model("text-embedding-3-small", max_tokens),
+        };
+
+        let input = ["a", "a", "a", &large, "a"].map(str::to_string).to_vec();
+        let embeddings = batched.embed(input.clone()).await.unwrap();
+
+        assert_eq!(
+            embeddings,
+            input
+                .iter()
+                .map(|text| vec![text.len() as f32])
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            requests
+                .0
+                .lock()
+                .unwrap()
+                .iter()
+                .map(Vec::len)
+                .collect::<Vec<_>>(),
+            vec![2, 1, 1, 1]
+        );
+    }
+
+    #[tokio::test]
+    async fn adaptive_limit_halves_on_rate_limits_and_grows_back() {
+        let limit = AdaptiveLimit::new(4);
+        let blocked =
+            |limit| tokio::time::timeout(Duration::from_millis(10), AdaptiveLimit::acquire(limit));
+
+        let mut permits = Vec::new();
+        for _ in 0..4 {
+            permits.push(limit.acquire().await);
+        }
+        assert!(blocked(&limit).await.is_err());
+
+        let mut rate_limited = permits.pop().unwrap();
+        rate_limited.rate_limited = true;
+        drop(rate_limited);
+        assert_eq!(limit.state.lock().unwrap().limit, 2.);
+        assert!(blocked(&limit).await.is_err());
+
+        permits.truncate(1);
+        assert!((limit.state.lock().unwrap().limit - 2.9).abs() < 1e-9);
+        permits.push(blocked(&limit).await.unwrap());
+        assert!(blocked(&limit).await.is_err());
+
+        drop(permits);
+        for _ in 0..100 {
+            drop(limit.acquire().await);
+        }
+        assert_eq!(limit.state.lock().unwrap().limit, 4.);
+    }
+
+    #[test]
+    fn is_retryable_looks_through_the_context() {
+        assert!(is_retryable(&anyhow!("HTTP 429 Too Many Requests")));
+        assert!(is_retryable(
+            &anyhow!("Connection reset by peer").context("Failed to embed")
+        ));
+        assert!(!is_retryable(&anyhow!("Incorrect API key provided")));
+        assert!(is_rate_limited(&anyhow!("Rate limit reached for gpt-4o")));
+        assert!(!is_rate_limited(&anyhow!("Request timed out")));
+    }
+
+    #[tokio::test]
+    async fn retrying_stops_at_permanent_errors_and_max_retries() {
+        let retrying = Retrying::<()> {
+            inner: Arc::new(()),
+            max_retries: 1,
+        };
+        let fail = |calls: &AtomicUsize, err: &str| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err::<(), _>(anyhow!("{err}"))
+        };
+
+        let calls = AtomicUsize::new(0);
+        let result = retrying
+            .retry(|| async { fail(&calls, "Incorrect API key provided") })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        let calls = AtomicUsize::new(0);
+        let result = retrying
+            .retry(|| async { fail(&calls, "Service unavailable") })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        let calls = AtomicUsize::new(0);
+        let result = retrying
+            .retry(|| async {
+                match calls.load(Ordering::SeqCst) {
+                    0 => fail(&calls, "Request timed out"),
+                    _ => Ok(()),
+                }
+            })
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
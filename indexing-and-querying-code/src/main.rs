@@ -1,174 +1,1937 @@
-use std::{path::PathBuf, str::FromStr};
+use std::{
+    io::{Read as _, Write as _},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use anyhow::{Context as _, Result};
-use clap::Parser;
-use indoc::formatdoc;
-use qdrant_client::qdrant::SearchPointsBuilder;
-use swiftide::{
-    indexing::Pipeline,
-    integrations::{openai::OpenAI, qdrant::Qdrant, redis::Redis, treesitter::SupportedLanguages},
-    loaders::FileLoader,
-    transformers::{ChunkCode, ChunkMarkdown, Embed, MetadataQACode, MetadataQAText},
-    EmbeddingModel, SimplePrompt,
+use clap::{Parser, Subcommand};
+use indexing_and_querying_code::{
+    answer_questions, build_llm, build_store, check_embed_model, converse, dry_run, evaluate,
+    evaluation_rows, generate_questions, ground_truth_dataset, index_all,
+    indexing_handles_interrupts, init_tracing, language_of_file, parse_language, preflight, query,
+    resolve_extensions, sample_files, score_evaluation, to_csv, validate_vector_size,
+    vector_size_for_model, verify, AnswerFormat, AnswerTemplate, Azure, Backend, CacheKind, Cohere,
+    CollectionConfig, Conversation, EmbedBackend, EmbedMetadata, EmbeddingCache, EvalContext,
+    FileFilter, HttpOptions, IndexOptions, IndexStats, Languages, Llm, Manifest, MarkdownSplit,
+    MetadataTemplate, OnEmpty, PathFilter, Prices, QdrantStore, QueryCache, QueryContext,
+    QueryMode, QueryResult, QuestionAnswer, SearchVector, Source, StoreConfig, StoreKind,
+    TokenBudget, TokenEstimate, Usage, VectorDistance, VectorStore, DEFAULT_NUM_SUBQUESTIONS,
+    EMBED_BATCH_SIZE, EMBED_TOKEN_BUDGET, MAX_NUM_SUBQUESTIONS,
 };
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use swiftide::query::evaluators::ragas::EvaluationDataSet;
+use tokio::io::{AsyncBufReadExt as _, BufReader};
+
+const DEFAULT_COLLECTION_NAME: &str = "swiftide-tutorial";
+const DEFAULT_CHUNK_RANGE: Range<usize> = 50..1024;
+
+/// Evaluations always start from an empty collection, so they get their own
+const EVAL_COLLECTION_NAME: &str = "swiftide-ragas";
+const EVAL_CHUNK_RANGE: Range<usize> = 50..2048;
+
+/// Number of questions to generate with `--generate-questions`
+const NUM_GENERATED_QUESTIONS: usize = 100;
+
+/// Retrieving more chunks than this is likely to overflow the context of the prompt model
+const MAX_SENSIBLE_TOP_K: u64 = 100;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
-struct Args {
+struct Cli {
+    /// Log more, `-v` for debug and `-vv` for trace. `RUST_LOG` is respected as well
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Only log warnings and errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Print the tokens used with OpenAI and an estimate of what they cost when done
+    #[arg(long, global = true)]
+    show_cost: bool,
+
+    /// Toml file with the dollars per 1k input and output tokens of models, overriding the
+    /// built-in prices for estimating the cost
+    #[arg(long, global = true, requires = "show_cost")]
+    prices: Option<PathBuf>,
+
+    /// Log a line of json per event, with the fields of its spans like the file and phase
+    #[arg(long, global = true)]
+    json_logs: bool,
+
+    /// Export traces to this OTLP gRPC endpoint, e.g. `http://localhost:4317`
+    #[arg(long, global = true)]
+    otel_endpoint: Option<String>,
+
+    /// Write a json summary of the run to this file when done, also when it failed
+    #[arg(long, global = true)]
+    summary_json: Option<PathBuf>,
+
+    /// Exit with an error if any file failed to index, instead of only leaving it out
+    #[arg(long, global = true)]
+    fail_on_error: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Index the code into the store
+    Index(IndexCommand),
+    /// Answer questions about the code, indexing it first unless it is already indexed
+    Query(Box<QueryArgs>),
+    /// Index the code from scratch and evaluate the answers to a set of questions with Ragas
+    Eval(EvalArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct IndexCommand {
+    #[command(flatten)]
+    index: IndexArgs,
+
+    /// Compare the chunks of the code to those in the collection without indexing anything, and
+    /// fail if they differ
+    #[arg(long, conflicts_with_all = ["dry_run", "count_tokens"])]
+    verify: bool,
+
+    #[command(flatten)]
+    store: StoreArgs,
+}
+
+/// What to index and how, shared by all subcommands
+#[derive(clap::Args, Debug)]
+struct IndexArgs {
+    /// Language of the code, or `auto` to detect the language of each file by its extension.
+    /// Can be left out when `--path` is a single file, which is indexed in its language
     #[arg(short, long)]
-    language: String,
+    language: Option<String>,
 
+    /// Local path or git url of the code to index, can be repeated to index several into the
+    /// same collection
     #[arg(short, long, default_value = "./")]
-    path: PathBuf,
+    path: Vec<String>,
+
+    /// Branch to check out when indexing a git url, defaults to the remote's default branch
+    #[arg(long)]
+    branch: Option<String>,
+
+    /// Comma separated file extensions to index instead of the language's and the documentation
+    #[arg(long, value_delimiter = ',')]
+    extensions: Option<Vec<String>>,
+
+    /// Comma separated file extensions to index on top of the others
+    #[arg(long, value_delimiter = ',')]
+    extra_extensions: Vec<String>,
+
+    /// Comma separated extensions of text-like files to index on top of the others, chunked like
+    /// markdown. Markdown, plain text and PDF are indexed by default
+    #[arg(long, value_delimiter = ',')]
+    docs_extensions: Vec<String>,
+
+    /// Glob of the files to index relative to the path, can be repeated
+    #[arg(long)]
+    include: Vec<String>,
+
+    /// Glob of the files to skip relative to the path, can be repeated, wins over --include
+    #[arg(long)]
+    exclude: Vec<String>,
+
+    /// Skip tests and generated code, like `tests/` directories and `*.pb.go` files, also when
+    /// they match --include. Files are indexed anyway when an --include names them more
+    /// specifically, like `tests/fixtures/**`
+    #[arg(long)]
+    exclude_tests: bool,
+
+    /// Minimum size of a chunk in characters, defaults to the config file or 50
+    #[arg(long)]
+    min_chunk: Option<usize>,
+
+    /// Maximum size of a chunk in characters, defaults to the config file or 1024, or 2048 when
+    /// evaluating. Without either chunk size, code is chunked with a range that suits its
+    /// language, from 768 for Python and Ruby to 1536 for Typescript and Javascript
+    #[arg(long)]
+    max_chunk: Option<usize>,
+
+    /// Number of characters at the end of a chunk to repeat at the start of the next chunk of
+    /// the file, at most the minimum chunk size
+    #[arg(long, default_value_t = 0)]
+    chunk_overlap: usize,
+
+    /// How to split markdown and other text, by size or at headings first. Split at headings,
+    /// only sections larger than the maximum chunk size are split further by size
+    #[arg(long, value_enum, default_value_t = MarkdownSplit::Size)]
+    markdown_split: MarkdownSplit,
+
+    /// The deepest heading markdown is split at with `--markdown-split heading`, 1 for `#` only
+    #[arg(long, default_value_t = 2, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..=6))]
+    markdown_heading_level: usize,
+
+    /// Number of code nodes processed concurrently, also used for embedding and storing
+    #[arg(long, default_value_t = 50, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    code_concurrency: usize,
+
+    /// Number of markdown and other text nodes processed concurrently
+    #[arg(long, default_value_t = 50, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    markdown_concurrency: usize,
+
+    /// Number of chunks embedded in a single request
+    #[arg(long, default_value_t = EMBED_BATCH_SIZE, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    embed_batch_size: usize,
+
+    /// Log the number of chunks and estimated tokens of every file at info level, instead of
+    /// only with `--verbose`
+    #[arg(long)]
+    log_per_file: bool,
+
+    /// Add when a file was last modified and by whom to the metadata of its chunks, using git
+    /// or the modification time of the file if it is not tracked
+    #[arg(long)]
+    with_git_metadata: bool,
+
+    /// Also write every indexed chunk as a line of json to this file
+    #[arg(long)]
+    export: Option<PathBuf>,
+
+    /// Follow symlinks to files and directories, files they lead to outside of the path are
+    /// still skipped. Without it, symlinks are skipped
+    #[arg(long)]
+    follow_symlinks: bool,
+
+    /// Also write the path, chunk and embedding of every indexed chunk to this Parquet file
+    #[arg(long)]
+    embeddings_parquet: Option<PathBuf>,
+
+    /// File with the prompt generating questions and answers about code chunks, a Tera template
+    /// with `{{ node.chunk }}` and `{{ questions }}`, and `{{ outline }}` if there is one
+    #[arg(long)]
+    qa_code_prompt: Option<PathBuf>,
+
+    /// File with the prompt generating questions and answers about markdown chunks, a Tera
+    /// template with `{{ node.chunk }}` and `{{ questions }}`
+    #[arg(long)]
+    qa_text_prompt: Option<PathBuf>,
+
+    /// Toml file with settings, flags take precedence over it
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Qdrant collection and cache prefix to index into and answer from, so projects and
+    /// languages can be kept apart. Defaults to swiftide-tutorial, or swiftide-ragas when
+    /// evaluating
+    #[arg(long)]
+    collection_name: Option<String>,
+
+    /// Only load and chunk the code, print what would be indexed and exit without calling an LLM
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Like `--dry-run`, also counting the tokens of the chunks with the tokenizer of the embed
+    /// model and estimating the cost of embedding them
+    #[arg(long, conflicts_with = "dry_run")]
+    count_tokens: bool,
+
+    /// Only index the files changed since this git ref, and untracked files, in every path
+    #[arg(long)]
+    since: Option<String>,
+
+    /// Only index this many files, picked at random from the files that would be indexed
+    #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    sample: Option<usize>,
+
+    /// Seed for picking the files of `--sample`, to pick the same files again
+    #[arg(long, requires = "sample")]
+    seed: Option<u64>,
+
+    /// Abort before indexing more files than this, to not spend a fortune on a wrong path
+    #[arg(long, default_value_t = 5000, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    max_files: usize,
+
+    /// Index any number of files, ignoring `--max-files`
+    #[arg(long)]
+    yes: bool,
+
+    /// Remove the chunks of the files deleted since `--since` from the store
+    #[arg(long, requires = "since")]
+    prune_deleted: bool,
+
+    /// Remove the chunks of the files under the paths that no longer exist from the store,
+    /// whether or not they were deleted since `--since`
+    #[arg(long)]
+    prune: bool,
+
+    /// Write the files that failed to load, chunk, enrich or embed, with their errors, to this
+    /// json file
+    #[arg(long)]
+    error_report: Option<PathBuf>,
+
+    /// Record every file to this file as soon as all its chunks are stored, and skip the files
+    /// recorded in it that have not changed since, to resume a run that was interrupted or
+    /// crashed. Created if it does not exist
+    #[arg(long)]
+    resume_from: Option<PathBuf>,
+
+    /// Which metadata of a chunk is embedded together with it, all of it is stored either way
+    #[arg(long, value_enum, default_value_t = EmbedMetadata::Include)]
+    embed_metadata: EmbedMetadata,
+
+    /// Stop indexing at the first file that fails to load, chunk, enrich or embed, instead of
+    /// leaving it out and indexing the rest
+    #[arg(long)]
+    strict: bool,
+
+    /// Do not show a progress bar while indexing, it is also hidden if stdout is not a terminal
+    #[arg(long)]
+    no_progress: bool,
+
+    /// Print how long loading, chunking, metadata, embedding and storing took after indexing
+    #[arg(long)]
+    timings: bool,
+
+    /// Do not check that Qdrant and Redis are reachable before starting
+    #[arg(long)]
+    skip_preflight: bool,
+
+    #[command(flatten)]
+    llm: LlmArgs,
+}
+
+/// Which models to use and how to reach them
+#[derive(clap::Args, Debug)]
+struct LlmArgs {
+    /// Which LLM backend to use for embeddings and prompts
+    #[arg(long, value_enum, default_value_t = Backend::OpenAI)]
+    backend: Backend,
+
+    /// Base url of the Ollama server, only used with `--backend ollama`
+    #[arg(long, default_value = "http://localhost:11434")]
+    ollama_url: String,
+
+    /// Backend for embeddings only, defaults to `--backend`
+    #[arg(long, value_enum)]
+    embed_backend: Option<EmbedBackend>,
+
+    /// Cohere api key, used with `--embed-backend cohere` and `--rerank-backend cohere`
+    #[arg(long, env = "COHERE_API_KEY", hide_env_values = true)]
+    cohere_api_key: Option<String>,
+
+    /// Embedding model for both the chunks and the questions, defaults to a sensible model for
+    /// the embedding backend
+    #[arg(long)]
+    embed_model: Option<String>,
+
+    /// Prompt model for both indexing and answering, defaults to sensible models for the backend
+    #[arg(long)]
+    prompt_model: Option<String>,
+
+    /// Prompt model for enriching the chunks while indexing, defaults to `--prompt-model`
+    #[arg(long)]
+    index_prompt_model: Option<String>,
+
+    /// Prompt model for rewriting and answering questions, defaults to `--prompt-model`
+    #[arg(long)]
+    query_prompt_model: Option<String>,
+
+    /// Dimensions of the embeddings, defaults to the known size of the embedding model
+    #[arg(long)]
+    vector_size: Option<u64>,
+
+    /// How often to retry LLM calls that fail with transient errors like rate limits
+    #[arg(long, default_value_t = 5)]
+    max_retries: u32,
 
-    query: String,
+    /// Maximum number of LLM requests in flight, lowered while rate limited and raised again as
+    /// requests succeed
+    #[arg(long, default_value_t = 50, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    concurrency: usize,
+
+    /// Maximum number of tokens in a single embedding request, batches with more are split
+    #[arg(long, default_value_t = EMBED_TOKEN_BUDGET, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    embed_token_budget: usize,
+
+    /// Scale every embedding to length one before storing or searching it, needed for
+    /// `--distance dot` to rank like cosine
+    #[arg(long)]
+    normalize_embeddings: bool,
+
+    /// Directory of a cache of embeddings kept across runs, so identical chunks are only embedded
+    /// once per model
+    #[arg(long)]
+    embedding_cache_path: Option<PathBuf>,
+
+    /// Empty the embedding cache before using it
+    #[arg(long, requires = "embedding_cache_path")]
+    clear_embedding_cache: bool,
+
+    /// OpenAI api key, also read from a .env file in the working directory or its parents
+    #[arg(long, env = "OPENAI_API_KEY", hide_env_values = true)]
+    api_key: Option<String>,
+
+    /// Reach OpenAI through this Azure OpenAI resource, like
+    /// `https://<resource>.openai.azure.com`. The api key is read from `AZURE_OPENAI_API_KEY`, or
+    /// `OPENAI_API_KEY` if that is not set
+    #[arg(long, env = "AZURE_OPENAI_ENDPOINT")]
+    azure_endpoint: Option<String>,
+
+    /// Comma separated `model=deployment` pairs of the Azure deployments serving the models,
+    /// models without one are expected to be deployed by their name
+    #[arg(long, env = "AZURE_OPENAI_DEPLOYMENTS", value_delimiter = ',', value_parser = parse_deployment, requires = "azure_endpoint")]
+    azure_deployment: Vec<(String, String)>,
+
+    /// Version of the Azure OpenAI api
+    #[arg(long, env = "OPENAI_API_VERSION", default_value = "2024-06-01")]
+    azure_api_version: String,
+
+    /// Send the requests to OpenAI through this proxy, like `http://proxy.example.com:3128`
+    #[arg(long, env = "HTTPS_PROXY")]
+    http_proxy: Option<String>,
+
+    /// Pem file with a CA certificate to trust on top of the system ones, for proxies that
+    /// inspect TLS
+    #[arg(long)]
+    ca_cert: Option<PathBuf>,
+}
+
+/// Where the indexed chunks are kept between runs
+#[derive(clap::Args, Debug)]
+struct StoreArgs {
+    /// Where to store the embedded chunks, memory needs no running services but is not kept
+    #[arg(long, value_enum, default_value_t = StoreKind::Qdrant)]
+    store: StoreKind,
+
+    /// Cache of the already indexed chunks, so unchanged files are skipped on the next run.
+    /// Changing the chunk size or models starts a fresh cache
+    #[arg(long, value_enum, default_value_t = CacheKind::Redis)]
+    cache: CacheKind,
+
+    /// Url of the Redis server used with `--cache redis`
+    #[arg(long, default_value = "redis://localhost:6379")]
+    redis_url: String,
+
+    /// Number of edges per node in the HNSW index of a new Qdrant collection
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    hnsw_m: Option<u64>,
+
+    /// Number of neighbours considered while building the HNSW index of a new Qdrant collection
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    hnsw_ef_construct: Option<u64>,
+
+    /// Enable int8 scalar quantization for a new Qdrant collection, to search with less memory
+    #[arg(long)]
+    scalar_quantization: bool,
+
+    /// Store a sparse keyword vector with every chunk and search by both keywords and meaning.
+    /// Needs a collection created with it, pass --force-reindex to recreate an existing one
+    #[arg(long)]
+    hybrid: bool,
+
+    /// Store a vector of the code and one of the questions and answers generated about it with
+    /// every chunk, instead of one of both, to search either with --search-vector. Needs a
+    /// collection created with it, pass --force-reindex to recreate an existing one
+    #[arg(long, conflicts_with = "hybrid")]
+    named_vectors: bool,
+
+    /// Which vector of a collection with --named-vectors to search
+    #[arg(long, value_enum, default_value_t = SearchVector::Code, requires = "named_vectors")]
+    search_vector: SearchVector,
+
+    /// How a new Qdrant collection compares the embeddings
+    #[arg(long, value_enum, default_value_t = VectorDistance::Cosine)]
+    distance: VectorDistance,
+
+    /// Skip indexing if the collection already exists
+    #[arg(long, conflicts_with = "force_reindex")]
+    skip_if_indexed: bool,
+
+    /// Delete the collection and index everything again, ignoring the cache
+    #[arg(long)]
+    force_reindex: bool,
+
+    /// Write a json manifest of the paths, settings, models and counts the index was built with
+    /// to this file
+    #[arg(long)]
+    manifest: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct QueryArgs {
+    #[command(flatten)]
+    index: IndexArgs,
+
+    #[command(flatten)]
+    store: StoreArgs,
+
+    /// Answer from the existing collection without indexing anything, failing if it does not
+    /// exist
+    #[arg(long, conflicts_with_all = ["force_reindex", "dry_run", "count_tokens"])]
+    query_only: bool,
+
+    /// Index the text read from stdin as a single file into an in-memory store and answer the
+    /// query about it, instead of indexing the path
+    #[arg(long, conflicts_with_all = ["interactive", "query_only", "collections", "inspect_file"])]
+    stdin: bool,
+
+    /// Extension of the text read with `--stdin`, which decides how it is chunked. Defaults to
+    /// the usual extension of `--language`
+    #[arg(long, requires = "stdin")]
+    stdin_ext: Option<String>,
+
+    /// Number of chunks to retrieve for answering a question
+    #[arg(long, default_value_t = 20, value_parser = clap::value_parser!(u64).range(1..))]
+    top_k: u64,
+
+    /// How to print the result of the query
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    output_format: OutputFormat,
+
+    /// Comma separated Qdrant collections to answer from, instead of the indexed collection.
+    /// Can be repeated, the collections must use the same embedding model
+    #[arg(long, value_delimiter = ',')]
+    collections: Vec<String>,
+
+    /// Retrieve three times `--top-k` chunks and keep the ones the prompt model rates most
+    /// relevant to the question
+    #[arg(long)]
+    rerank: bool,
+
+    /// What rates the relevance of the chunks with `--rerank`
+    #[arg(long, value_enum, default_value_t = RerankBackend::Llm, requires = "rerank")]
+    rerank_backend: RerankBackend,
+
+    /// Cohere model for `--rerank-backend cohere`
+    #[arg(long, default_value = "rerank-english-v3.0")]
+    rerank_model: String,
+
+    /// Only retrieve chunks of files whose path matches this glob, e.g. `**/src/**`
+    #[arg(long)]
+    filter_path: Option<String>,
+
+    /// Only retrieve chunks of files with this extension, e.g. `rs`
+    #[arg(long)]
+    filter_ext: Option<String>,
+
+    /// How the question is transformed before retrieving chunks for it
+    #[arg(long, value_enum, default_value_t = QueryMode::Subquestions)]
+    query_mode: QueryMode,
+
+    /// Print what the question is rewritten into before retrieving chunks, to stderr
+    #[arg(long)]
+    show_rewrite: bool,
+
+    /// Number of additional questions generated with `--query-mode subquestions`, 0 only embeds
+    /// the question
+    #[arg(long, default_value_t = DEFAULT_NUM_SUBQUESTIONS, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(0..=MAX_NUM_SUBQUESTIONS as u64))]
+    num_subquestions: usize,
+
+    /// Print the retrieved sources with their score after the answer, most similar first, see
+    /// --min-score for what the scores range over. The json output always includes them
+    #[arg(long)]
+    show_sources: bool,
+
+    /// Leave chunks less similar to the question than this out of the context. The range depends
+    /// on --distance: cosine is between -1 and 1, dot is unbounded unless the embeddings are
+    /// normalized, when it equals cosine, and euclidean is the negated distance, 0 or less. Hybrid
+    /// search scores chunks by their fused ranks instead of their similarity, so it has no minimum
+    /// score
+    #[arg(long, allow_negative_numbers = true, conflicts_with = "hybrid")]
+    min_score: Option<f32>,
+
+    /// What to answer when no chunk is similar enough to the question, or none are retrieved
+    #[arg(long, value_enum, default_value_t = OnEmpty::Refuse)]
+    on_empty: OnEmpty,
+
+    /// Print every sentence of the answer with the numbers of the sources most similar to it,
+    /// which embeds the answer and the sources once more
+    #[arg(long)]
+    explain: bool,
+
+    /// Ask for the code that answers the question to be quoted verbatim with its path, and warn
+    /// about quoted code that is not in the retrieved chunks
+    #[arg(long)]
+    verbatim_code: bool,
+
+    /// How to write the answer, json answers are parsed and asked for once more if invalid
+    #[arg(long, value_enum, default_value_t = AnswerFormat::Prose)]
+    answer_format: AnswerFormat,
+
+    /// Ask for answers of at most this many words
+    #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    max_answer_words: Option<usize>,
+
+    /// Drop retrieved chunks sharing more than this fraction of their tokens with a better match,
+    /// 1 keeps every chunk
+    #[arg(long, default_value_t = 0.95, value_parser = parse_fraction)]
+    dedup_threshold: f64,
+
+    /// Leave out the least relevant chunks so the prompt for answering stays within this many
+    /// tokens, counted with the tokenizer of the prompt model
+    #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    max_context_tokens: Option<usize>,
+
+    /// Directory to cache answers in, so asking the same question again is free. Reindexing, or
+    /// a new `--manifest`, invalidates them
+    #[arg(long)]
+    query_cache: Option<PathBuf>,
+
+    /// Ask cached questions again when their answer is older than this, like `1h` or `7days`
+    #[arg(long, value_parser = humantime::parse_duration, requires = "query_cache")]
+    query_cache_ttl: Option<Duration>,
+
+    /// File with the prompt for answering, `{question}` and `{answer_context}` are replaced with
+    /// the question and the retrieved chunks
+    #[arg(long)]
+    prompt_template: Option<PathBuf>,
+
+    /// Print the answer while it is being generated, ignored with `--output-format json`
+    #[arg(long)]
+    stream: bool,
+
+    /// Keep asking questions from stdin until EOF or `:quit`, skips indexing if the collection
+    /// already exists
+    #[arg(short, long)]
+    interactive: bool,
+
+    /// Number of earlier questions and answers kept as context for follow-up questions in the
+    /// interactive mode, 0 answers every question on its own
+    #[arg(long, default_value_t = 5, requires = "interactive")]
+    history_limit: usize,
+
+    /// Print every chunk stored for the file with this path, exactly as it was indexed, instead
+    /// of answering a question. Nothing is embedded or indexed
+    #[arg(long, conflicts_with_all = ["interactive", "query", "collections"])]
+    inspect_file: Option<String>,
+
+    #[arg(required_unless_present_any = ["interactive", "dry_run", "count_tokens", "inspect_file"])]
+    query: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct EvalArgs {
+    #[command(flatten)]
+    index: IndexArgs,
+
+    /// Number of chunks to retrieve for answering and generating questions
+    #[arg(long, default_value_t = 20, value_parser = clap::value_parser!(u64).range(1..))]
+    top_k: u64,
+
+    /// How questions are transformed before retrieving chunks to answer them
+    #[arg(long, value_enum, default_value_t = QueryMode::Subquestions)]
+    query_mode: QueryMode,
+
+    /// Print what every question is rewritten into before retrieving chunks, to stderr
+    #[arg(long)]
+    show_rewrite: bool,
+
+    /// Number of additional questions generated with `--query-mode subquestions`, 0 only embeds
+    /// the question
+    #[arg(long, default_value_t = DEFAULT_NUM_SUBQUESTIONS, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(0..=MAX_NUM_SUBQUESTIONS as u64))]
+    num_subquestions: usize,
+
+    #[command(flatten)]
+    dataset: DatasetArg,
+
+    /// Records answers as ground truth
+    #[arg(short, long)]
+    record_ground_truth: bool,
+
+    /// Generate questions about the code and write them to the output instead of evaluating
+    #[arg(short, long)]
+    generate_questions: bool,
+
+    /// Number of questions to ask for at a time when generating questions
+    #[arg(long, default_value_t = 20, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..), requires = "generate_questions")]
+    batch_size: usize,
+
+    /// Fail if fewer valid questions than this could be generated
+    #[arg(long, requires = "generate_questions")]
+    min_questions: Option<usize>,
+
+    /// Continue generating questions from those in the output file of an interrupted run
+    #[arg(long, requires = "generate_questions")]
+    resume: bool,
+
+    /// Answer every generated question with the query pipeline and write `{question, answer}`
+    /// objects instead of bare questions, to bootstrap a ground truth
+    #[arg(long, requires = "generate_questions")]
+    with_answers: bool,
+
+    /// Describe the project questions are generated about from only the chunks at least this
+    /// similar to the description prompt, by their cosine similarity between -1 and 1. Falls back to the few most similar
+    /// chunks if none are
+    #[arg(long, allow_negative_numbers = true, requires = "generate_questions")]
+    description_min_score: Option<f32>,
+
+    /// Number of questions answered at the same time when evaluating, defaults to the number of
+    /// CPUs
+    #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    eval_concurrency: Option<usize>,
+
+    /// Output file to write the evaluation results to
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// How to write the evaluation results, json is what the notebook reads
+    #[arg(long, value_enum, default_value_t = EvalFormat::Json)]
+    format: EvalFormat,
+
+    /// Also write only the questions and ground truths to this file, which can be passed to
+    /// `--file` in later evaluations
+    #[arg(long)]
+    ground_truth_out: Option<PathBuf>,
+
+    /// Print the averages of the prompt model's estimates of the Ragas metrics, asking it to judge
+    /// every answer. These are not the metrics Ragas computes in the notebook
+    #[arg(long, conflicts_with = "generate_questions")]
+    summary: bool,
+
+    /// Add a `judge_` column per metric to the csv output with the prompt model's estimates of
+    /// the Ragas metrics, asking it to judge every answer once the answers are written
+    #[arg(long, conflicts_with = "generate_questions")]
+    judge: bool,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+#[group(multiple = false)]
+struct DatasetArg {
+    /// Dataset json file to load questions and ground truths from
+    #[arg(short, long, conflicts_with = "questions")]
+    file: Option<PathBuf>,
+
+    /// List of questions to use for evaluation
+    questions: Option<Vec<String>>,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum OutputFormat {
+    /// Only the answer
+    Text,
+    /// The question, rewritten questions, sources and answer as a json object
+    Json,
+}
+
+/// What the clients are built for, every phase has its own prompt model
+#[derive(Clone, Copy, Debug)]
+enum Phase {
+    Index,
+    Query,
+    /// Indexing and answering the evaluation questions, with a single prompt model
+    Eval,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum RerankBackend {
+    /// The prompt model rates every chunk
+    Llm,
+    /// Cohere's rerank api
+    Cohere,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum EvalFormat {
+    /// The Ragas dataset with the answers and contexts
+    Json,
+    /// A row per question with the answer, ground truth and joined contexts, and the scores with
+    /// --judge
+    Csv,
+}
+
+/// Settings that can be loaded from a toml file with `--config`
+///
+/// Flags given on the command line take precedence over the config file, which in turn takes
+/// precedence over the built-in defaults. The chunk range is written as
+/// `chunk_range = { start = 50, end = 1024 }`.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    embed_model: Option<String>,
+    prompt_model: Option<String>,
+    index_prompt_model: Option<String>,
+    query_prompt_model: Option<String>,
+    collection_name: Option<String>,
+    vector_size: Option<u64>,
+    chunk_range: Option<Range<usize>>,
+}
+
+impl Config {
+    fn load(path: &Path) -> Result<Config> {
+        let config = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+
+        toml::from_str(&config)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))
+    }
+}
+
+impl IndexArgs {
+    /// Loads the config file if one was given and fills in any settings missing from the flags
+    fn load_config(&mut self) -> Result<Config> {
+        let config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+
+        self.llm.embed_model = self.llm.embed_model.take().or(config.embed_model.clone());
+        self.llm.prompt_model = self.llm.prompt_model.take().or(config.prompt_model.clone());
+        self.llm.index_prompt_model = self
+            .llm
+            .index_prompt_model
+            .take()
+            .or(config.index_prompt_model.clone());
+        self.llm.query_prompt_model = self
+            .llm
+            .query_prompt_model
+            .take()
+            .or(config.query_prompt_model.clone());
+        self.llm.vector_size = self.llm.vector_size.or(config.vector_size);
+        self.collection_name = self
+            .collection_name
+            .take()
+            .or(config.collection_name.clone());
+
+        Ok(config)
+    }
+
+    /// The chunk size range from the flags, falling back to the config file and the default
+    fn chunk_range(&self, config: &Config, default: Range<usize>) -> Result<Range<usize>> {
+        let fallback = config.chunk_range.clone().unwrap_or(default);
+        let min = self.min_chunk.unwrap_or(fallback.start);
+        let max = self.max_chunk.unwrap_or(fallback.end);
+
+        anyhow::ensure!(
+            min >= 1,
+            "The minimum chunk size must be at least 1, got {min}"
+        );
+        anyhow::ensure!(
+            min < max,
+            "The minimum chunk size {min} must be smaller than the maximum {max}"
+        );
+        anyhow::ensure!(
+            self.chunk_overlap <= min,
+            "The chunk overlap {} must not exceed the minimum chunk size {min}",
+            self.chunk_overlap
+        );
+
+        Ok(min..max)
+    }
+
+    /// The language from `--language`, or of the file when a single file is indexed without it
+    fn language(&self) -> Result<Languages> {
+        if let Some(language) = &self.language {
+            return parse_language(language);
+        }
+
+        match self.path.as_slice() {
+            [path] if Path::new(path).is_file() => language_of_file(Path::new(path)),
+            _ => anyhow::bail!("Pass --language, it can only be left out when --path is a file"),
+        }
+    }
+
+    /// Counts the tokens in the dry run with `--count-tokens`
+    fn token_estimate(&self, prices: &Prices) -> Option<TokenEstimate> {
+        self.count_tokens
+            .then(|| TokenEstimate::new(self.llm.embed_model(), prices))
+    }
+
+    /// Whether the chunk size was given by a flag or the config file, instead of defaulting to
+    /// the range of the language
+    fn explicit_chunk_range(&self, config: &Config) -> bool {
+        self.min_chunk.is_some() || self.max_chunk.is_some() || config.chunk_range.is_some()
+    }
+
+    /// Resolves the sources, cloning git urls, and collects everything needed for indexing
+    async fn options(&self, chunk_range: Range<usize>) -> Result<IndexOptions> {
+        let mut sources = Vec::with_capacity(self.path.len());
+        for path in &self.path {
+            let mut source = Source::resolve(path, self.branch.as_deref()).await?;
+            if let Some(since) = &self.since {
+                source.changed_since(since).await?;
+            }
+            sources.push(source);
+        }
+
+        let mut options = self.options_for(self.language()?, sources, chunk_range)?;
+        if let Some(sample) = self.sample {
+            sample_files(&mut options, sample, self.seed);
+        }
+
+        Ok(options)
+    }
+
+    /// The options to index the sources with, which are not resolved from `--path`
+    fn options_for(
+        &self,
+        language: Languages,
+        sources: Vec<Source>,
+        chunk_range: Range<usize>,
+    ) -> Result<IndexOptions> {
+        let mut filter = FileFilter::new(&self.include, &self.exclude)?;
+        if self.exclude_tests {
+            filter = filter.excluding_tests()?;
+        }
+
+        Ok(IndexOptions {
+            language,
+            sources,
+            extensions: resolve_extensions(
+                language,
+                &self.extra_extensions,
+                &self.docs_extensions,
+                self.extensions.as_deref(),
+            )?,
+            docs_extensions: self
+                .docs_extensions
+                .iter()
+                .map(|ext| ext.trim().trim_start_matches('.').to_string())
+                .collect(),
+            filter,
+            chunk_range,
+            chunk_overlap: self.chunk_overlap,
+            code_concurrency: self.code_concurrency,
+            markdown_concurrency: self.markdown_concurrency,
+            embed_batch_size: self.embed_batch_size,
+            export: self.export.clone(),
+            git_metadata: self.with_git_metadata,
+            timings: self.timings,
+            prune_deleted: self.prune_deleted,
+            prune: self.prune,
+            error_report: self.error_report.clone(),
+            resume_from: self.resume_from.clone(),
+            embed_metadata: self.embed_metadata,
+            language_chunk_ranges: false,
+            markdown_split: self.markdown_split,
+            markdown_heading_level: self.markdown_heading_level,
+            strict: self.strict,
+            max_files: (!self.yes).then_some(self.max_files),
+            log_per_file: self.log_per_file,
+            embeddings_parquet: self.embeddings_parquet.clone(),
+            follow_symlinks: self.follow_symlinks,
+            embed_model: Some(self.llm.embed_model().to_string()),
+            qa_code_prompt: self
+                .qa_code_prompt
+                .as_deref()
+                .map(MetadataTemplate::from_file)
+                .transpose()?,
+            qa_text_prompt: self
+                .qa_text_prompt
+                .as_deref()
+                .map(MetadataTemplate::from_file)
+                .transpose()?,
+        })
+    }
+}
+
+impl StoreArgs {
+    fn collection_config(&self) -> Result<CollectionConfig> {
+        anyhow::ensure!(
+            !self.hybrid || matches!(self.store, StoreKind::Qdrant),
+            "--hybrid needs --store qdrant, the memory store only searches the embeddings"
+        );
+        anyhow::ensure!(
+            !self.named_vectors || matches!(self.store, StoreKind::Qdrant),
+            "--named-vectors needs --store qdrant, the memory store searches every vector"
+        );
+
+        Ok(CollectionConfig {
+            hnsw_m: self.hnsw_m,
+            hnsw_ef_construct: self.hnsw_ef_construct,
+            scalar_quantization: self.scalar_quantization,
+            hybrid: self.hybrid,
+            distance: self.distance,
+            named_vectors: self.named_vectors,
+            search_vector: self.search_vector,
+        })
+    }
+}
+
+impl LlmArgs {
+    fn http(&self) -> HttpOptions {
+        HttpOptions {
+            proxy: self.http_proxy.clone(),
+            ca_cert: self.ca_cert.clone(),
+        }
+    }
+
+    fn embeds_with_cohere(&self) -> bool {
+        self.embed_backend
+            .is_some_and(|embed_backend| embed_backend.backend().is_none())
+    }
+
+    fn embed_model(&self) -> &str {
+        self.embed_model
+            .as_deref()
+            .unwrap_or(match self.embed_backend {
+                Some(embed_backend) => embed_backend.default_embed_model(),
+                None => self.backend.default_embed_model(),
+            })
+    }
+
+    /// The prompt model of the phase from the flags, falling back to `--prompt-model` and the
+    /// default of the backend for the phase
+    fn prompt_model(&self, phase: Phase) -> &str {
+        let phase_model = match phase {
+            Phase::Index => self.index_prompt_model.as_deref(),
+            Phase::Query => self.query_prompt_model.as_deref(),
+            Phase::Eval => None,
+        };
+
+        phase_model
+            .or(self.prompt_model.as_deref())
+            .unwrap_or(match phase {
+                Phase::Index => self.backend.default_index_prompt_model(),
+                Phase::Query => self.backend.default_query_prompt_model(),
+                Phase::Eval => self.backend.default_eval_prompt_model(),
+            })
+    }
+
+    /// Builds the clients for the phase, every phase shares the embedding model so questions are
+    /// embedded like the chunks. Texts are embedded as queries in the query phase
+    fn build(&self, phase: Phase, usage: &Arc<Usage>) -> Result<Llm> {
+        let cache = self.embedding_cache()?;
+        self.build_with_cache(phase, matches!(phase, Phase::Query), cache, usage)
+    }
+
+    /// The cache of `--embedding-cache`, which can only be opened once at a time
+    fn embedding_cache(&self) -> Result<Option<EmbeddingCache>> {
+        let Some(path) = &self.embedding_cache_path else {
+            return Ok(None);
+        };
+        let cache = EmbeddingCache::open(path)?;
+        if self.clear_embedding_cache {
+            cache.clear()?;
+        }
+        Ok(Some(cache))
+    }
+
+    /// Builds the clients for the phase with the embedding cache, embedding texts as queries
+    /// instead of documents with `queries`, for the models that tell them apart
+    fn build_with_cache(
+        &self,
+        phase: Phase,
+        queries: bool,
+        cache: Option<EmbeddingCache>,
+        usage: &Arc<Usage>,
+    ) -> Result<Llm> {
+        let azure = self.azure_endpoint.as_ref().map(|endpoint| Azure {
+            endpoint: endpoint.clone(),
+            api_version: self.azure_api_version.clone(),
+            deployments: self.azure_deployment.iter().cloned().collect(),
+        });
+        let http = self.http();
+        let mut llm = build_llm(
+            self.backend,
+            &self.ollama_url,
+            azure.as_ref(),
+            self.api_key.as_deref(),
+            &http,
+            self.embed_model(),
+            self.prompt_model(phase),
+            usage,
+        )?;
+        if let Some(embed_backend) = self.embed_backend {
+            llm.embed = match embed_backend.backend() {
+                Some(backend) => {
+                    build_llm(
+                        backend,
+                        &self.ollama_url,
+                        azure.as_ref(),
+                        self.api_key.as_deref(),
+                        &http,
+                        self.embed_model(),
+                        self.prompt_model(phase),
+                        usage,
+                    )?
+                    .embed
+                }
+                None => {
+                    let cohere = Cohere::new(
+                        self.cohere_api_key.as_deref(),
+                        self.embed_model(),
+                        &http,
+                        usage,
+                    )?;
+                    Arc::new(if queries {
+                        cohere.for_queries()
+                    } else {
+                        cohere
+                    })
+                }
+            };
+        }
+
+        let llm = llm
+            .with_adaptive_concurrency(self.concurrency)
+            .with_retries(self.max_retries)
+            .with_embed_token_budget(self.embed_model(), self.embed_token_budget);
+
+        // Normalized after the cache, so the cache holds the embeddings as the model returned them
+        let llm = match cache {
+            // Cohere embeds queries differently from documents of the same text
+            Some(cache) if queries && self.embeds_with_cohere() => {
+                llm.with_embedding_cache(&format!("{} (queries)", self.embed_model()), cache)
+            }
+            Some(cache) => llm.with_embedding_cache(self.embed_model(), cache),
+            None => llm,
+        };
+
+        Ok(if self.normalize_embeddings {
+            llm.with_normalized_embeddings()
+        } else {
+            llm
+        })
+    }
+
+    fn vector_size(&self) -> Result<u64> {
+        match self.vector_size {
+            Some(vector_size) => Ok(vector_size),
+            None => vector_size_for_model(self.embed_model()),
+        }
+    }
+}
+
+/// Written to `--summary-json`, for scripts to check what a run did
+#[derive(Serialize, Debug, Default)]
+struct Summary {
+    success: bool,
+    files_indexed: usize,
+    chunks_stored: usize,
+    /// Chunks in the store after indexing, including those of earlier runs
+    points: u64,
+    /// Chunks removed because their file no longer exists, with `--prune`
+    chunks_pruned: usize,
+    /// Files that failed to index and were left out
+    errors: usize,
+    elapsed_ms: u128,
+    /// Whether a question was answered from the code, only set by a single query
+    #[serde(skip_serializing_if = "Option::is_none")]
+    answered: Option<bool>,
+}
+
+impl Summary {
+    fn record_index(&mut self, stats: IndexStats) {
+        self.files_indexed = stats.files;
+        self.chunks_stored = stats.chunks;
+        self.points = stats.points;
+        self.chunks_pruned = stats.pruned;
+        self.errors = stats.errors;
+    }
+
+    fn write(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write summary to {}", path.display()))
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+    // Before parsing, so the flags read from the environment see the .env file as well
+    if let Err(err) = dotenvy::dotenv() {
+        if !err.not_found() {
+            return Err(err).context("Failed to load .env file");
+        }
+    }
+    let cli = Cli::parse();
+    let _tracing = init_tracing(
+        cli.verbose,
+        cli.quiet,
+        cli.json_logs,
+        cli.otel_endpoint.as_deref(),
+    )?;
+
+    // Loaded up front, to not find out about a mistake after spending the tokens
+    let prices = match &cli.prices {
+        Some(path) => Prices::from_file(path)?,
+        None => Prices::default(),
+    };
+    let usage = Arc::new(Usage::default());
 
-    let args = Args::parse();
+    let started = Instant::now();
+    let mut summary = Summary::default();
+    let command = async {
+        match cli.command {
+            Command::Index(args) => run_index(args, &usage, &prices, &mut summary).await,
+            Command::Query(args) => run_query(*args, &usage, &prices, &mut summary).await,
+            Command::Eval(args) => run_eval(args, &usage, &prices, &mut summary).await,
+        }
+    };
+    let mut result = tokio::select! {
+        result = command => result,
+        () = interrupted() => Err(anyhow::anyhow!("Interrupted")),
+    };
+    if result.is_ok() && cli.fail_on_error && summary.errors > 0 {
+        result = Err(anyhow::anyhow!(
+            "{} files failed to index, see the logs or --error-report",
+            summary.errors
+        ));
+    }
 
-    let openai = OpenAI::builder()
-        .default_embed_model("text-embedding-3-small")
-        .default_prompt_model("gpt-3.5-turbo")
-        .build()?;
+    // Tokens are spent whether the command succeeds or not
+    if cli.show_cost {
+        usage.print_summary(&prices);
+    }
 
-    let qdrant = Qdrant::builder()
-        .vector_size(1536)
-        .collection_name("swiftide-tutorial")
-        .build()?;
+    if let Some(path) = &cli.summary_json {
+        summary.success = result.is_ok();
+        summary.elapsed_ms = started.elapsed().as_millis();
+        summary.write(path)?;
+    }
 
-    index_all(&args.language, &args.path, &openai, &qdrant).await?;
+    result
+}
+
+/// Set while the REPL runs, which handles Ctrl-C itself
+static IN_REPL: AtomicBool = AtomicBool::new(false);
+
+/// Completes on Ctrl-C, unless indexing or the REPL handles it
+///
+/// Tokio keeps handling Ctrl-C once anything listened for it, so it would no longer end the
+/// process. Ending the command instead still reports the cost and summary.
+async fn interrupted() {
+    loop {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return std::future::pending().await;
+        }
+        if !indexing_handles_interrupts() && !IN_REPL.load(Ordering::SeqCst) {
+            return;
+        }
+    }
+}
+
+async fn run_index(
+    mut args: IndexCommand,
+    usage: &Arc<Usage>,
+    prices: &Prices,
+    summary: &mut Summary,
+) -> Result<()> {
+    let config = args.index.load_config()?;
+    let chunk_range = args.index.chunk_range(&config, DEFAULT_CHUNK_RANGE)?;
+    let collection_name = args
+        .index
+        .collection_name
+        .clone()
+        .unwrap_or(DEFAULT_COLLECTION_NAME.to_string());
 
-    let openai = OpenAI::builder()
-        .default_embed_model("text-embedding-3-small")
-        .default_prompt_model("gpt-4o")
-        .build()?;
+    let mut options = args.index.options(chunk_range).await?;
+    options.language_chunk_ranges = !args.index.explicit_chunk_range(&config);
+    if args.index.dry_run || args.index.count_tokens {
+        return dry_run(&options, args.index.token_estimate(prices)).await;
+    }
 
-    let response = query(&openai, &args.query).await?;
-    println!("{}", response);
+    if args.verify {
+        anyhow::ensure!(
+            matches!(args.store.store, StoreKind::Qdrant),
+            "--verify needs --store qdrant, the memory store is empty on every run"
+        );
+        if !args.index.skip_preflight {
+            preflight(StoreKind::Qdrant, None).await?;
+        }
+
+        let store = build_store(
+            StoreKind::Qdrant,
+            &collection_name,
+            args.index.llm.vector_size()?,
+            args.store.collection_config()?,
+        )?;
+        let drift = verify(&options, store.as_ref()).await?;
+        print!("{drift}");
+        anyhow::ensure!(
+            drift.is_empty(),
+            "Collection {collection_name} differs from the code, index it to update it"
+        );
+
+        return Ok(());
+    }
+
+    let index_llm = args.index.llm.build(Phase::Index, usage)?;
+    let (_, stats) = index_into_store(
+        &args.index,
+        &args.store,
+        &options,
+        &index_llm,
+        &collection_name,
+        false,
+    )
+    .await?;
+    summary.record_index(stats);
 
     Ok(())
 }
 
-async fn index_all(language: &str, path: &PathBuf, openai: &OpenAI, qdrant: &Qdrant) -> Result<()> {
-    tracing::info!(path=?path, language, "Indexing code");
+async fn run_query(
+    mut args: QueryArgs,
+    usage: &Arc<Usage>,
+    prices: &Prices,
+    summary: &mut Summary,
+) -> Result<()> {
+    let config = args.index.load_config()?;
+    warn_on_large_top_k(args.top_k);
 
-    let language = SupportedLanguages::from_str(language)?;
-    let mut extensions = language.file_extensions().to_owned();
-    extensions.push("md");
+    anyhow::ensure!(
+        args.collections.is_empty() || matches!(args.store.store, StoreKind::Qdrant),
+        "--collections can only be used with --store qdrant"
+    );
+    let path_filter = PathFilter::new(args.filter_path.as_deref(), args.filter_ext.as_deref())?;
+    let answer_template = match &args.prompt_template {
+        Some(path) => AnswerTemplate::from_file(path)?,
+        None => AnswerTemplate::default(),
+    };
 
-    let (mut markdown, mut code) =
-        Pipeline::from_loader(FileLoader::new(path).with_extensions(&extensions))
-            .with_concurrency(50)
-            .filter_cached(Redis::try_from_url(
-                "redis://localhost:6379",
-                "swiftide-tutorial",
+    let chunk_range = args.index.chunk_range(&config, DEFAULT_CHUNK_RANGE)?;
+    let collection_name = args
+        .index
+        .collection_name
+        .clone()
+        .unwrap_or(DEFAULT_COLLECTION_NAME.to_string());
+
+    if let Some(path) = &args.inspect_file {
+        anyhow::ensure!(
+            matches!(args.store.store, StoreKind::Qdrant),
+            "--inspect-file needs --store qdrant, the memory store is empty on every run"
+        );
+        if !args.index.skip_preflight {
+            preflight(StoreKind::Qdrant, None).await?;
+        }
+        let store = build_store(
+            StoreKind::Qdrant,
+            &collection_name,
+            args.index.llm.vector_size()?,
+            args.store.collection_config()?,
+        )?;
+        return inspect_file(store.as_ref(), path, args.output_format).await;
+    }
+
+    let (store, language) = if args.query_only {
+        if !args.index.skip_preflight {
+            preflight(args.store.store, None).await?;
+        }
+        let store = build_store(
+            args.store.store,
+            &collection_name,
+            args.index.llm.vector_size()?,
+            args.store.collection_config()?,
+        )?;
+        // Collections queried without indexing may hold any language, unless told otherwise
+        let language = match &args.index.language {
+            Some(language) => parse_language(language)?,
+            None => Languages::Auto,
+        };
+        (store, language)
+    } else {
+        let mut options = if args.stdin {
+            // Nothing is kept of a one-off question
+            args.store.store = StoreKind::Memory;
+            let (source, language) = read_stdin(&args)?;
+            args.index
+                .options_for(language, vec![source], chunk_range)?
+        } else {
+            args.index.options(chunk_range).await?
+        };
+        options.language_chunk_ranges = !args.index.explicit_chunk_range(&config);
+        if args.index.dry_run || args.index.count_tokens {
+            return dry_run(&options, args.index.token_estimate(prices)).await;
+        }
+
+        let index_llm = args.index.llm.build(Phase::Index, usage)?;
+        let (store, stats) = index_into_store(
+            &args.index,
+            &args.store,
+            &options,
+            &index_llm,
+            &collection_name,
+            args.interactive,
+        )
+        .await?;
+        summary.record_index(stats);
+        (store, options.language)
+    };
+
+    let stores = if args.collections.is_empty() {
+        if args.query_only && !store.is_indexed().await? {
+            anyhow::bail!(
+                "Collection {collection_name} does not exist, index it first or leave out --query-only"
+            );
+        }
+        vec![store]
+    } else {
+        let vector_size = args.index.llm.vector_size()?;
+        let collection_config = args.store.collection_config()?;
+        args.collections
+            .iter()
+            .map(|name| build_store(StoreKind::Qdrant, name, vector_size, collection_config))
+            .collect::<Result<Vec<_>>>()?
+    };
+
+    if args.query_only {
+        for (name, store) in args.collections.iter().zip(&stores) {
+            anyhow::ensure!(
+                store.is_indexed().await?,
+                "Collection {name} does not exist, index it first or leave out --query-only"
+            );
+        }
+    }
+
+    // Searching an empty collection only ever answers that it can't answer
+    let names = if args.collections.is_empty() {
+        vec![collection_name]
+    } else {
+        args.collections.clone()
+    };
+    let mut scope = Vec::with_capacity(stores.len());
+    for (name, store) in names.iter().zip(&stores) {
+        let count = store.count().await?;
+        scope.push(format!("{name}:{count}"));
+        if count == 0 {
+            tracing::warn!(
+                collection_name = name,
+                "Collection is empty, check --collection-name matches the indexed collection"
+            );
+        }
+        check_embed_model(store.as_ref(), args.index.llm.embed_model()).await?;
+    }
+
+    let prompt_model = args.index.llm.prompt_model(Phase::Query);
+    let query_llm = args.index.llm.build(Phase::Query, usage)?;
+    let token_budget = args
+        .max_context_tokens
+        .map(|max_tokens| TokenBudget::for_model(prompt_model, max_tokens));
+
+    let cohere_rerank = match args.rerank_backend {
+        RerankBackend::Cohere => Some(Cohere::new(
+            args.index.llm.cohere_api_key.as_deref(),
+            &args.rerank_model,
+            &args.index.llm.http(),
+            usage,
+        )?),
+        RerankBackend::Llm => None,
+    };
+
+    // Chunk counts alone miss reindexing changed files, the manifest catches that
+    let query_cache = match &args.query_cache {
+        Some(path) => {
+            if let Some(manifest) = &args.store.manifest {
+                if let Some(indexed_at) = Manifest::indexed_at(manifest)? {
+                    scope.push(indexed_at);
+                }
+            }
+            Some(QueryCache::open(
+                path,
+                scope.join(","),
+                args.query_cache_ttl,
             )?)
-            .split_by(|node| {
-                // Any errors at this point we just pass to 'markdown'
-                let Ok(node) = node else { return true };
+        }
+        None => None,
+    };
 
-                // On true we go 'markdown', on false we go 'code'.
-                node.path.extension().map_or(true, |ext| ext == "md")
-            });
+    // Printing tokens as they arrive would break the json
+    let stream = args.stream && matches!(args.output_format, OutputFormat::Text);
+    let context = QueryContext {
+        llm: query_llm,
+        prompt_model: prompt_model.to_string(),
+        stores,
+        top_k: args.top_k,
+        language,
+        stream,
+        rerank: args.rerank,
+        dedup_threshold: args.dedup_threshold,
+        token_budget,
+        query_mode: args.query_mode,
+        path_filter,
+        answer_template,
+        show_rewrite: args.show_rewrite,
+        num_subquestions: args.num_subquestions,
+        cohere_rerank,
+        explain: args.explain,
+        min_score: args.min_score,
+        on_empty: args.on_empty,
+        query_cache,
+        verbatim_code: args.verbatim_code,
+        answer_format: args.answer_format,
+        max_answer_words: args.max_answer_words,
+    };
 
-    code = code
-        // Uses tree-sitter to extract best effort blocks of code. We still keep the minimum
-        // fairly high and double the chunk size
-        .then_chunk(ChunkCode::try_for_language_and_chunk_size(
-            language,
-            50..1024,
-        )?)
-        .then(MetadataQACode::new(openai.clone()));
+    if args.interactive {
+        let conversation = Conversation::new(prompt_model, args.history_limit);
+        return repl(
+            &context,
+            conversation,
+            args.output_format,
+            args.show_sources,
+        )
+        .await;
+    }
+
+    let question = args.query.as_deref().context("Expected a query")?;
+    let result = query(&context, question).await?;
+    summary.answered = Some(result.context_found && !result.answer.trim().is_empty());
+    print_result(&result, args.output_format, stream, args.show_sources)?;
+
+    Ok(())
+}
 
-    markdown = markdown
-        .then_chunk(ChunkMarkdown::from_chunk_range(50..1024))
-        // Generate questions and answers and them to the metadata of the node
-        .then(MetadataQAText::new(openai.clone()));
+/// Indexes the code into the store of the flags
+async fn index_into_store(
+    index: &IndexArgs,
+    store_args: &StoreArgs,
+    options: &IndexOptions,
+    llm: &Llm,
+    collection_name: &str,
+    skip_if_indexed: bool,
+) -> Result<(Box<dyn VectorStore>, IndexStats)> {
+    if store_args.distance == VectorDistance::Dot && !index.llm.normalize_embeddings {
+        tracing::warn!(
+            "--distance dot without --normalize-embeddings ranks longer embeddings higher \
+             regardless of how similar they are, unless the model already normalizes them"
+        );
+    }
 
-    code.merge(markdown)
-        .then_in_batch(50, Embed::new(openai.clone()))
-        .then_store_with(qdrant.clone())
-        .run()
-        .await
+    let config = StoreConfig {
+        store: store_args.store,
+        collection_name: collection_name.to_string(),
+        vector_size: index.llm.vector_size()?,
+        collection: store_args.collection_config()?,
+        redis_url: matches!(store_args.cache, CacheKind::Redis)
+            .then(|| store_args.redis_url.clone()),
+        embed_model: index.llm.embed_model().to_string(),
+        prompt_model: index.llm.prompt_model(Phase::Index).to_string(),
+        force_reindex: store_args.force_reindex,
+        skip_if_indexed: store_args.skip_if_indexed || skip_if_indexed,
+        preflight: !index.skip_preflight,
+        show_progress: !index.no_progress,
+        manifest: store_args.manifest.clone(),
+    };
+
+    indexing_and_querying_code::index(options, &config, llm).await
 }
 
-async fn query(openai: &OpenAI, question: &str) -> Result<String> {
-    let qdrant_url =
-        std::env::var("QDRANT_URL").unwrap_or_else(|_err| "http://localhost:6334".to_string());
+async fn run_eval(
+    mut args: EvalArgs,
+    usage: &Arc<Usage>,
+    prices: &Prices,
+    summary: &mut Summary,
+) -> Result<()> {
+    let config = args.index.load_config()?;
+    warn_on_large_top_k(args.top_k);
+    anyhow::ensure!(
+        !args.judge || matches!(args.format, EvalFormat::Csv),
+        "--judge adds columns to the csv, pass --format csv"
+    );
 
-    // Build a manual client as Swiftide does not support querying yet
-    let qdrant_client = qdrant_client::Qdrant::from_url(&qdrant_url).build()?;
+    let chunk_range = args.index.chunk_range(&config, EVAL_CHUNK_RANGE)?;
+    let collection_name = args
+        .index
+        .collection_name
+        .clone()
+        .unwrap_or(EVAL_COLLECTION_NAME.to_string());
 
-    // Use Swiftide's openai to rewrite the prompt to a set of questions
-    let transformed_question = openai.prompt(formatdoc!(r"
-        Your job is to help a code query tool finding the right context.
+    let mut options = args.index.options(chunk_range).await?;
+    options.language_chunk_ranges = !args.index.explicit_chunk_range(&config);
+    if args.index.dry_run || args.index.count_tokens {
+        return dry_run(&options, args.index.token_estimate(prices)).await;
+    }
 
-        Given the following question:
-        {question}
+    let dir_name = options
+        .sources
+        .iter()
+        .map(|source| source.name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
 
-        Please think of 5 additional questions that can help answering the original question. The code is written in {lang}.
+    // Both embed with the same cache, which can only be opened once
+    let embedding_cache = args.index.llm.embedding_cache()?;
+    let llm =
+        args.index
+            .llm
+            .build_with_cache(Phase::Eval, false, embedding_cache.clone(), usage)?;
 
-        Especially consider what might be relevant to answer the question, like dependencies, usage and structure of the code.
+    if !args.index.skip_preflight {
+        preflight(StoreKind::Qdrant, None).await?;
+    }
 
-        Please respond with the original question and the additional questions only.
+    // Make sure the embeddings fit the collection before doing any expensive work
+    let vector_size = args.index.llm.vector_size()?;
+    validate_vector_size(llm.embed.as_ref(), vector_size).await?;
 
-        ## Example
+    // Every evaluation indexes from scratch, so the results only depend on the enabled features
+    anyhow::ensure!(
+        options.resume_from.is_none(),
+        "--resume-from cannot be used with eval, every evaluation indexes from scratch"
+    );
+    let store = Arc::new(QdrantStore::new(
+        &collection_name,
+        vector_size,
+        CollectionConfig::default(),
+    )?);
+    store.clear().await?;
+    let stats = index_all(
+        &options,
+        None,
+        !args.index.no_progress,
+        &llm,
+        store.as_ref(),
+    )
+    .await?;
+    summary.record_index(stats);
 
-        - {question}
-        - Additional question 1
-        - Additional question 2
-        - Additional question 3
-        - Additional question 4
-        - Additional question 5
-        ", question = question, lang = "rust"
-    ).into()).await?;
+    // The questions are embedded as queries to search the chunks with
+    let llm = args
+        .index
+        .llm
+        .build_with_cache(Phase::Eval, true, embedding_cache, usage)?;
+    let context = EvalContext {
+        llm,
+        qdrant: store.qdrant().clone(),
+        store: store.clone(),
+        dir_name,
+        lang: options.language.to_string(),
+        top_k: args.top_k,
+        query_mode: args.query_mode,
+        show_rewrite: args.show_rewrite,
+        num_subquestions: args.num_subquestions,
+        description_min_score: args.description_min_score,
+        eval_concurrency: args.eval_concurrency,
+    };
 
-    // Embed the full rewrite for querying
-    let embedded_question = openai
-        .embed(vec![transformed_question.clone()])
-        .await?
-        .pop()
-        .context("Expected embedding")?;
+    if args.generate_questions {
+        let questions = if args.resume && args.output.exists() {
+            read_questions(&args.output)?
+        } else {
+            Vec::new()
+        };
 
-    // Search for matches
-    let answer_context_points = qdrant_client
-        .search_points(
-            SearchPointsBuilder::new("swiftide-tutorial", embedded_question, 20).with_payload(true),
+        // Written after every batch, so an interrupted run can be resumed
+        let save = |questions: &[String]| {
+            let json = json!({
+                "questions": questions
+            });
+            std::fs::write(&args.output, json.to_string())
+                .with_context(|| format!("Failed to write {}", args.output.display()))
+        };
+
+        let questions = generate_questions(
+            &context,
+            NUM_GENERATED_QUESTIONS,
+            args.batch_size,
+            questions,
+            save,
         )
         .await?;
 
-    // Concatenate all the found chunks
-    let answer_context = answer_context_points
-        .result
+        if let Some(min_questions) = args.min_questions {
+            anyhow::ensure!(
+                questions.len() >= min_questions,
+                "Only {} valid questions were generated, expected at least {min_questions}",
+                questions.len()
+            );
+        }
+
+        if args.with_answers {
+            let answers = answer_questions(&questions, &context).await?;
+            let json = json!({
+                "questions": answers
+            });
+            std::fs::write(&args.output, json.to_string())
+                .with_context(|| format!("Failed to write {}", args.output.display()))?;
+        }
+        return Ok(());
+    }
+
+    // Either load the dataset from a file or use the questions provided
+    // Then create the evaluation dataset to be used
+    let dataset: EvaluationDataSet = if let Some(path) = args.dataset.file {
+        std::fs::read_to_string(path)?.parse()?
+    } else {
+        args.dataset
+            .questions
+            .ok_or(anyhow::anyhow!("Expected questions"))?
+            .into()
+    };
+
+    // Query the indexed dataset and return the evaluation
+    let evaluation = evaluate(dataset, args.record_ground_truth, &context).await?;
+
+    // The json is used by the python notebook, csv is easier to inspect in a spreadsheet
+    let rows = evaluation_rows(&evaluation).await?;
+    let output = match args.format {
+        EvalFormat::Json => evaluation.to_json().await,
+        EvalFormat::Csv => to_csv(&rows, None)?,
+    };
+    std::fs::write(&args.output, output)
+        .with_context(|| format!("Failed to write {}", args.output.display()))?;
+
+    if let Some(path) = &args.ground_truth_out {
+        std::fs::write(path, ground_truth_dataset(&evaluation).await?)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+    }
+
+    // Scoring costs a prompt per question, so the answers are safely written before
+    if args.judge || args.summary {
+        let scores = score_evaluation(&rows, &context).await;
+        if args.judge {
+            std::fs::write(&args.output, to_csv(&rows, Some(&scores))?)
+                .with_context(|| format!("Failed to write {}", args.output.display()))?;
+        }
+        if args.summary {
+            println!("{}", scores.summary());
+        }
+    }
+
+    Ok(())
+}
+
+/// The questions in a file written by `--generate-questions`, with or without `--with-answers`
+fn read_questions(path: &Path) -> Result<Vec<String>> {
+    #[derive(Deserialize)]
+    struct Questions {
+        questions: Vec<Question>,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Question {
+        Bare(String),
+        Answered(QuestionAnswer),
+    }
+
+    let questions = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let questions = serde_json::from_str::<Questions>(&questions)
+        .with_context(|| format!("Failed to parse questions in {}", path.display()))?;
+
+    Ok(questions
+        .questions
         .into_iter()
-        .map(|v| v.payload.get("content").unwrap().to_string())
-        .collect::<Vec<_>>()
-        .join("\n\n");
-
-    // A prompt for answering the initial question with the found context
-    let prompt = formatdoc!(
-        r#"
-        Answer the following question(s):
-        {question}
-
-        ## Constraints
-        * Only answer based on the provided context below
-        * Always reference files by the full path if it is relevant to the question
-        * Answer the question fully and remember to be concise
-        * Only answer based on the given context. If you cannot answer the question based on the
-            context, say so.
-        * Do not make up anything, especially code, that is not included in the provided context
-
-        ## Context:
-        {answer_context}
-        "#,
+        .map(|question| match question {
+            Question::Bare(question) => question,
+            Question::Answered(answered) => answered.question,
+        })
+        .collect())
+}
+
+/// Parses a fraction between 0 and 1
+fn parse_fraction(value: &str) -> Result<f64, String> {
+    let fraction = value.parse::<f64>().map_err(|err| err.to_string())?;
+    if !(0. ..=1.).contains(&fraction) {
+        return Err(format!("{fraction} is not between 0 and 1"));
+    }
+
+    Ok(fraction)
+}
+
+/// Parses a `model=deployment` pair
+fn parse_deployment(value: &str) -> Result<(String, String), String> {
+    let (model, deployment) = value
+        .split_once('=')
+        .ok_or_else(|| format!("{value} is not a model=deployment pair"))?;
+
+    Ok((model.trim().to_string(), deployment.trim().to_string()))
+}
+
+/// The text on stdin as a source, named by `--stdin-ext` or the extension of the language, with
+/// the language from `--language` or else of the extension
+fn read_stdin(args: &QueryArgs) -> Result<(Source, Languages)> {
+    let language = args
+        .index
+        .language
+        .as_deref()
+        .map(parse_language)
+        .transpose()?;
+    let extension = match (&args.stdin_ext, language) {
+        (Some(extension), _) => extension.trim_start_matches('.').to_string(),
+        (None, Some(language)) => language
+            .extension()
+            .context("Pass --stdin-ext to tell how to chunk the text with --language auto")?,
+        (None, None) => {
+            anyhow::bail!("Pass --stdin-ext or --language to tell how to chunk the text on stdin")
+        }
+    };
+    // Text and other extensions of no language are chunked as text either way
+    let language = language.unwrap_or_else(|| {
+        language_of_file(Path::new(&format!("stdin.{extension}"))).unwrap_or(Languages::Auto)
+    });
+
+    let mut text = String::new();
+    std::io::stdin()
+        .read_to_string(&mut text)
+        .context("Failed to read stdin")?;
+    anyhow::ensure!(!text.trim().is_empty(), "Nothing to index on stdin");
+
+    Ok((Source::from_text(text, &extension), language))
+}
+
+/// Prints the chunks stored for the file with their metadata, to see why it is or is not
+/// retrieved
+async fn inspect_file(
+    store: &dyn VectorStore,
+    path: &str,
+    output_format: OutputFormat,
+) -> Result<()> {
+    let chunks = store.chunks_of(path).await?;
+    anyhow::ensure!(
+        !chunks.is_empty(),
+        "No chunks stored for {path}, the path has to match the path the file was indexed from, \
+         like `./src/main.rs` when indexing `./`"
     );
 
-    let answer = openai.prompt(prompt.into()).await?;
+    match output_format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&chunks)?),
+        OutputFormat::Text => {
+            for (i, chunk) in chunks.iter().enumerate() {
+                let lines = chunk
+                    .lines
+                    .map(|(start, end)| format!(" lines {start}-{end}"))
+                    .unwrap_or_default();
+                println!("## Chunk {} of {}{lines}", i + 1, chunks.len());
+                for (key, value) in &chunk.metadata {
+                    println!("{key}: {value}");
+                }
+                println!();
+                println!("{}", chunk.content);
+                println!();
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn warn_on_large_top_k(top_k: u64) {
+    if top_k > MAX_SENSIBLE_TOP_K {
+        tracing::warn!(
+            top_k,
+            "Retrieving more than {MAX_SENSIBLE_TOP_K} chunks might not fit in the prompt"
+        );
+    }
+}
+
+/// Prints the result in the requested format, `streamed` if the answer has been printed already
+///
+/// The json always includes the sources with their scores, the text only with `show_sources` or
+/// when the sentences of the answer are attributed to them.
+fn print_result(
+    result: &QueryResult,
+    output_format: OutputFormat,
+    streamed: bool,
+    show_sources: bool,
+) -> Result<()> {
+    match output_format {
+        // The answer has been printed while it was generated
+        OutputFormat::Text if streamed => {}
+        OutputFormat::Text => println!("{}", result.answer),
+        OutputFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(result)?);
+            return Ok(());
+        }
+    }
+
+    // Numbered in the order of the sources, as that is what the attributions refer to
+    if !result.attributions.is_empty() {
+        println!();
+        println!("Explained:");
+        for attribution in &result.attributions {
+            let sources = attribution
+                .sources
+                .iter()
+                .map(|index| (index + 1).to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            println!("  {} [{sources}]", attribution.sentence);
+        }
+
+        println!();
+        println!("Sources:");
+        for (index, source) in result.sources.iter().enumerate() {
+            println!(
+                "  [{}] {} ({:.3})",
+                index + 1,
+                source.location,
+                source.score
+            );
+        }
+    } else if show_sources {
+        let mut sources = result.sources.clone();
+        sources.sort_by(|a, b| b.score.total_cmp(&a.score));
+
+        println!();
+        println!("Sources:");
+        for source in sources {
+            println!("  {} ({:.3})", source.location, source.score);
+        }
+    }
+
+    if !result.unverified_code.is_empty() {
+        println!();
+        println!("Quoted code not found in the retrieved chunks:");
+        for block in &result.unverified_code {
+            println!("```\n{block}\n```");
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads questions from stdin and answers them until EOF, `:quit` or Ctrl-C
+async fn repl(
+    context: &QueryContext,
+    mut conversation: Conversation,
+    output_format: OutputFormat,
+    show_sources: bool,
+) -> Result<()> {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+
+    IN_REPL.store(true, Ordering::SeqCst);
+    let result = repl_loop(
+        context,
+        &mut conversation,
+        &mut lines,
+        output_format,
+        show_sources,
+    )
+    .await;
+    IN_REPL.store(false, Ordering::SeqCst);
+    result
+}
+
+async fn repl_loop(
+    context: &QueryContext,
+    conversation: &mut Conversation,
+    lines: &mut tokio::io::Lines<BufReader<tokio::io::Stdin>>,
+    output_format: OutputFormat,
+    show_sources: bool,
+) -> Result<()> {
+    loop {
+        print!("> ");
+        std::io::stdout().flush()?;
 
-    Ok(answer)
+        let line = tokio::select! {
+            line = lines.next_line() => line?,
+            _ = tokio::signal::ctrl_c() => None,
+        };
+        let Some(line) = line else { break };
+
+        let question = line.trim();
+        if question.is_empty() {
+            continue;
+        }
+        if question == ":quit" {
+            break;
+        }
+
+        tokio::select! {
+            result = converse(context, conversation, question) => match result {
+                Ok(result) => print_result(&result, output_format, context.stream, show_sources)?,
+                // A failing question should not end the session
+                Err(err) => eprintln!("Error: {err:#}"),
+            },
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    println!();
+    Ok(())
 }
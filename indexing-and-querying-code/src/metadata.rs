@@ -0,0 +1,278 @@
+//! Metadata added to chunks before they are embedded
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::UNIX_EPOCH,
+};
+
+use anyhow::{Context as _, Result};
+use async_trait::async_trait;
+use swiftide::{
+    indexing::{EmbeddedField, IndexingDefaults, IndexingStream, Node},
+    traits::{
+        BatchableTransformer, EmbeddingModel, Transformer, WithBatchIndexingDefaults,
+        WithIndexingDefaults,
+    },
+};
+use tokio_stream::StreamExt as _;
+
+use crate::store::SUMMARY_FIELD;
+
+/// The metadata Swiftide stores the generated questions and answers of code and text in
+const QA_METADATA: [&str; 2] = [
+    "Questions and Answers (code)",
+    "Questions and Answers (text)",
+];
+
+/// Which metadata of a chunk is embedded together with it, all of it is stored either way
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EmbedMetadata {
+    /// All of it, like Swiftide does
+    #[default]
+    Include,
+    /// What describes the chunk, like the generated questions and answers, but not what only
+    /// keeps track of it, like its hash, lines or last author
+    Exclude,
+    /// None, only the chunk itself
+    ContentOnly,
+}
+
+/// A template of the prompt generating questions and answers for a chunk while indexing
+///
+/// Swiftide renders it with Tera: `{{ node.chunk }}` is the chunk and `{{ questions }}` the
+/// number of questions to generate. Code chunks may have an `{{ outline }}` of their file as
+/// well, which is best wrapped in `{% if outline %}`.
+#[derive(Clone, Debug)]
+pub struct MetadataTemplate(pub(crate) String);
+
+impl MetadataTemplate {
+    /// Loads a template from a file, which must contain the chunk
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let template = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        anyhow::ensure!(
+            template.contains("node.chunk"),
+            "The metadata prompt {} is missing {{{{ node.chunk }}}}",
+            path.display()
+        );
+
+        Ok(Self(template))
+    }
+}
+
+/// Embeds the chunk and its generated questions and answers separately, as the vectors of a
+/// collection with `CollectionConfig::named_vectors`
+///
+/// Chunks without questions and answers, like those indexed without the metadata feature, have
+/// the chunk embedded as their summary as well.
+#[derive(Clone)]
+pub(crate) struct EmbedNamed(pub(crate) Arc<dyn EmbeddingModel>);
+
+#[async_trait]
+impl BatchableTransformer for EmbedNamed {
+    async fn batch_transform(&self, nodes: Vec<Node>) -> IndexingStream {
+        let texts = nodes
+            .iter()
+            .flat_map(|node| {
+                let summary = QA_METADATA
+                    .iter()
+                    .filter_map(|key| node.metadata.get(*key)?.as_str())
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                let summary = if summary.is_empty() {
+                    node.chunk.clone()
+                } else {
+                    summary
+                };
+                [node.chunk.clone(), summary]
+            })
+            .collect::<Vec<_>>();
+
+        let mut embeddings = match self.0.embed(texts).await {
+            Ok(embeddings) => embeddings.into_iter(),
+            Err(err) => return err.into(),
+        };
+
+        IndexingStream::iter(nodes.into_iter().map(move |mut node| {
+            let (Some(chunk), Some(summary)) = (embeddings.next(), embeddings.next()) else {
+                anyhow::bail!("Expected an embedding of the chunk and of its summary");
+            };
+            node.vectors = Some(HashMap::from([
+                (EmbeddedField::Chunk, chunk),
+                (EmbeddedField::Metadata(SUMMARY_FIELD.to_string()), summary),
+            ]));
+            Ok(node)
+        }))
+    }
+
+    fn name(&self) -> &'static str {
+        "EmbedNamed"
+    }
+}
+
+impl WithBatchIndexingDefaults for EmbedNamed {}
+
+/// Leaves metadata out of the embedded text, by taking it out of the nodes while the wrapped
+/// embedding step runs and putting it back after, so it is still stored
+#[derive(Debug)]
+pub(crate) struct SelectMetadata<T> {
+    pub(crate) inner: T,
+    pub(crate) embed_metadata: EmbedMetadata,
+}
+
+/// Metadata that only keeps track of a chunk, saying nothing about what it does
+const BOOKKEEPING_METADATA: [&str; 6] = [
+    "content_hash",
+    "start_line",
+    "end_line",
+    "source",
+    "last_modified",
+    "last_author",
+];
+
+#[async_trait]
+impl<T: BatchableTransformer> BatchableTransformer for SelectMetadata<T> {
+    async fn batch_transform(&self, mut nodes: Vec<Node>) -> IndexingStream {
+        if self.embed_metadata == EmbedMetadata::Include {
+            return self.inner.batch_transform(nodes).await;
+        }
+
+        let left_out = nodes
+            .iter_mut()
+            .map(|node| {
+                let metadata = std::mem::take(&mut node.metadata).into_iter();
+                match self.embed_metadata {
+                    EmbedMetadata::Include | EmbedMetadata::ContentOnly => metadata.collect(),
+                    EmbedMetadata::Exclude => {
+                        let (left_out, kept): (Vec<_>, Vec<_>) = metadata
+                            .partition(|(key, _)| BOOKKEEPING_METADATA.contains(&key.as_str()));
+                        node.metadata = kept.into();
+                        left_out
+                    }
+                }
+            })
+            .collect::<Vec<Vec<_>>>();
+
+        // The embedding step keeps the nodes in order
+        let embedded = self
+            .inner
+            .batch_transform(nodes)
+            .await
+            .collect::<Vec<_>>()
+            .await;
+        IndexingStream::iter(embedded.into_iter().zip(left_out).map(|(node, left_out)| {
+            node.map(|mut node| {
+                node.metadata.extend(left_out);
+                node
+            })
+        }))
+    }
+
+    fn concurrency(&self) -> Option<usize> {
+        self.inner.concurrency()
+    }
+
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+}
+
+impl<T: WithBatchIndexingDefaults> WithBatchIndexingDefaults for SelectMetadata<T> {
+    fn with_indexing_defaults(&mut self, indexing_defaults: IndexingDefaults) {
+        self.inner.with_indexing_defaults(indexing_defaults);
+    }
+}
+
+/// Adds when a file was last modified, and by whom, to the metadata of its chunks
+///
+/// Uses the last commit touching the file, falling back to the modification time of the file if
+/// it is not tracked by git. Looked up once per file, as a file is usually split in many chunks.
+#[derive(Default)]
+pub(crate) struct GitMetadata {
+    files: Mutex<HashMap<PathBuf, FileHistory>>,
+}
+
+#[derive(Clone, Debug)]
+struct FileHistory {
+    /// Seconds since the unix epoch
+    last_modified: Option<u64>,
+    last_author: Option<String>,
+}
+
+impl GitMetadata {
+    async fn history(path: &Path) -> FileHistory {
+        if let Some(history) = Self::git_history(path).await {
+            return history;
+        }
+
+        let last_modified = std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|since_epoch| since_epoch.as_secs());
+
+        FileHistory {
+            last_modified,
+            last_author: None,
+        }
+    }
+
+    /// The last commit touching the file, `None` if git fails or the file is not tracked
+    async fn git_history(path: &Path) -> Option<FileHistory> {
+        let dir = path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+
+        let output = tokio::process::Command::new("git")
+            .args(["log", "-1", "--format=%at%x00%an", "--"])
+            .arg(path.file_name()?)
+            .current_dir(dir)
+            .output()
+            .await
+            .ok()?;
+
+        if !output.status.success() {
+            return None;
+        }
+
+        let output = String::from_utf8(output.stdout).ok()?;
+        let (timestamp, author) = output.trim_end().split_once('\0')?;
+
+        Some(FileHistory {
+            last_modified: Some(timestamp.parse().ok()?),
+            last_author: Some(author.to_string()),
+        })
+    }
+}
+
+#[async_trait]
+impl Transformer for GitMetadata {
+    async fn transform_node(&self, mut node: Node) -> Result<Node> {
+        let known = self.files.lock().unwrap().get(&node.path).cloned();
+        let history = match known {
+            Some(history) => history,
+            None => {
+                let history = Self::history(&node.path).await;
+                self.files
+                    .lock()
+                    .unwrap()
+                    .insert(node.path.clone(), history.clone());
+                history
+            }
+        };
+
+        if let Some(last_modified) = history.last_modified {
+            node.metadata.insert("last_modified", last_modified);
+        }
+        if let Some(last_author) = history.last_author {
+            node.metadata.insert("last_author", last_author);
+        }
+
+        Ok(node)
+    }
+}
+
+impl WithIndexingDefaults for GitMetadata {}
@@ -0,0 +1,1229 @@
+//! Answers questions from the indexed code
+
+use std::{
+    collections::{HashSet, VecDeque},
+    io::Write as _,
+    path::Path,
+    sync::Arc,
+    time::Instant,
+};
+
+use anyhow::{Context as _, Result};
+use async_trait::async_trait;
+use indoc::formatdoc;
+use serde::{Deserialize, Serialize};
+use swiftide::{
+    query::{query_transformers::GenerateSubquestions, states, Query},
+    traits::{SimplePrompt, TransformQuery},
+};
+use tiktoken_rs::CoreBPE;
+use tracing::Instrument as _;
+
+use crate::{
+    cache::QueryCache,
+    chunk::Languages,
+    filter::PathFilter,
+    llm::{Cohere, Llm, Shared},
+    store::{cosine_similarity, Retrieved, VectorStore},
+    timing::elapsed_ms,
+};
+
+/// Joins the retrieved chunks in the prompt for answering
+pub(crate) const CHUNK_SEPARATOR: &str = "\n\n";
+
+/// How much less similar than the best chunk a chunk may be to also be attributed to a sentence
+const ATTRIBUTION_MARGIN: f32 = 0.02;
+
+/// The answer when no relevant chunks are found, with `OnEmpty::Refuse`
+const NO_CONTEXT_ANSWER: &str = "No relevant context was found to answer the question.";
+
+/// Precedes answers from general knowledge, with `OnEmpty::General`
+const GENERAL_KNOWLEDGE_NOTE: &str =
+    "Note: no relevant context was found, this answer is based on general knowledge only.";
+
+/// Number of additional questions generated to improve retrieval, unless configured otherwise
+pub const DEFAULT_NUM_SUBQUESTIONS: usize = 5;
+
+/// More subquestions than this drown out the question when embedded together
+pub const MAX_NUM_SUBQUESTIONS: usize = 20;
+
+/// How many times `top_k` chunks are retrieved as candidates for reranking
+const RERANK_CANDIDATES: u64 = 3;
+
+/// Number of tokens the history of a conversation may take before it is summarized
+const MAX_HISTORY_TOKENS: usize = 2_000;
+
+/// How a question is turned into the text that is embedded to retrieve chunks
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+pub enum QueryMode {
+    /// Embed the question together with additional questions that help answering it
+    #[default]
+    Subquestions,
+    /// Embed a hypothetical answer, which tends to be closer to the code than the question is
+    Hyde,
+    /// Embed the question as is
+    Raw,
+}
+
+impl QueryMode {
+    /// The query transformer of the mode, for use in a Swiftide query pipeline
+    pub(crate) fn transformer(
+        self,
+        llm: &Llm,
+        lang: &str,
+        num_subquestions: usize,
+    ) -> Result<Box<dyn TransformQuery>> {
+        Ok(match self {
+            QueryMode::Subquestions => subquestions_transformer(llm, num_subquestions)?,
+            QueryMode::Hyde => Box::new(Hyde {
+                client: llm.prompt.clone(),
+                lang: lang.to_string(),
+            }),
+            QueryMode::Raw => Box::new(|query: Query<states::Pending>| Ok(query)),
+        })
+    }
+}
+
+/// Swiftide's subquestion generator, with the example in its prompt listing as many questions as
+/// it asks for
+pub(crate) fn subquestions_transformer(
+    llm: &Llm,
+    num_subquestions: usize,
+) -> Result<Box<dyn TransformQuery>> {
+    if num_subquestions == 0 {
+        return Ok(Box::new(|query: Query<states::Pending>| Ok(query)));
+    }
+
+    let prompt_template = formatdoc!(
+        "
+        Your job is to help a query tool find the right context.
+
+        Given the following question:
+        {{{{question}}}}
+
+        Please think of {{{{num_questions}}}} additional questions that can help answering the original question.
+
+        Especially consider what might be relevant to answer the question, like dependencies, usage and structure of the code.
+
+        Please respond with the original question and the additional questions only.
+
+        ## Example
+
+        - {{{{question}}}}
+        {example}
+        ",
+        example = subquestions_example(num_subquestions),
+    );
+
+    Ok(Box::new(
+        GenerateSubquestions::builder()
+            .client(Shared(llm.prompt.clone()))
+            .prompt_template(prompt_template.into())
+            .num_questions(num_subquestions)
+            .build()?,
+    ))
+}
+
+/// The additional questions of the example answer in the subquestion prompts
+fn subquestions_example(num_subquestions: usize) -> String {
+    (1..=num_subquestions)
+        .map(|number| format!("- Additional question {number}"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Clients and settings for answering questions, set up once and reused for every question
+pub struct QueryContext {
+    pub llm: Llm,
+    /// The model `llm` prompts, answers of other models are not served from the query cache
+    pub prompt_model: String,
+    /// The stores to answer from, the best matches across all of them are used
+    pub stores: Vec<Box<dyn VectorStore>>,
+    pub top_k: u64,
+    /// The language of the code, which hypothetical answers and additional questions are about
+    pub language: Languages,
+    /// Print the answer to stdout while it is generated
+    pub stream: bool,
+    /// Retrieve more chunks than needed and keep the ones the prompt model rates most relevant
+    pub rerank: bool,
+    /// Chunks sharing more than this fraction of their tokens with a better match are dropped
+    pub dedup_threshold: f64,
+    /// Limits the size of the prompt for answering, by leaving out the least relevant chunks
+    pub token_budget: Option<TokenBudget>,
+    pub query_mode: QueryMode,
+    /// Only chunks of files matching the filter are retrieved
+    pub path_filter: PathFilter,
+    pub answer_template: AnswerTemplate,
+    /// Print what the question is rewritten into to stderr before retrieving
+    pub show_rewrite: bool,
+    /// Rerank with Cohere instead of the prompt model, if set
+    pub cohere_rerank: Option<Cohere>,
+    /// Attribute every sentence of the answer to the sources that support it best
+    pub explain: bool,
+    /// Number of additional questions generated with `QueryMode::Subquestions`
+    pub num_subquestions: usize,
+    /// Chunks less similar to the question than this are left out of the context
+    pub min_score: Option<f32>,
+    /// What to answer when no chunk is left to answer from
+    pub on_empty: OnEmpty,
+    /// Answer questions asked before from the cache, used by [`query`] only
+    pub query_cache: Option<QueryCache>,
+    /// Ask for the relevant code to be quoted verbatim, and flag quoted code that is not in the
+    /// retrieved chunks
+    pub verbatim_code: bool,
+    pub answer_format: AnswerFormat,
+    /// Ask for answers of at most this many words
+    pub max_answer_words: Option<usize>,
+}
+
+/// How answers are written
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AnswerFormat {
+    /// As the answer template asks for
+    #[default]
+    Prose,
+    /// As a markdown list of short points
+    Bullets,
+    /// As a json object with the answer and the paths it is based on, asked for once more if
+    /// the model does not return valid json
+    Json,
+}
+
+/// What to answer with when no relevant chunks are retrieved for a question
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+pub enum OnEmpty {
+    /// Answer that no relevant context was found, without asking the prompt model
+    #[default]
+    Refuse,
+    /// Answer from the general knowledge of the prompt model, flagged as such
+    General,
+    /// Fail the question
+    Error,
+}
+
+/// The maximum number of tokens in the prompt for answering a question
+pub struct TokenBudget {
+    pub(crate) max_tokens: usize,
+    bpe: &'static CoreBPE,
+}
+
+impl TokenBudget {
+    /// Counts tokens with the tokenizer of the model, falling back to the one of GPT-4 for models
+    /// tiktoken does not know, like those served by Ollama. The count is an estimate for those.
+    pub fn for_model(model: &str, max_tokens: usize) -> Self {
+        Self {
+            max_tokens,
+            bpe: tiktoken_rs::bpe_for_model(model)
+                .unwrap_or_else(|_| tiktoken_rs::cl100k_base_singleton()),
+        }
+    }
+
+    pub(crate) fn count(&self, text: &str) -> usize {
+        self.bpe.encode_with_special_tokens(text).len()
+    }
+
+    /// Keeps the chunks in order until the next one would push the prompt over the budget
+    fn fit(
+        &self,
+        template: &AnswerTemplate,
+        question: &str,
+        chunks: Vec<Retrieved>,
+    ) -> Vec<Retrieved> {
+        let retrieved = chunks.len();
+
+        let mut tokens = self.count(&template.render(question, ""));
+        if tokens > self.max_tokens {
+            tracing::warn!(
+                tokens,
+                max_tokens = self.max_tokens,
+                "The prompt exceeds the token budget without any context"
+            );
+        }
+
+        let included = chunks
+            .into_iter()
+            .take_while(|chunk| {
+                // The separator between chunks is counted as part of the chunk
+                tokens += self.count(&chunk.context()) + self.count(CHUNK_SEPARATOR);
+                tokens <= self.max_tokens
+            })
+            .collect::<Vec<_>>();
+
+        tracing::info!(
+            included = included.len(),
+            dropped = retrieved - included.len(),
+            max_tokens = self.max_tokens,
+            "Fitted retrieved chunks in the token budget"
+        );
+
+        included
+    }
+}
+
+/// The earlier questions and answers of an interactive session, for answering follow-up
+/// questions
+///
+/// Only the last `limit` turns are kept. Once they take more than `MAX_HISTORY_TOKENS`, all
+/// but the last turn are summarized with the prompt model.
+pub struct Conversation {
+    turns: VecDeque<(String, String)>,
+    summary: Option<String>,
+    limit: usize,
+    budget: TokenBudget,
+}
+
+impl Conversation {
+    /// A conversation keeping `limit` turns, with the tokens counted for the prompt model
+    pub fn new(prompt_model: &str, limit: usize) -> Self {
+        Self {
+            turns: VecDeque::new(),
+            summary: None,
+            limit,
+            budget: TokenBudget::for_model(prompt_model, MAX_HISTORY_TOKENS),
+        }
+    }
+
+    /// The summary and the turns as text for the prompts, `None` before the first answer
+    fn history(&self) -> Option<String> {
+        let turns = self
+            .turns
+            .iter()
+            .map(|(question, answer)| format!("Q: {question}\nA: {answer}"));
+        let history = self
+            .summary
+            .iter()
+            .map(|summary| format!("Summary of the earlier conversation: {summary}"))
+            .chain(turns)
+            .collect::<Vec<_>>();
+
+        (!history.is_empty()).then(|| history.join("\n\n"))
+    }
+
+    async fn record(&mut self, llm: &Llm, question: &str, answer: &str) -> Result<()> {
+        if self.limit == 0 {
+            return Ok(());
+        }
+
+        self.turns
+            .push_back((question.to_string(), answer.to_string()));
+        while self.turns.len() > self.limit {
+            self.turns.pop_front();
+        }
+
+        let tokens = self
+            .history()
+            .map_or(0, |history| self.budget.count(&history));
+        if tokens <= self.budget.max_tokens || self.turns.len() < 2 {
+            return Ok(());
+        }
+
+        // Summarizing the last turn as well would lose the details follow-ups ask about
+        let last = self.turns.pop_back().expect("Expected a turn");
+        let summary = llm
+            .prompt
+            .prompt(
+                formatdoc!(
+                    r"
+                    Summarize the following conversation about a codebase in a few sentences.
+                    Keep the files, functions and conclusions that were mentioned.
+
+                    {history}
+                    ",
+                    history = self.history().unwrap_or_default()
+                )
+                .into(),
+            )
+            .await?;
+        tracing::debug!(tokens, "Summarized the conversation");
+
+        self.summary = Some(summary);
+        self.turns = VecDeque::from([last]);
+
+        Ok(())
+    }
+}
+
+/// Everything that went into answering a question
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QueryResult {
+    pub question: String,
+    /// The additional questions generated to improve retrieval, with `QueryMode::Subquestions`
+    pub subquestions: Vec<String>,
+    /// The answer embedded instead of the question, with `QueryMode::Hyde`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hypothetical_answer: Option<String>,
+    /// The retrieved chunks the answer is based on, in order of similarity or relevance when
+    /// reranked
+    pub sources: Vec<Citation>,
+    pub answer: String,
+    /// Whether any retrieved chunk was left to answer from, see `QueryContext::min_score`
+    pub context_found: bool,
+    /// The sources supporting every sentence of the answer, with `QueryContext::explain`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attributions: Vec<Attribution>,
+    /// Code blocks of the answer that are not in the retrieved chunks, with
+    /// `QueryContext::verbatim_code`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unverified_code: Vec<String>,
+    /// The parsed answer, with `AnswerFormat::Json`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub answer_json: Option<serde_json::Value>,
+}
+
+/// A sentence of the answer and the sources most similar to it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Attribution {
+    pub sentence: String,
+    /// Indices into `QueryResult::sources`, most similar first
+    pub sources: Vec<usize>,
+}
+
+/// Where a retrieved chunk comes from, and how similar it is to the question
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Citation {
+    /// Path of the chunk with its lines as `path:start-end` if known
+    pub location: String,
+    /// Similarity of the chunk to the question, see [`Retrieved::score`]
+    pub score: f32,
+}
+
+pub async fn query(context: &QueryContext, question: &str) -> Result<QueryResult> {
+    let Some(cache) = &context.query_cache else {
+        return answer(context, question, None).await;
+    };
+
+    let key = cache.key(context, question);
+    if let Some(result) = cache.get(&key)? {
+        tracing::info!("Answered from the query cache");
+        if context.stream {
+            println!("{}", result.answer);
+        }
+        return Ok(result);
+    }
+
+    let result = answer(context, question, None).await?;
+    cache.insert(&key, &result)?;
+    Ok(result)
+}
+
+/// Answers a question that may follow up on the earlier questions in the conversation, and adds
+/// it to the conversation
+pub async fn converse(
+    context: &QueryContext,
+    conversation: &mut Conversation,
+    question: &str,
+) -> Result<QueryResult> {
+    let history = conversation.history();
+    let result = answer(context, question, history.as_deref()).await?;
+    conversation
+        .record(&context.llm, &result.question, &result.answer)
+        .await?;
+
+    Ok(result)
+}
+
+/// Answers the question, with the history of the conversation in the prompts if there is one
+#[tracing::instrument(skip(context, history))]
+async fn answer(
+    context: &QueryContext,
+    question: &str,
+    history: Option<&str>,
+) -> Result<QueryResult> {
+    let QueryContext {
+        llm,
+        prompt_model: _,
+        stores,
+        top_k,
+        language,
+        stream,
+        rerank: rerank_enabled,
+        dedup_threshold,
+        token_budget,
+        query_mode,
+        path_filter,
+        answer_template,
+        show_rewrite,
+        cohere_rerank,
+        explain,
+        num_subquestions,
+        min_score,
+        on_empty,
+        query_cache: _,
+        verbatim_code,
+        answer_format,
+        max_answer_words,
+    } = context;
+
+    let transformed_question = match query_mode {
+        QueryMode::Subquestions => {
+            generate_subquestions(llm, question, *language, history, *num_subquestions).await?
+        }
+        QueryMode::Hyde => {
+            llm.prompt
+                .prompt(hyde_prompt(question, &language.to_string(), history).into())
+                .await?
+        }
+        QueryMode::Raw => question.to_string(),
+    };
+    if *show_rewrite {
+        print_rewrite(question, &transformed_question);
+    }
+
+    // Embed the full rewrite for querying
+    let span = tracing::info_span!("embed", texts = 1, latency_ms = tracing::field::Empty);
+    let started = Instant::now();
+    let embedded_question = llm
+        .embed
+        .embed(vec![transformed_question.clone()])
+        .instrument(span.clone())
+        .await?
+        .pop()
+        .context("Expected embedding")?;
+    span.record("latency_ms", elapsed_ms(started.elapsed()));
+
+    // Reranking needs more candidates than it keeps to make a difference
+    let candidates = if *rerank_enabled {
+        top_k * RERANK_CANDIDATES
+    } else {
+        *top_k
+    };
+
+    // Search for matches in every store and keep the best ones overall
+    let mut retrieved = Vec::new();
+    for store in stores {
+        retrieved.extend(
+            store
+                .search(
+                    embedded_question.clone(),
+                    &transformed_question,
+                    candidates,
+                    path_filter,
+                )
+                .await?,
+        );
+    }
+    if retrieved.is_empty() && !path_filter.is_empty() {
+        anyhow::bail!(
+            "No indexed chunks match the path filter, check --filter-path and --filter-ext"
+        );
+    }
+    retrieved.sort_by(|a, b| b.score.total_cmp(&a.score));
+    retrieved.truncate(usize::try_from(candidates).unwrap_or(usize::MAX));
+
+    if *rerank_enabled {
+        retrieved = match cohere_rerank {
+            Some(cohere) => cohere.rerank(question, retrieved, *top_k).await?,
+            None => rerank(&llm.prompt, question, retrieved, *top_k).await?,
+        };
+    }
+
+    let mut retrieved = dedup(retrieved, *dedup_threshold);
+    if let Some(min_score) = min_score {
+        retrieved.retain(|chunk| chunk.score >= *min_score);
+    }
+    let context_found = !retrieved.is_empty();
+
+    // The history goes with the question, so it works with custom templates as well
+    let mut prompt_question = match history {
+        Some(history) => format!("{question}\n\n## Conversation so far\n{history}"),
+        None => question.to_string(),
+    };
+    if *verbatim_code {
+        prompt_question = format!("{prompt_question}\n\n{VERBATIM_CODE_INSTRUCTIONS}");
+    }
+    if let Some(instructions) = answer_instructions(*answer_format, *max_answer_words) {
+        prompt_question = format!("{prompt_question}\n\n{instructions}");
+    }
+
+    if let Some(token_budget) = token_budget {
+        retrieved = token_budget.fit(answer_template, &prompt_question, retrieved);
+    }
+
+    let sources = retrieved
+        .iter()
+        .map(|retrieved| Citation {
+            location: retrieved.citation(),
+            score: retrieved.score,
+        })
+        .collect::<Vec<_>>();
+
+    // Concatenate all the found chunks
+    let answer_context = retrieved
+        .iter()
+        .map(Retrieved::context)
+        .collect::<Vec<_>>()
+        .join(CHUNK_SEPARATOR);
+
+    let answer = match (context_found, on_empty) {
+        (true, _) => {
+            let prompt = answer_template.render(&prompt_question, &answer_context);
+            generate_answer(llm, prompt, *stream, *answer_format).await?
+        }
+        (false, OnEmpty::Refuse) => {
+            let answer = match answer_format {
+                AnswerFormat::Json => serde_json::to_string_pretty(&serde_json::json!({
+                    "answer": NO_CONTEXT_ANSWER,
+                    "sources": [],
+                }))?,
+                AnswerFormat::Prose | AnswerFormat::Bullets => NO_CONTEXT_ANSWER.to_string(),
+            };
+            if *stream {
+                println!("{answer}");
+            }
+            answer
+        }
+        (false, OnEmpty::General) => {
+            let prompt = formatdoc!(
+                r"
+                Answer the following question from your general knowledge, no code or
+                documentation of the project was found for it. Do not make up details about the
+                project.
+
+                ## Question
+                {prompt_question}
+                "
+            );
+            if *answer_format == AnswerFormat::Json {
+                // The note would break the json, `context_found` tells these answers apart
+                generate_answer(llm, prompt, *stream, *answer_format).await?
+            } else {
+                if *stream {
+                    println!("{GENERAL_KNOWLEDGE_NOTE}\n");
+                }
+                let answer = generate_answer(llm, prompt, *stream, *answer_format).await?;
+                format!("{GENERAL_KNOWLEDGE_NOTE}\n\n{answer}")
+            }
+        }
+        (false, OnEmpty::Error) => match min_score {
+            Some(min_score) => anyhow::bail!(
+                "No retrieved chunk scored at least {min_score}, lower --min-score or rephrase the \
+                 question"
+            ),
+            None => anyhow::bail!("No chunks were retrieved, is the collection indexed?"),
+        },
+    };
+
+    // The rewrite is a markdown list with the original question first
+    let subquestions = match query_mode {
+        QueryMode::Subquestions => transformed_question
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("- "))
+            .filter(|line| *line != question)
+            .map(str::to_string)
+            .collect(),
+        QueryMode::Hyde | QueryMode::Raw => Vec::new(),
+    };
+    let hypothetical_answer = matches!(query_mode, QueryMode::Hyde).then_some(transformed_question);
+
+    let attributions = if *explain {
+        attribute(llm, &answer, &retrieved).await?
+    } else {
+        Vec::new()
+    };
+
+    let unverified_code = if *verbatim_code {
+        unverified_code_blocks(&answer, &answer_context)
+    } else {
+        Vec::new()
+    };
+    if !unverified_code.is_empty() {
+        tracing::warn!(
+            blocks = unverified_code.len(),
+            "The answer quotes code that is not in the retrieved chunks"
+        );
+    }
+
+    let answer_json = match answer_format {
+        AnswerFormat::Json => Some(serde_json::from_str(&answer)?),
+        AnswerFormat::Prose | AnswerFormat::Bullets => None,
+    };
+
+    Ok(QueryResult {
+        question: question.to_string(),
+        subquestions,
+        hypothetical_answer,
+        sources,
+        answer,
+        context_found,
+        attributions,
+        unverified_code,
+        answer_json,
+    })
+}
+
+/// Added to the question for `QueryContext::answer_format` and `max_answer_words`, if either
+/// changes the answer
+fn answer_instructions(format: AnswerFormat, max_words: Option<usize>) -> Option<String> {
+    let mut instructions = match format {
+        AnswerFormat::Prose => Vec::new(),
+        AnswerFormat::Bullets => vec![
+            "* Answer with a markdown list of short points, without an introduction or a \
+             conclusion"
+                .to_string(),
+        ],
+        AnswerFormat::Json => vec![JSON_ANSWER_INSTRUCTIONS.trim_end().to_string()],
+    };
+    if let Some(max_words) = max_words {
+        instructions.push(format!("* Use at most {max_words} words"));
+    }
+
+    (!instructions.is_empty()).then(|| format!("## Answer format\n{}", instructions.join("\n")))
+}
+
+/// Added to the question with `AnswerFormat::Json`
+const JSON_ANSWER_INSTRUCTIONS: &str = indoc::indoc! {r#"
+    * Respond with a json object only, without a code fence or any text around it
+    * The object has the answer as a string and the paths of the context it is based on:
+        {"answer": "...", "sources": ["..."]}
+"#};
+
+/// Prompts for the answer, printing it while it is generated if streaming
+///
+/// Json answers are only printed once they are parsed, as an invalid one is asked for once more.
+/// They are returned pretty printed.
+async fn generate_answer(
+    llm: &Llm,
+    prompt: String,
+    stream: bool,
+    format: AnswerFormat,
+) -> Result<String> {
+    match format {
+        AnswerFormat::Json => {
+            let answer = serde_json::to_string_pretty(&json_answer(llm, prompt).await?)?;
+            if stream {
+                println!("{answer}");
+            }
+            Ok(answer)
+        }
+        AnswerFormat::Prose | AnswerFormat::Bullets if stream => stream_answer(llm, prompt).await,
+        AnswerFormat::Prose | AnswerFormat::Bullets => llm.prompt.prompt(prompt.into()).await,
+    }
+}
+
+/// Prompts for a json answer, asking once more with the error if it is not valid
+async fn json_answer(llm: &Llm, prompt: String) -> Result<serde_json::Value> {
+    let answer = llm.prompt.prompt(prompt.clone().into()).await?;
+    let err = match parse_json_answer(&answer) {
+        Ok(json) => return Ok(json),
+        Err(err) => err,
+    };
+
+    tracing::warn!(error = %err, "The answer is not valid json, asking once more");
+    let retry = format!(
+        "{prompt}\n\n## Your previous response\n{answer}\n\nIt is invalid: {err}. Respond \
+         with the json object only."
+    );
+    let answer = llm.prompt.prompt(retry.into()).await?;
+    parse_json_answer(&answer).context("The answer is not valid json, even when asked once more")
+}
+
+/// Parses a json answer, which must be an object with an answer string
+fn parse_json_answer(answer: &str) -> Result<serde_json::Value> {
+    // Models tend to fence json even when asked not to
+    let answer = answer.trim();
+    let json = answer
+        .strip_prefix("```json")
+        .or_else(|| answer.strip_prefix("```"))
+        .and_then(|fenced| fenced.strip_suffix("```"))
+        .unwrap_or(answer);
+
+    let value: serde_json::Value = serde_json::from_str(json)?;
+    anyhow::ensure!(
+        value
+            .get("answer")
+            .is_some_and(serde_json::Value::is_string),
+        "Expected a json object with an answer string"
+    );
+    Ok(value)
+}
+
+/// Added to the question with `QueryContext::verbatim_code`
+const VERBATIM_CODE_INSTRUCTIONS: &str = indoc::indoc! {"
+    ## Quoting code
+    * Quote the code that answers the question verbatim in fenced code blocks, exactly as it is
+        in the context, without changing, shortening or completing it
+    * Put the path and lines of the quoted code, as given in the context, right before every
+        block
+"};
+
+/// The fenced code blocks of the answer whose lines are not in the context in the same order
+///
+/// Lines are compared trimmed and blank lines are ignored, as models tend to change the
+/// indentation of code they quote.
+fn unverified_code_blocks(answer: &str, answer_context: &str) -> Vec<String> {
+    let normalize = |text: &str| {
+        text.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+    let context = normalize(answer_context);
+
+    let mut blocks = Vec::new();
+    let mut block: Option<Vec<&str>> = None;
+    for line in answer.lines() {
+        if !line.trim_start().starts_with("```") {
+            if let Some(block) = &mut block {
+                block.push(line);
+            }
+            continue;
+        }
+
+        match block.take() {
+            Some(lines) => blocks.push(lines.join("\n")),
+            None => block = Some(Vec::new()),
+        }
+    }
+
+    blocks
+        .into_iter()
+        .filter(|block| {
+            let quoted = normalize(block);
+            !quoted.is_empty() && !context.contains(&quoted)
+        })
+        .collect()
+}
+
+/// Attributes every sentence of the answer to the chunks most similar to it, by embedding the
+/// sentences and the chunks
+///
+/// Besides the most similar chunk, chunks at most `ATTRIBUTION_MARGIN` less similar are
+/// attributed as well.
+async fn attribute(llm: &Llm, answer: &str, chunks: &[Retrieved]) -> Result<Vec<Attribution>> {
+    let sentences = split_sentences(answer);
+    if sentences.is_empty() || chunks.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let texts = sentences
+        .iter()
+        .map(|sentence| (*sentence).to_string())
+        .chain(chunks.iter().map(|chunk| chunk.content.clone()))
+        .collect();
+    let mut sentence_embeddings = llm.embed.embed(texts).await?;
+    let chunk_embeddings = sentence_embeddings.split_off(sentences.len());
+
+    Ok(sentences
+        .into_iter()
+        .zip(sentence_embeddings)
+        .map(|(sentence, sentence_embedding)| {
+            let mut scored = chunk_embeddings
+                .iter()
+                .map(|chunk| cosine_similarity(&sentence_embedding, chunk))
+                .enumerate()
+                .collect::<Vec<_>>();
+            scored.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+            let best = scored[0].1;
+
+            Attribution {
+                sentence: sentence.to_string(),
+                sources: scored
+                    .into_iter()
+                    .take_while(|(_, score)| *score >= best - ATTRIBUTION_MARGIN)
+                    .map(|(index, _)| index)
+                    .collect(),
+            }
+        })
+        .collect())
+}
+
+/// Splits text into sentences after `.`, `?` and `!` followed by whitespace, and at line breaks
+///
+/// Code fences are left out, lines of code count as sentences.
+fn split_sentences(text: &str) -> Vec<&str> {
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((index, char)) = chars.next() {
+        let end = match char {
+            '\n' => index,
+            '.' | '?' | '!' if chars.peek().is_none_or(|(_, next)| next.is_whitespace()) => {
+                index + char.len_utf8()
+            }
+            _ => continue,
+        };
+        sentences.push(&text[start..end]);
+        start = end;
+    }
+    sentences.push(&text[start..]);
+
+    sentences
+        .into_iter()
+        .map(str::trim)
+        .filter(|sentence| !sentence.is_empty() && !sentence.starts_with("```"))
+        .collect()
+}
+
+/// Uses Swiftide's llm client to rewrite the question to a set of questions
+///
+/// Without any additional questions to generate, the question is left as is.
+async fn generate_subquestions(
+    llm: &Llm,
+    question: &str,
+    language: Languages,
+    history: Option<&str>,
+    num_subquestions: usize,
+) -> Result<String> {
+    if num_subquestions == 0 {
+        return Ok(format!("- {question}"));
+    }
+
+    let follow_up = follow_up(history);
+    llm.prompt.prompt(formatdoc!(r"
+        Your job is to help a code query tool finding the right context.
+
+        Given the following question:
+        {question}
+        {follow_up}
+
+        Please think of {num_subquestions} additional questions that can help answering the original question. The code is written in {lang}.
+
+        Especially consider what might be relevant to answer the question, like dependencies, usage and structure of the code.
+
+        Please respond with the original question and the additional questions only.
+
+        ## Example
+
+        - {question}
+        {example}
+        ", question = question, lang = language, example = subquestions_example(num_subquestions)
+    ).into()).await
+}
+
+/// Asks to make the questions understandable on their own when the question follows up on a
+/// conversation, nothing otherwise
+fn follow_up(history: Option<&str>) -> String {
+    history.map_or_else(String::new, |history| {
+        formatdoc!(
+            r"
+            The question follows up on the conversation below. Make sure the questions can be
+            understood without it.
+
+            ## Conversation so far
+            {history}
+            "
+        )
+    })
+}
+
+/// A prompt for a hypothetical answer to the question, to embed instead of the question itself
+///
+/// The answer does not need to be correct, it only needs to resemble the code and documentation
+/// that answer the question. See <https://arxiv.org/abs/2212.10496>.
+fn hyde_prompt(question: &str, lang: &str, history: Option<&str>) -> String {
+    let follow_up = follow_up(history);
+    formatdoc!(
+        r"
+        Your job is to help a code query tool finding the right context.
+
+        Given the following question about a project written in {lang}:
+        {question}
+        {follow_up}
+
+        Please write a plausible answer to the question, including the code that would implement
+        or use what is asked about. It does not matter if the answer is not correct for this
+        project, as long as it looks like the code and documentation that would answer it.
+
+        Please respond with the answer only.
+        "
+    )
+}
+
+/// Replaces the query with a hypothetical answer to it (HyDE)
+struct Hyde {
+    client: Arc<dyn SimplePrompt>,
+    lang: String,
+}
+
+#[async_trait]
+impl TransformQuery for Hyde {
+    async fn transform_query(
+        &self,
+        mut query: Query<states::Pending>,
+    ) -> Result<Query<states::Pending>> {
+        let answer = self
+            .client
+            .prompt(hyde_prompt(query.current(), &self.lang, None).into())
+            .await?;
+        query.transformed_query(answer);
+
+        Ok(query)
+    }
+}
+
+/// Prints the rewrites of the wrapped transformer, like `--show-rewrite` does when querying
+pub(crate) struct ShowRewrite(pub(crate) Box<dyn TransformQuery>);
+
+#[async_trait]
+impl TransformQuery for ShowRewrite {
+    async fn transform_query(
+        &self,
+        query: Query<states::Pending>,
+    ) -> Result<Query<states::Pending>> {
+        let query = self.0.transform_query(query).await?;
+        print_rewrite(query.original(), query.current());
+
+        Ok(query)
+    }
+}
+
+/// Prints the rewrite of a question to stderr, apart from the answer on stdout
+///
+/// Shows whether a bad answer comes from a bad rewrite or from bad retrieval.
+fn print_rewrite(question: &str, rewrite: &str) {
+    eprintln!("## Rewrite of: {question}");
+    eprintln!("{}", rewrite.trim());
+    eprintln!();
+}
+
+/// The template of the prompt for answering the question with the found context
+///
+/// `{question}` and `{answer_context}` are replaced with the question and the retrieved chunks,
+/// anything else is kept as is.
+#[derive(Clone, Debug)]
+pub struct AnswerTemplate(pub(crate) String);
+
+impl AnswerTemplate {
+    const PLACEHOLDERS: [&str; 2] = ["{question}", "{answer_context}"];
+
+    /// Loads a template from a file, which must contain both placeholders
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let template = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+
+        let missing = Self::PLACEHOLDERS
+            .into_iter()
+            .filter(|placeholder| !template.contains(placeholder))
+            .collect::<Vec<_>>();
+        anyhow::ensure!(
+            missing.is_empty(),
+            "The prompt template {} is missing {}",
+            path.display(),
+            missing.join(" and ")
+        );
+
+        Ok(Self(template))
+    }
+
+    fn render(&self, question: &str, answer_context: &str) -> String {
+        // The question goes first, as the context is far more likely to contain a placeholder
+        self.0
+            .replace("{question}", question)
+            .replace("{answer_context}", answer_context)
+    }
+}
+
+impl Default for AnswerTemplate {
+    fn default() -> Self {
+        Self(DEFAULT_ANSWER_TEMPLATE.to_string())
+    }
+}
+
+const DEFAULT_ANSWER_TEMPLATE: &str = indoc::indoc! {r#"
+    Answer the following question(s):
+    {question}
+
+    ## Constraints
+    * Only answer based on the provided context below
+    * Always reference files by the full path if it is relevant to the question, with the
+        lines as given in the context
+    * Answer the question fully and remember to be concise
+    * Only answer based on the given context. If you cannot answer the question based on the
+        context, say so.
+    * Do not make up anything, especially code, that is not included in the provided context
+
+    ## Context:
+    {answer_context}
+    "#};
+
+/// Rates the relevance of every chunk to the question with the prompt model and keeps the `top_k`
+/// most relevant ones
+///
+/// Chunks are rated concurrently. Chunks rated equally keep their order by similarity.
+async fn rerank(
+    prompt: &Arc<dyn SimplePrompt>,
+    question: &str,
+    chunks: Vec<Retrieved>,
+    top_k: u64,
+) -> Result<Vec<Retrieved>> {
+    let candidates = chunks.len();
+
+    let mut rating = tokio::task::JoinSet::new();
+    for (position, chunk) in chunks.into_iter().enumerate() {
+        let prompt = Arc::clone(prompt);
+        let rate = formatdoc!(
+            r"
+            Rate how relevant the following code or documentation is for answering the question.
+
+            Respond with a single number from 0 to 10 only, where 0 is irrelevant and 10 answers
+            the question directly.
+
+            ## Question
+            {question}
+
+            ## {path}
+            {content}
+            ",
+            path = chunk.path,
+            content = chunk.content,
+        );
+
+        rating.spawn(async move {
+            let rating = prompt.prompt(rate.into()).await?;
+            let relevance = rating.trim().parse::<f32>().unwrap_or_else(|_| {
+                tracing::warn!(
+                    rating,
+                    path = chunk.path,
+                    "Could not parse relevance, using 0"
+                );
+                0.
+            });
+
+            Ok::<_, anyhow::Error>((relevance, position, chunk))
+        });
+    }
+
+    let mut rated = Vec::with_capacity(candidates);
+    while let Some(result) = rating.join_next().await {
+        rated.push(result??);
+    }
+    rated.sort_by(|(a, a_pos, _), (b, b_pos, _)| b.total_cmp(a).then(a_pos.cmp(b_pos)));
+
+    let kept = rated
+        .into_iter()
+        .take(usize::try_from(top_k).unwrap_or(usize::MAX))
+        .map(|(_, _, chunk)| chunk)
+        .collect::<Vec<_>>();
+
+    tracing::info!(
+        candidates,
+        kept = kept.len(),
+        dropped = candidates - kept.len(),
+        "Reranked retrieved chunks"
+    );
+
+    Ok(kept)
+}
+
+/// Drops chunks that are near copies of a better match, as they only take up context
+///
+/// Similarity is the overlap of the whitespace separated tokens of two chunks (Jaccard index).
+/// Chunks are only dropped if they are more similar than the threshold, so 1 keeps everything.
+fn dedup(chunks: Vec<Retrieved>, threshold: f64) -> Vec<Retrieved> {
+    let retrieved = chunks.len();
+
+    let keep = {
+        let tokens = chunks
+            .iter()
+            .map(|chunk| chunk.content.split_whitespace().collect::<HashSet<_>>())
+            .collect::<Vec<_>>();
+
+        let mut kept = Vec::<&HashSet<&str>>::with_capacity(retrieved);
+        tokens
+            .iter()
+            .map(|chunk| {
+                let duplicate = kept
+                    .iter()
+                    .any(|kept| token_overlap(chunk, kept) > threshold);
+                if !duplicate {
+                    kept.push(chunk);
+                }
+                !duplicate
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let deduped = chunks
+        .into_iter()
+        .zip(keep)
+        .filter_map(|(chunk, keep)| keep.then_some(chunk))
+        .collect::<Vec<_>>();
+
+    tracing::debug!(
+        retrieved,
+        removed = retrieved - deduped.len(),
+        "Removed near duplicate chunks"
+    );
+
+    deduped
+}
+
+fn token_overlap(a: &HashSet<&str>, b: &HashSet<&str>) -> f64 {
+    let union = a.union(b).count();
+    if union == 0 {
+        return 1.;
+    }
+
+    a.intersection(b).count() as f64 / union as f64
+}
+
+/// Prints the answer while it is generated and returns it once complete
+///
+/// Falls back to waiting for the full answer if the backend fails before streaming anything.
+async fn stream_answer(llm: &Llm, prompt: String) -> Result<String> {
+    let mut streamed = false;
+    let mut stdout = std::io::stdout();
+
+    let result = llm
+        .stream
+        .prompt_streaming(prompt.clone().into(), &mut |token| {
+            streamed = true;
+            print!("{token}");
+            let _ = stdout.flush();
+        })
+        .await;
+
+    match result {
+        Ok(answer) => {
+            println!();
+            Ok(answer)
+        }
+        Err(err) if !streamed => {
+            tracing::warn!(error = %err, "Failed to stream the answer, waiting for all of it");
+            let answer = llm.prompt.prompt(prompt.into()).await?;
+            println!("{answer}");
+            Ok(answer)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn retrieved(content: &str) -> Retrieved {
+        Retrieved {
+            path: "src/lib.rs".to_string(),
+            content: content.to_string(),
+            score: 1.,
+            lines: None,
+        }
+    }
+
+    #[test]
+    fn token_budget_keeps_the_chunks_that_fit() {
+        let template = AnswerTemplate::default();
+        let chunks = || vec![retrieved("fn first() {}"), retrieved("fn second() {}")];
+        let unlimited = TokenBudget::for_model("gpt-4o", usize::MAX);
+        let first = unlimited.count(&template.render("Why?", ""))
+            + unlimited.count(&retrieved("fn first() {}").context())
+            + unlimited.count(CHUNK_SEPARATOR);
+
+        assert_eq!(unlimited.fit(&template, "Why?", chunks()).len(), 2);
+        let kept = TokenBudget::for_model("gpt-4o", first).fit(&template, "Why?", chunks());
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].content, "fn first() {}");
+        assert!(TokenBudget::for_model("gpt-4o", 0)
+            .fit(&template, "Why?", chunks())
+            .is_empty());
+    }
+
+    #[test]
+    fn unverified_code_blocks_ignore_indentation() {
+        let context = "## src/lib.rs\nfn a() {\n    1\n}\n";
+        let answer = "It is\n```rust\nfn a() {\n  1\n}\n```\nnot\n```\nfn b() {}\n```\n";
+        assert_eq!(unverified_code_blocks(answer, context), vec!["fn b() {}"]);
+    }
+}
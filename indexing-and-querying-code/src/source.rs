@@ -0,0 +1,394 @@
+//! The sources to index and the files selected from them
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context as _, Result};
+use rand::{rngs::StdRng, seq::SliceRandom as _, SeedableRng as _};
+use swiftide::{
+    indexing::{IndexingStream, Node},
+    traits::Loader,
+};
+use tempfile::TempDir;
+
+use crate::index::IndexOptions;
+
+/// A local directory to index, shallow cloned first if `--path` is a git url, or text read from
+/// stdin
+pub struct Source {
+    pub path: PathBuf,
+    /// Name of the directory or repository
+    pub name: String,
+    /// The path or url as given on the command line
+    pub origin: String,
+    /// Only these files are indexed instead of every file in the directory, if set
+    pub files: Option<Vec<PathBuf>>,
+    /// Files that no longer exist since the ref of `Source::changed_since`
+    pub deleted: Vec<PathBuf>,
+    /// Indexed as the only file, at `path`, instead of reading anything from disk
+    pub text: Option<String>,
+    /// Removes the clone when the source is dropped
+    _checkout: Option<TempDir>,
+}
+
+impl Source {
+    pub async fn resolve(path: &str, branch: Option<&str>) -> Result<Source> {
+        if !is_git_url(path) {
+            let path = PathBuf::from(path);
+            return Ok(Source {
+                origin: path.display().to_string(),
+                name: path
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .to_string(),
+                path,
+                files: None,
+                deleted: Vec::new(),
+                text: None,
+                _checkout: None,
+            });
+        }
+
+        let checkout = TempDir::new()?;
+        tracing::info!(url = path, branch, dir = ?checkout.path(), "Cloning repository");
+
+        let mut git = tokio::process::Command::new("git");
+        git.args(["clone", "--depth", "1"]);
+        if let Some(branch) = branch {
+            git.args(["--branch", branch]);
+        }
+        let status = git
+            .arg(path)
+            .arg(checkout.path())
+            .status()
+            .await
+            .context("Failed to run git, is it installed?")?;
+
+        if !status.success() {
+            anyhow::bail!("Failed to clone {path}");
+        }
+
+        Ok(Source {
+            path: checkout.path().to_path_buf(),
+            origin: path.to_string(),
+            name: path
+                .trim_end_matches('/')
+                .trim_end_matches(".git")
+                .rsplit(['/', ':'])
+                .next()
+                .unwrap_or_default()
+                .to_string(),
+            files: None,
+            deleted: Vec::new(),
+            text: None,
+            _checkout: Some(checkout),
+        })
+    }
+
+    /// Text to index as a single file named `stdin.<extension>`, the extension decides how it is
+    /// chunked
+    pub fn from_text(text: String, extension: &str) -> Source {
+        Source {
+            path: PathBuf::from(format!("stdin.{extension}")),
+            name: "stdin".to_string(),
+            origin: "stdin".to_string(),
+            files: None,
+            deleted: Vec::new(),
+            text: Some(text),
+            _checkout: None,
+        }
+    }
+
+    /// Only index the files changed since `git_ref`, instead of every file in the directory
+    ///
+    /// Changed files are the ones `git diff` reports against the ref, plus untracked files. Files
+    /// that no longer exist are kept in `deleted`, so their chunks can be removed from the store.
+    pub async fn changed_since(&mut self, git_ref: &str) -> Result<()> {
+        let changed = git_lines(
+            &self.path,
+            &["diff", "--name-only", "--relative", git_ref, "--"],
+        )
+        .await
+        .with_context(|| format!("Failed to diff {} against {git_ref}", self.origin))?;
+        let untracked = git_lines(&self.path, &["ls-files", "--others", "--exclude-standard"])
+            .await
+            .with_context(|| format!("Failed to list untracked files in {}", self.origin))?;
+
+        let (files, deleted): (Vec<_>, Vec<_>) = changed
+            .into_iter()
+            .chain(untracked)
+            .map(|path| self.path.join(path))
+            .partition(|path| path.exists());
+        tracing::info!(
+            source = self.origin,
+            since = git_ref,
+            changed = files.len(),
+            deleted = deleted.len(),
+            "Only indexing changed files"
+        );
+
+        self.files = Some(files);
+        self.deleted = deleted;
+
+        Ok(())
+    }
+}
+
+/// The lines git prints for the arguments, run in `dir`
+async fn git_lines(dir: &Path, args: &[&str]) -> Result<Vec<String>> {
+    let output = tokio::process::Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .await
+        .context("Failed to run git, is it installed?")?;
+
+    if !output.status.success() {
+        anyhow::bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+
+    Ok(String::from_utf8(output.stdout)?
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+fn is_git_url(path: &str) -> bool {
+    ["https://", "http://", "ssh://", "git@"]
+        .iter()
+        .any(|prefix| path.starts_with(prefix))
+}
+
+/// Loads the given files, like `FileLoader` does for the files in a directory
+///
+/// The text of PDFs is extracted, PDFs it cannot be extracted from are skipped.
+pub(crate) struct FileList(pub(crate) Vec<PathBuf>);
+
+impl Loader for FileList {
+    fn into_stream(self) -> IndexingStream {
+        IndexingStream::iter(self.0.into_iter().filter_map(|path| {
+            let content = if is_pdf(&path) {
+                extract_pdf(&path)?
+            } else {
+                match std::fs::read_to_string(&path) {
+                    Ok(content) => content,
+                    Err(err) => {
+                        return Some(Err(anyhow::Error::from(err)
+                            .context(format!("Failed to read {}", path.display()))))
+                    }
+                }
+            };
+
+            Some(Ok(Node {
+                path,
+                original_size: content.len(),
+                chunk: content,
+                ..Default::default()
+            }))
+        }))
+    }
+}
+
+/// Loads text that is not read from a file, as a file at the path
+pub(crate) struct Text(pub(crate) PathBuf, pub(crate) String);
+
+impl Loader for Text {
+    fn into_stream(self) -> IndexingStream {
+        let Text(path, content) = self;
+        IndexingStream::iter([Ok(Node {
+            path,
+            original_size: content.len(),
+            chunk: content,
+            ..Default::default()
+        })])
+    }
+}
+
+pub(crate) fn is_pdf(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "pdf")
+}
+
+/// The text of a PDF, `None` if it cannot be extracted
+///
+/// The extraction panics on some malformed PDFs, those are skipped as well.
+fn extract_pdf(path: &Path) -> Option<String> {
+    match std::panic::catch_unwind(|| pdf_extract::extract_text(path)) {
+        Ok(Ok(text)) => Some(text),
+        Ok(Err(err)) => {
+            tracing::warn!(path = %path.display(), %err, "Skipping PDF, failed to extract its text");
+            None
+        }
+        Err(_) => {
+            tracing::warn!(path = %path.display(), "Skipping PDF, extracting its text panicked");
+            None
+        }
+    }
+}
+
+/// Lists the files the loader will pick up, walking the directory the same way it does
+pub(crate) fn list_files(options: &IndexOptions) -> Vec<PathBuf> {
+    let mut skipped = 0;
+    let files = options
+        .sources
+        .iter()
+        .flat_map(|source| {
+            let (files, tests) = select_source_files(source, options);
+            skipped += tests;
+            files
+        })
+        .collect();
+
+    if options.filter.tests.is_some() {
+        tracing::info!(skipped, "Skipped test and generated files");
+    }
+    files
+}
+
+pub(crate) fn source_files(source: &Source, options: &IndexOptions) -> Vec<PathBuf> {
+    select_source_files(source, options).0
+}
+
+/// The files of the source to index, and how many were skipped as tests or generated code
+fn select_source_files(source: &Source, options: &IndexOptions) -> (Vec<PathBuf>, usize) {
+    if source.text.is_some() {
+        return (vec![source.path.clone()], 0);
+    }
+
+    let files = source.files.clone().unwrap_or_else(|| {
+        ignore::WalkBuilder::new(&source.path)
+            .follow_links(options.follow_symlinks)
+            .build()
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_type().is_some_and(|ft| ft.is_file()))
+            .map(ignore::DirEntry::into_path)
+            .collect()
+    });
+
+    // Resolved once, to check every file resolves to somewhere inside it
+    let root = source
+        .path
+        .canonicalize()
+        .unwrap_or_else(|_| source.path.clone());
+
+    let (files, tests): (Vec<_>, Vec<_>) = files
+        .into_iter()
+        .filter(|path| has_extension(path, &options.extensions))
+        .partition(|path| !options.filter.is_test(&source.path, path));
+
+    let files = files
+        .into_iter()
+        .filter(|path| options.filter.matches(&source.path, path))
+        .filter(|path| is_inside_root(&root, path, options.follow_symlinks))
+        .collect();
+    (files, tests.len())
+}
+
+/// Whether the file is safe to index: not a symlink unless symlinks are followed, and resolving
+/// to a file inside the resolved `root` either way
+fn is_inside_root(root: &Path, path: &Path, follow_symlinks: bool) -> bool {
+    if !follow_symlinks && path.is_symlink() {
+        tracing::debug!(path = %path.display(), "Skipping symlink");
+        return false;
+    }
+
+    let inside = path
+        .canonicalize()
+        .is_ok_and(|resolved| resolved.starts_with(root));
+    if !inside {
+        tracing::warn!(path = %path.display(), "Skipping file outside of the indexed path");
+    }
+    inside
+}
+
+/// Only index `size` files picked at random from all the files of the sources, keeping files
+/// whole
+///
+/// The same seed picks the same files, as long as the files do not change. Without a seed, a
+/// random one is used and logged, so the sample can be repeated.
+pub fn sample_files(options: &mut IndexOptions, size: usize, seed: Option<u64>) {
+    let files = options
+        .sources
+        .iter()
+        .enumerate()
+        .flat_map(|(index, source)| {
+            source_files(source, options)
+                .into_iter()
+                .map(move |path| (index, path))
+        })
+        .collect::<Vec<_>>();
+
+    if size >= files.len() {
+        tracing::info!(
+            size,
+            files = files.len(),
+            "The sample is not smaller than the number of files, indexing all of them"
+        );
+        return;
+    }
+
+    let seed = seed.unwrap_or_else(rand::random);
+    tracing::info!(
+        size,
+        files = files.len(),
+        seed,
+        "Indexing a sample of the files"
+    );
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut sample = files
+        .choose_multiple(&mut rng, size)
+        .cloned()
+        .collect::<Vec<_>>();
+    // Keep the order of the directory walk, as the order of the sample is random
+    sample.sort();
+
+    for (index, source) in options.sources.iter_mut().enumerate() {
+        source.files = Some(
+            sample
+                .iter()
+                .filter(|(source, _)| *source == index)
+                .map(|(_, path)| path.clone())
+                .collect(),
+        );
+    }
+}
+
+fn has_extension(path: &Path, extensions: &[String]) -> bool {
+    path.extension()
+        .is_some_and(|ext| extensions.iter().any(|e| ext == e.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn is_inside_root_skips_symlinks_and_files_outside() {
+        use std::os::unix::fs::symlink;
+
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let root_path = root.path().canonicalize().unwrap();
+        let file = root_path.join("lib.rs");
+        let secret = outside.path().join("secret.rs");
+        std::fs::write(&file, "fn a() {}").unwrap();
+        std::fs::write(&secret, "fn b() {}").unwrap();
+        let link = root_path.join("link.rs");
+        let escape = root_path.join("escape.rs");
+        symlink(&file, &link).unwrap();
+        symlink(&secret, &escape).unwrap();
+
+        assert!(is_inside_root(&root_path, &file, false));
+        assert!(!is_inside_root(&root_path, &link, false));
+        assert!(is_inside_root(&root_path, &link, true));
+        assert!(!is_inside_root(&root_path, &escape, true));
+        assert!(!is_inside_root(&root_path, &secret, true));
+        assert!(!is_inside_root(
+            &root_path,
+            &root_path.join("missing.rs"),
+            true
+        ));
+    }
+}
@@ -0,0 +1,951 @@
+//! The vector stores chunks are indexed into and retrieved from
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use anyhow::{Context as _, Result};
+use async_trait::async_trait;
+use qdrant_client::qdrant::{
+    with_payload_selector::SelectorOptions, Condition, CountPointsBuilder, CreateCollectionBuilder,
+    DeletePointsBuilder, Distance, Filter, Fusion, GetPointsBuilder, HnswConfigDiffBuilder,
+    Modifier, PrefetchQueryBuilder, Query as QdrantQuery, QueryPointsBuilder,
+    ScalarQuantizationBuilder, ScrollPointsBuilder, SearchPointsBuilder, SparseVectorParamsBuilder,
+    SparseVectorsConfigBuilder, VectorInput, VectorParamsBuilder, VectorsConfigBuilder,
+};
+use serde::Serialize;
+use swiftide::{
+    indexing::{EmbeddedField, IndexingStream, Node},
+    integrations::qdrant::Qdrant,
+    traits::{Persist, SparseEmbeddingModel},
+    SparseEmbedding, SparseEmbeddings,
+};
+
+use crate::{chunk::line_range, filter::PathFilter};
+
+/// Default number of chunks embedded in a single request
+pub const EMBED_BATCH_SIZE: usize = 50;
+
+/// Default maximum number of tokens in a single embedding request, OpenAI allows 300k
+pub const EMBED_TOKEN_BUDGET: usize = 250_000;
+
+/// Number of points fetched at once when listing everything in a Qdrant collection
+const SCROLL_BATCH_SIZE: u32 = 1000;
+
+/// Candidates each of the dense and the sparse search contribute to a hybrid search
+const HYBRID_PREFETCH: u64 = 50;
+
+/// Name Swiftide gives the dense vector of a chunk when the collection has named vectors
+const DENSE_VECTOR: &str = "Combined";
+
+/// Name Swiftide gives the sparse vector of a chunk
+const SPARSE_VECTOR: &str = "Combined_sparse";
+
+/// Name Swiftide gives the vector of only the chunk itself
+const CODE_VECTOR: &str = "Chunk";
+
+/// The field the vector of the generated questions and answers of a chunk is stored as, which
+/// Swiftide names `Metadata: summary`
+pub(crate) const SUMMARY_FIELD: &str = "summary";
+const SUMMARY_VECTOR: &str = "Metadata: summary";
+
+/// Where swiftide connects to Qdrant unless `QDRANT_URL` is set
+const DEFAULT_QDRANT_URL: &str = "http://localhost:6334";
+
+/// How long to wait for Qdrant and Redis to respond before giving up on them
+const PREFLIGHT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum StoreKind {
+    Qdrant,
+    Memory,
+}
+
+/// How Qdrant compares the vectors of a collection, set when it is created
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VectorDistance {
+    /// The angle between the vectors, regardless of their length
+    #[default]
+    Cosine,
+    /// The dot product, which equals cosine only for vectors of length one
+    Dot,
+    /// The straight line distance, searched as its negation so higher scores are still better
+    Euclidean,
+}
+
+impl From<VectorDistance> for Distance {
+    fn from(distance: VectorDistance) -> Self {
+        match distance {
+            VectorDistance::Cosine => Distance::Cosine,
+            VectorDistance::Dot => Distance::Dot,
+            VectorDistance::Euclidean => Distance::Euclid,
+        }
+    }
+}
+
+/// Where to index the code and how to treat what is indexed already, see [`index`](crate::index())
+#[derive(Clone, Debug)]
+pub struct StoreConfig {
+    pub store: StoreKind,
+    pub collection_name: String,
+    /// Dimensions of the embeddings, see [`vector_size_for_model`](crate::vector_size_for_model)
+    pub vector_size: u64,
+    /// Index settings, only used when the collection is created
+    pub collection: CollectionConfig,
+    /// Url of the Redis cache that skips files indexed before, `None` indexes every file
+    pub redis_url: Option<String>,
+    /// The models are part of the keys in the cache, see [`cache_prefix`](crate::cache_prefix)
+    pub embed_model: String,
+    pub prompt_model: String,
+    /// Delete the collection and index everything again, ignoring the cache
+    pub force_reindex: bool,
+    /// Skip indexing if the collection exists already
+    pub skip_if_indexed: bool,
+    /// Check that Qdrant and Redis are reachable first, see [`preflight`]
+    pub preflight: bool,
+    pub show_progress: bool,
+    /// Write a [`Manifest`](crate::Manifest) of how the index was built to this file
+    pub manifest: Option<PathBuf>,
+}
+
+/// A chunk found by searching a store
+pub struct Retrieved {
+    pub path: String,
+    pub content: String,
+    /// Similarity to the question by the distance of the collection, higher is better: between -1
+    /// and 1 for cosine, unbounded for dot and the negated distance for euclidean. The reciprocal
+    /// rank fusion of the dense and keyword searches with hybrid search, roughly between 0 and
+    /// 0.03, which is not comparable to a similarity
+    pub score: f32,
+    /// First and last line of the chunk in its file, if they were recorded while indexing
+    pub lines: Option<(u64, u64)>,
+}
+
+impl Retrieved {
+    /// The path of the chunk, followed by its lines as `path:start-end` if known
+    pub fn citation(&self) -> String {
+        match self.lines {
+            Some((start, end)) => format!("{}:{start}-{end}", self.path),
+            None => self.path.clone(),
+        }
+    }
+
+    /// The chunk as it is given to the model for answering, headed by its citation
+    pub(crate) fn context(&self) -> String {
+        format!("## {}\n{}", self.citation(), self.content)
+    }
+}
+
+/// A chunk as it is stored, to inspect what was indexed
+#[derive(Serialize, Debug)]
+pub struct StoredChunk {
+    pub path: String,
+    pub content: String,
+    /// First and last line of the chunk in its file, if they were recorded while indexing
+    pub lines: Option<(u64, u64)>,
+    /// Everything else stored with the chunk
+    pub metadata: BTreeMap<String, serde_json::Value>,
+}
+
+/// Stores the embedded chunks while indexing and searches them while querying
+#[async_trait]
+pub trait VectorStore: Send + Sync {
+    /// The storage step for the indexing pipeline
+    fn persist(&self) -> Box<dyn Persist>;
+
+    /// Whether anything has been indexed already
+    async fn is_indexed(&self) -> Result<bool>;
+
+    /// Creates the store with its configured index settings if it does not exist yet
+    async fn create_if_missing(&self) -> Result<()>;
+
+    /// Removes everything that has been indexed
+    async fn clear(&self) -> Result<()>;
+
+    /// Removes the chunks of the files with these paths
+    async fn delete_paths(&self, paths: &[String]) -> Result<()>;
+
+    /// Number of chunks in the store, zero if it does not exist
+    async fn count(&self) -> Result<u64>;
+
+    /// The path and `content_hash` of every chunk, chunks indexed without a hash have none
+    async fn content_hashes(&self) -> Result<Vec<(String, Option<String>)>>;
+
+    /// Every chunk of the file with exactly this path, in the order of their lines
+    async fn chunks_of(&self, path: &str) -> Result<Vec<StoredChunk>>;
+
+    /// Number of the chunks with these ids that are not in the store
+    async fn missing(&self, ids: &[String]) -> Result<usize>;
+
+    /// Whether chunks are stored with sparse keyword vectors as well, for hybrid search
+    fn hybrid(&self) -> bool;
+
+    /// Whether chunks are stored with a vector of the chunk and one of its questions and answers,
+    /// instead of one of both combined
+    fn named_vectors(&self) -> bool;
+
+    /// The `top_k` chunks most similar to the embedding among those matching the filter, best
+    /// match first
+    ///
+    /// Hybrid stores also match the keywords of `text`, the text that was embedded.
+    async fn search(
+        &self,
+        embedding: Vec<f32>,
+        text: &str,
+        top_k: u64,
+        filter: &PathFilter,
+    ) -> Result<Vec<Retrieved>>;
+
+    /// The model the chunks were embedded with, if any chunk recorded it
+    async fn embed_model(&self) -> Result<Option<String>>;
+}
+
+/// Warns if the chunks in the store were embedded with another model than `embed_model`
+///
+/// Questions embedded with another model than the chunks are compared to vectors of another
+/// space, which retrieves chunks that look random, or fails if their sizes differ.
+pub async fn check_embed_model(store: &dyn VectorStore, embed_model: &str) -> Result<()> {
+    if let Some(indexed_with) = store.embed_model().await? {
+        if indexed_with != embed_model {
+            tracing::warn!(
+                indexed_with,
+                embed_model,
+                "The chunks were embedded with another model than the questions will be, pass \
+                 --embed-model {indexed_with} or index again"
+            );
+        }
+    }
+
+    Ok(())
+}
+
+pub fn build_store(
+    kind: StoreKind,
+    collection_name: &str,
+    vector_size: u64,
+    collection_config: CollectionConfig,
+) -> Result<Box<dyn VectorStore>> {
+    Ok(match kind {
+        StoreKind::Qdrant => Box::new(QdrantStore::new(
+            collection_name,
+            vector_size,
+            collection_config,
+        )?),
+        StoreKind::Memory => Box::new(MemoryVectorStore::default()),
+    })
+}
+
+/// Checks that Qdrant and Redis are reachable before doing any work
+///
+/// Otherwise an unreachable Qdrant only fails deep inside the pipeline, and an unreachable Redis
+/// is only logged by swiftide, which then indexes everything again without a cache.
+pub async fn preflight(store: StoreKind, redis_url: Option<&str>) -> Result<()> {
+    if matches!(store, StoreKind::Qdrant) {
+        let url = std::env::var("QDRANT_URL").unwrap_or(DEFAULT_QDRANT_URL.to_string());
+        let reachable = async {
+            qdrant_client::Qdrant::from_url(&url)
+                .api_key(std::env::var("QDRANT_API_KEY"))
+                .connect_timeout(PREFLIGHT_TIMEOUT)
+                .timeout(PREFLIGHT_TIMEOUT)
+                .build()?
+                .list_collections()
+                .await?;
+            anyhow::Ok(())
+        };
+        reachable.await.with_context(|| {
+            format!("Qdrant is unreachable at {url}, start it or set QDRANT_URL")
+        })?;
+    }
+
+    if let Some(url) = redis_url {
+        let reachable = async {
+            let mut connection = redis::Client::open(url)?
+                .get_multiplexed_async_connection()
+                .await?;
+            redis::cmd("PING")
+                .query_async::<String>(&mut connection)
+                .await?;
+            anyhow::Ok(())
+        };
+        tokio::time::timeout(PREFLIGHT_TIMEOUT, reachable)
+            .await
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("Timed out")))
+            .with_context(|| {
+                format!("Redis is unreachable at {url}, start it, pass --redis-url or --cache none")
+            })?;
+    }
+
+    Ok(())
+}
+
+/// Index settings for new Qdrant collections, Qdrant's defaults are used for anything not set
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CollectionConfig {
+    /// Number of edges per node in the HNSW graph
+    pub hnsw_m: Option<u64>,
+    /// Number of neighbours considered while building the HNSW graph
+    pub hnsw_ef_construct: Option<u64>,
+    /// Store the vectors as int8 as well, which takes a quarter of the memory to search
+    pub scalar_quantization: bool,
+    /// Store a sparse keyword vector next to the dense one and search both
+    ///
+    /// The collection then has a named dense vector `Combined` and a named sparse vector
+    /// `Combined_sparse` with the IDF modifier, instead of a single unnamed vector. A collection
+    /// created without it has to be indexed again, with `--force-reindex`.
+    pub hybrid: bool,
+    /// How the dense vectors are compared
+    pub distance: VectorDistance,
+    /// Store a vector of the chunk and one of its generated questions and answers, instead of
+    /// one of both combined
+    ///
+    /// The collection then has the named vectors `Chunk` and `Metadata: summary`, of which
+    /// `search_vector` is searched. Cannot be combined with `hybrid`.
+    pub named_vectors: bool,
+    /// Which of the named vectors is searched
+    pub search_vector: SearchVector,
+}
+
+/// Which named vector of a collection with `CollectionConfig::named_vectors` is searched
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SearchVector {
+    /// The embedding of the chunk itself
+    #[default]
+    Code,
+    /// The embedding of the questions and answers generated about the chunk
+    Summary,
+}
+
+impl SearchVector {
+    fn vector_name(self) -> &'static str {
+        match self {
+            SearchVector::Code => CODE_VECTOR,
+            SearchVector::Summary => SUMMARY_VECTOR,
+        }
+    }
+}
+
+impl CollectionConfig {
+    fn is_default(&self) -> bool {
+        self.hnsw_m.is_none()
+            && self.hnsw_ef_construct.is_none()
+            && !self.scalar_quantization
+            && !self.hybrid
+            && !self.named_vectors
+            && self.distance == VectorDistance::Cosine
+    }
+}
+
+pub struct QdrantStore {
+    qdrant: Qdrant,
+    collection_name: String,
+    vector_size: u64,
+    config: CollectionConfig,
+}
+
+impl QdrantStore {
+    pub fn new(collection_name: &str, vector_size: u64, config: CollectionConfig) -> Result<Self> {
+        let mut qdrant = Qdrant::builder()
+            .vector_size(vector_size)
+            .collection_name(collection_name)
+            .vector_distance(config.distance.into())
+            .batch_size(EMBED_BATCH_SIZE);
+        if config.hybrid {
+            qdrant = qdrant
+                .with_vector(EmbeddedField::Combined)
+                .with_sparse_vector(EmbeddedField::Combined);
+        } else if config.named_vectors {
+            qdrant = qdrant
+                .with_vector(EmbeddedField::Chunk)
+                .with_vector(EmbeddedField::Metadata(SUMMARY_FIELD.to_string()));
+        }
+
+        Ok(Self {
+            qdrant: qdrant.build()?,
+            collection_name: collection_name.to_string(),
+            vector_size,
+            config,
+        })
+    }
+
+    /// The Swiftide client, for use in Swiftide's query pipelines
+    pub fn qdrant(&self) -> &Qdrant {
+        &self.qdrant
+    }
+}
+
+#[async_trait]
+impl VectorStore for QdrantStore {
+    fn persist(&self) -> Box<dyn Persist> {
+        Box::new(self.qdrant.clone())
+    }
+
+    async fn is_indexed(&self) -> Result<bool> {
+        Ok(self
+            .qdrant
+            .client()
+            .collection_exists(&self.collection_name)
+            .await?)
+    }
+
+    /// Swiftide creates the collection when the pipeline starts, but without a way to configure
+    /// its index. Creating it up front with the same vectors lets Swiftide use it as is.
+    async fn create_if_missing(&self) -> Result<()> {
+        if self.config.is_default() {
+            return Ok(());
+        }
+
+        if self.is_indexed().await? {
+            tracing::warn!(
+                collection_name = self.collection_name,
+                "Collection exists, index settings only apply to new collections"
+            );
+            return Ok(());
+        }
+
+        let mut hnsw = HnswConfigDiffBuilder::default();
+        if let Some(m) = self.config.hnsw_m {
+            hnsw = hnsw.m(m);
+        }
+        if let Some(ef_construct) = self.config.hnsw_ef_construct {
+            hnsw = hnsw.ef_construct(ef_construct);
+        }
+
+        let dense = VectorParamsBuilder::new(self.vector_size, self.config.distance.into());
+        let mut collection = CreateCollectionBuilder::new(&self.collection_name).hnsw_config(hnsw);
+        if self.config.hybrid {
+            // Named like Swiftide names them, with Qdrant weighing keywords by how rare they are
+            let mut vectors = VectorsConfigBuilder::default();
+            vectors.add_named_vector_params(DENSE_VECTOR, dense);
+            let mut sparse_vectors = SparseVectorsConfigBuilder::default();
+            sparse_vectors.add_named_vector_params(
+                SPARSE_VECTOR,
+                SparseVectorParamsBuilder::default().modifier(Modifier::Idf as i32),
+            );
+            collection = collection
+                .vectors_config(vectors)
+                .sparse_vectors_config(sparse_vectors);
+        } else if self.config.named_vectors {
+            let mut vectors = VectorsConfigBuilder::default();
+            let dense = dense.build();
+            vectors.add_named_vector_params(CODE_VECTOR, dense);
+            vectors.add_named_vector_params(SUMMARY_VECTOR, dense);
+            collection = collection.vectors_config(vectors);
+        } else {
+            collection = collection.vectors_config(dense);
+        }
+        if self.config.scalar_quantization {
+            collection = collection.quantization_config(ScalarQuantizationBuilder::default());
+        }
+
+        tracing::info!(collection_name = self.collection_name, config = ?self.config, "Creating collection");
+        self.qdrant.client().create_collection(collection).await?;
+
+        Ok(())
+    }
+
+    async fn clear(&self) -> Result<()> {
+        // Nothing to clear yet, but failing to reach Qdrant must not pass for a cleared collection
+        if !self.is_indexed().await.with_context(|| {
+            format!(
+                "Failed to check if collection {} exists",
+                self.collection_name
+            )
+        })? {
+            return Ok(());
+        }
+
+        tracing::info!(
+            collection_name = self.collection_name,
+            "Deleting collection"
+        );
+        self.qdrant
+            .client()
+            .delete_collection(&self.collection_name)
+            .await
+            .with_context(|| format!("Failed to delete collection {}", self.collection_name))?;
+
+        Ok(())
+    }
+
+    async fn delete_paths(&self, paths: &[String]) -> Result<()> {
+        self.qdrant
+            .client()
+            .delete_points(
+                DeletePointsBuilder::new(&self.collection_name)
+                    .points(Filter::must([Condition::matches("path", paths.to_vec())]))
+                    .wait(true),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn count(&self) -> Result<u64> {
+        if !self.is_indexed().await? {
+            return Ok(0);
+        }
+
+        let response = self
+            .qdrant
+            .client()
+            .count(CountPointsBuilder::new(&self.collection_name).exact(true))
+            .await?;
+        Ok(response.result.map_or(0, |result| result.count))
+    }
+
+    async fn embed_model(&self) -> Result<Option<String>> {
+        if !self.is_indexed().await? {
+            return Ok(None);
+        }
+
+        let scroll = ScrollPointsBuilder::new(&self.collection_name)
+            .limit(1)
+            .with_payload(SelectorOptions::Include(
+                vec!["embed_model".to_string()].into(),
+            ));
+        let response = self.qdrant.client().scroll(scroll).await?;
+
+        Ok(response.result.into_iter().next().and_then(|point| {
+            point
+                .payload
+                .get("embed_model")
+                .and_then(|model| model.as_str())
+                .cloned()
+        }))
+    }
+
+    async fn content_hashes(&self) -> Result<Vec<(String, Option<String>)>> {
+        if !self.is_indexed().await? {
+            return Ok(Vec::new());
+        }
+
+        let mut hashes = Vec::new();
+        let mut offset = None;
+        loop {
+            let mut scroll = ScrollPointsBuilder::new(&self.collection_name)
+                .limit(SCROLL_BATCH_SIZE)
+                .with_payload(SelectorOptions::Include(
+                    vec!["path".to_string(), "content_hash".to_string()].into(),
+                ));
+            if let Some(offset) = offset {
+                scroll = scroll.offset(offset);
+            }
+
+            let response = self.qdrant.client().scroll(scroll).await?;
+            hashes.extend(response.result.into_iter().filter_map(|point| {
+                let path = point.payload.get("path")?.as_str()?.clone();
+                let hash = point
+                    .payload
+                    .get("content_hash")
+                    .and_then(|hash| hash.as_str())
+                    .cloned();
+                Some((path, hash))
+            }));
+
+            offset = response.next_page_offset;
+            if offset.is_none() {
+                return Ok(hashes);
+            }
+        }
+    }
+
+    async fn chunks_of(&self, path: &str) -> Result<Vec<StoredChunk>> {
+        if !self.is_indexed().await? {
+            return Ok(Vec::new());
+        }
+
+        let mut chunks = Vec::new();
+        let mut offset = None;
+        loop {
+            let mut scroll = ScrollPointsBuilder::new(&self.collection_name)
+                .limit(SCROLL_BATCH_SIZE)
+                .filter(Filter::must([Condition::matches("path", path.to_string())]))
+                .with_payload(true);
+            if let Some(offset) = offset {
+                scroll = scroll.offset(offset);
+            }
+
+            let response = self.qdrant.client().scroll(scroll).await?;
+            chunks.extend(response.result.into_iter().map(|point| {
+                stored_chunk(
+                    point
+                        .payload
+                        .into_iter()
+                        .map(|(key, value)| (key, serde_json::Value::from(value)))
+                        .collect(),
+                )
+            }));
+
+            offset = response.next_page_offset;
+            if offset.is_none() {
+                break;
+            }
+        }
+
+        chunks.sort_by_key(|chunk| chunk.lines);
+        Ok(chunks)
+    }
+
+    async fn missing(&self, ids: &[String]) -> Result<usize> {
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        if !self.is_indexed().await? {
+            return Ok(ids.len());
+        }
+
+        let mut found = 0;
+        for batch in ids.chunks(SCROLL_BATCH_SIZE as usize) {
+            let get = GetPointsBuilder::new(
+                &self.collection_name,
+                batch.iter().cloned().map(Into::into).collect::<Vec<_>>(),
+            )
+            .with_payload(false)
+            .with_vectors(false);
+            found += self.qdrant.client().get_points(get).await?.result.len();
+        }
+
+        Ok(ids.len() - found)
+    }
+
+    fn hybrid(&self) -> bool {
+        self.config.hybrid
+    }
+
+    fn named_vectors(&self) -> bool {
+        self.config.named_vectors
+    }
+
+    async fn search(
+        &self,
+        embedding: Vec<f32>,
+        text: &str,
+        top_k: u64,
+        filter: &PathFilter,
+    ) -> Result<Vec<Retrieved>> {
+        // Without a full-text index on the path, Qdrant matches text as a plain substring
+        let substrings = filter.required_substrings();
+        let path_filter = (!substrings.is_empty()).then(|| {
+            Filter::must(
+                substrings
+                    .into_iter()
+                    .map(|substring| Condition::matches_text("path", substring)),
+            )
+        });
+
+        let points = if self.config.hybrid {
+            // Both searches get their own candidates, fused by their ranks as their scores are
+            // not comparable. The fused score is a reciprocal rank, not a cosine similarity.
+            let sparse = KeywordSparse::embed(text);
+            let prefetch_limit = top_k.max(HYBRID_PREFETCH);
+            let mut sparse_prefetch = PrefetchQueryBuilder::default()
+                .query(QdrantQuery::new_nearest(VectorInput::new_sparse(
+                    sparse.indices,
+                    sparse.values,
+                )))
+                .using(SPARSE_VECTOR)
+                .limit(prefetch_limit);
+            let mut dense_prefetch = PrefetchQueryBuilder::default()
+                .query(QdrantQuery::new_nearest(embedding))
+                .using(DENSE_VECTOR)
+                .limit(prefetch_limit);
+            if let Some(path_filter) = &path_filter {
+                sparse_prefetch = sparse_prefetch.filter(path_filter.clone());
+                dense_prefetch = dense_prefetch.filter(path_filter.clone());
+            }
+
+            let query = QueryPointsBuilder::new(&self.collection_name)
+                .with_payload(true)
+                .add_prefetch(sparse_prefetch)
+                .add_prefetch(dense_prefetch)
+                .query(QdrantQuery::new_fusion(Fusion::Rrf))
+                .limit(top_k);
+            self.qdrant.client().query(query).await?.result
+        } else {
+            let mut search = SearchPointsBuilder::new(&self.collection_name, embedding, top_k)
+                .with_payload(true);
+            if let Some(path_filter) = path_filter {
+                search = search.filter(path_filter);
+            }
+            if self.config.named_vectors {
+                search = search.vector_name(self.config.search_vector.vector_name());
+            }
+            self.qdrant.client().search_points(search).await?.result
+        };
+
+        // Swiftide's indexing pipeline stores the path and chunk in the payload
+        Ok(points
+            .into_iter()
+            .filter_map(|point| {
+                let line = |key: &str| {
+                    let line = point.payload.get(key)?.as_integer()?;
+                    u64::try_from(line).ok()
+                };
+
+                Some(Retrieved {
+                    path: point.payload.get("path")?.as_str()?.clone(),
+                    content: point.payload.get("content")?.as_str()?.clone(),
+                    score: match self.config.distance {
+                        VectorDistance::Euclidean if !self.config.hybrid => -point.score,
+                        _ => point.score,
+                    },
+                    lines: line("start_line").zip(line("end_line")),
+                })
+            })
+            .filter(|retrieved| filter.matches(&retrieved.path))
+            .collect())
+    }
+}
+
+/// Keeps the nodes in memory and searches them by brute force cosine similarity
+///
+/// Nothing is kept between runs, but it needs no running services, which makes it handy for
+/// trying things out.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryVectorStore {
+    nodes: Arc<RwLock<Vec<Node>>>,
+}
+
+#[async_trait]
+impl Persist for MemoryVectorStore {
+    async fn setup(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn store(&self, node: Node) -> Result<Node> {
+        self.nodes.write().unwrap().push(node.clone());
+        Ok(node)
+    }
+
+    async fn batch_store(&self, nodes: Vec<Node>) -> IndexingStream {
+        self.nodes.write().unwrap().extend(nodes.iter().cloned());
+        nodes.into()
+    }
+}
+
+#[async_trait]
+impl VectorStore for MemoryVectorStore {
+    fn persist(&self) -> Box<dyn Persist> {
+        Box::new(self.clone())
+    }
+
+    async fn is_indexed(&self) -> Result<bool> {
+        Ok(!self.nodes.read().unwrap().is_empty())
+    }
+
+    async fn create_if_missing(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn clear(&self) -> Result<()> {
+        self.nodes.write().unwrap().clear();
+        Ok(())
+    }
+
+    async fn delete_paths(&self, paths: &[String]) -> Result<()> {
+        self.nodes
+            .write()
+            .unwrap()
+            .retain(|node| !paths.contains(&node.path.to_string_lossy().to_string()));
+        Ok(())
+    }
+
+    async fn count(&self) -> Result<u64> {
+        Ok(self.nodes.read().unwrap().len() as u64)
+    }
+
+    async fn embed_model(&self) -> Result<Option<String>> {
+        Ok(self.nodes.read().unwrap().first().and_then(|node| {
+            node.metadata
+                .get("embed_model")
+                .and_then(|model| model.as_str())
+                .map(str::to_string)
+        }))
+    }
+
+    async fn content_hashes(&self) -> Result<Vec<(String, Option<String>)>> {
+        Ok(self
+            .nodes
+            .read()
+            .unwrap()
+            .iter()
+            .map(|node| {
+                let hash = node
+                    .metadata
+                    .get("content_hash")
+                    .and_then(|hash| hash.as_str())
+                    .map(ToString::to_string);
+                (node.path.to_string_lossy().to_string(), hash)
+            })
+            .collect())
+    }
+
+    async fn chunks_of(&self, path: &str) -> Result<Vec<StoredChunk>> {
+        let mut chunks = self
+            .nodes
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|node| node.path.to_string_lossy() == path)
+            .map(|node| {
+                let mut payload = node
+                    .metadata
+                    .iter()
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect::<BTreeMap<_, _>>();
+                payload.insert("path".to_string(), path.into());
+                payload.insert("content".to_string(), node.chunk.clone().into());
+                stored_chunk(payload)
+            })
+            .collect::<Vec<_>>();
+
+        chunks.sort_by_key(|chunk| chunk.lines);
+        Ok(chunks)
+    }
+
+    async fn missing(&self, ids: &[String]) -> Result<usize> {
+        let stored = self
+            .nodes
+            .read()
+            .unwrap()
+            .iter()
+            .map(|node| node.id().to_string())
+            .collect::<HashSet<_>>();
+        Ok(ids.iter().filter(|id| !stored.contains(*id)).count())
+    }
+
+    fn hybrid(&self) -> bool {
+        false
+    }
+
+    fn named_vectors(&self) -> bool {
+        false
+    }
+
+    async fn search(
+        &self,
+        embedding: Vec<f32>,
+        _text: &str,
+        top_k: u64,
+        filter: &PathFilter,
+    ) -> Result<Vec<Retrieved>> {
+        let nodes = self.nodes.read().unwrap();
+
+        // A node can have multiple embeddings depending on the embed mode, the best one counts
+        let mut scored = nodes
+            .iter()
+            .filter(|node| filter.matches(&node.path.to_string_lossy()))
+            .filter_map(|node| {
+                let score = node
+                    .vectors
+                    .as_ref()?
+                    .values()
+                    .map(|vector| cosine_similarity(&embedding, vector))
+                    .reduce(f32::max)?;
+                Some((score, node))
+            })
+            .collect::<Vec<_>>();
+        scored.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+
+        Ok(scored
+            .into_iter()
+            .take(usize::try_from(top_k).unwrap_or(usize::MAX))
+            .map(|(score, node)| Retrieved {
+                path: node.path.to_string_lossy().to_string(),
+                content: node.chunk.clone(),
+                score,
+                lines: line_range(node),
+            })
+            .collect())
+    }
+}
+
+/// Splits the payload of a chunk into the chunk and the rest of its metadata
+fn stored_chunk(mut payload: BTreeMap<String, serde_json::Value>) -> StoredChunk {
+    let mut take_string = |key: &str| match payload.remove(key) {
+        Some(serde_json::Value::String(value)) => value,
+        _ => String::new(),
+    };
+    let path = take_string("path");
+    let content = take_string("content");
+
+    let line = |key: &str| payload.get(key).and_then(serde_json::Value::as_u64);
+    let lines = line("start_line").zip(line("end_line"));
+
+    StoredChunk {
+        path,
+        content,
+        lines,
+        metadata: payload,
+    }
+}
+
+pub(crate) fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let norm = |vector: &[f32]| vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let dot = a.iter().zip(b).map(|(a, b)| a * b).sum::<f32>();
+
+    dot / (norm(a) * norm(b)).max(f32::EPSILON)
+}
+
+/// Embeds text as the words in it, for the keyword half of hybrid search
+///
+/// Every word of at least two letters or digits is a dimension at the FNV-1a hash of the word in
+/// lower case, weighted by one plus the log of how often it occurs. How rare a word is across the
+/// chunks is left to Qdrant's IDF modifier, so no model or vocabulary is needed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KeywordSparse;
+
+impl KeywordSparse {
+    pub fn embed(text: &str) -> SparseEmbedding {
+        let mut counts = BTreeMap::<u32, u32>::new();
+        for word in text
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| word.chars().count() >= 2)
+        {
+            *counts.entry(fnv1a(&word.to_lowercase())).or_default() += 1;
+        }
+
+        let (indices, values) = counts
+            .into_iter()
+            .map(|(index, count)| (index, 1.0 + (count as f32).ln()))
+            .unzip();
+        SparseEmbedding { indices, values }
+    }
+}
+
+#[async_trait]
+impl SparseEmbeddingModel for KeywordSparse {
+    async fn sparse_embed(&self, input: Vec<String>) -> Result<SparseEmbeddings> {
+        Ok(input.iter().map(|text| Self::embed(text)).collect())
+    }
+}
+
+fn fnv1a(word: &str) -> u32 {
+    word.bytes().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyword_sparse_counts_words_regardless_of_case() {
+        // Known FNV-1a hashes keep the dimensions stable across releases
+        assert_eq!(fnv1a("a"), 0xe40c_292c);
+        assert_eq!(fnv1a("foobar"), 0xbf9c_f968);
+
+        let embedding = KeywordSparse::embed("Parse the parse, a x! PARSE");
+        let mut expected = vec![(fnv1a("parse"), 1. + 3_f32.ln()), (fnv1a("the"), 1.)];
+        expected.sort_by_key(|(index, _)| *index);
+        let (indices, values): (Vec<_>, Vec<_>) = expected.into_iter().unzip();
+        assert_eq!(embedding.indices, indices);
+        assert_eq!(embedding.values, values);
+
+        assert!(KeywordSparse::embed("a ! b").indices.is_empty());
+    }
+}
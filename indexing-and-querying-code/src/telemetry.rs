@@ -0,0 +1,90 @@
+//! Tracing and OpenTelemetry export
+
+use anyhow::{Context as _, Result};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::WithExportConfig as _;
+use tracing_subscriber::{layer::SubscriberExt as _, util::SubscriberInitExt as _};
+
+/// Logs to stdout at info level, or warn, debug or trace depending on the verbosity flags
+///
+/// `RUST_LOG` is still respected. Its directives for specific targets always apply, the level of
+/// the flags only replaces its default level if a flag is given.
+///
+/// With `json`, every event is a line of json instead, with the fields of the spans it happened
+/// in. With an `otel_endpoint`, spans are also exported to it with OTLP over gRPC. Keep the
+/// returned guard until the end, dropping it exports the remaining spans.
+pub fn init_tracing(
+    verbose: u8,
+    quiet: bool,
+    json: bool,
+    otel_endpoint: Option<&str>,
+) -> Result<TracingGuard> {
+    let level = match (quiet, verbose) {
+        (true, _) => Some(tracing::Level::WARN),
+        (false, 0) => None,
+        (false, 1) => Some(tracing::Level::DEBUG),
+        (false, _) => Some(tracing::Level::TRACE),
+    };
+
+    let mut filter = tracing_subscriber::EnvFilter::builder()
+        .with_default_directive(tracing::Level::INFO.into())
+        .from_env_lossy();
+    if let Some(level) = level {
+        filter = filter.add_directive(level.into());
+    }
+
+    let provider = otel_endpoint
+        .map(|endpoint| {
+            opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .tonic()
+                        .with_endpoint(endpoint),
+                )
+                .with_trace_config(opentelemetry_sdk::trace::Config::default().with_resource(
+                    opentelemetry_sdk::Resource::new([opentelemetry::KeyValue::new(
+                        "service.name",
+                        env!("CARGO_PKG_NAME"),
+                    )]),
+                ))
+                .install_batch(opentelemetry_sdk::runtime::Tokio)
+        })
+        .transpose()
+        .context("Failed to set up exporting traces")?;
+    let otel = provider.as_ref().map(|provider| {
+        tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME")))
+    });
+
+    let (text, json) = if json {
+        let json = tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true);
+        (None, Some(json))
+    } else {
+        (Some(tracing_subscriber::fmt::layer()), None)
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(text)
+        .with(json)
+        .with(otel)
+        .init();
+
+    Ok(TracingGuard(provider))
+}
+
+/// Exports the spans that have not been exported yet when dropped
+pub struct TracingGuard(Option<opentelemetry_sdk::trace::TracerProvider>);
+
+impl Drop for TracingGuard {
+    fn drop(&mut self) {
+        if let Some(provider) = &self.0 {
+            if let Err(err) = provider.shutdown() {
+                eprintln!("Failed to export traces: {err}");
+            }
+        }
+    }
+}
@@ -0,0 +1,317 @@
+//! Timings of the indexing phases
+
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::Poll,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use swiftide::{
+    indexing::{IndexingDefaults, IndexingStream, Node},
+    traits::{
+        BatchableTransformer, ChunkerTransformer, Loader, Persist, Transformer,
+        WithBatchIndexingDefaults, WithIndexingDefaults,
+    },
+};
+use tokio_stream::{Stream, StreamExt as _};
+use tracing::Instrument as _;
+
+/// The phases of indexing that are timed, in the order they run
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Phase {
+    Load,
+    Chunk,
+    Metadata,
+    Embed,
+    Store,
+}
+
+impl Phase {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Phase::Load => "load",
+            Phase::Chunk => "chunk",
+            Phase::Metadata => "metadata",
+            Phase::Embed => "embed",
+            Phase::Store => "store",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct PhaseTiming {
+    elapsed: Duration,
+    nodes: usize,
+    /// Only counted for steps that process nodes in batches
+    batches: usize,
+}
+
+/// Time spent in each phase of indexing, summed over all calls
+///
+/// The pipeline runs the phases concurrently, so the sum of the phases can exceed the wall time.
+#[derive(Debug, Default)]
+pub(crate) struct Timings {
+    phases: Mutex<BTreeMap<Phase, PhaseTiming>>,
+}
+
+impl Timings {
+    fn record(&self, phase: Phase, elapsed: Duration, nodes: usize, batched: bool) {
+        let mut phases = self.phases.lock().unwrap();
+        let timing = phases.entry(phase).or_default();
+        timing.elapsed += elapsed;
+        timing.nodes += nodes;
+        timing.batches += usize::from(batched);
+    }
+
+    pub(crate) fn print_summary(&self, total: Duration) {
+        let phases = self.phases.lock().unwrap();
+
+        eprintln!();
+        eprintln!("Indexed in {total:.2?}, phases are summed over concurrent calls");
+        eprintln!(
+            "{:<8}  {:>10}  {:>7}  {:>7}  {:>10}",
+            "PHASE", "TIME", "NODES", "BATCHES", "AVG BATCH"
+        );
+        for (phase, timing) in phases.iter() {
+            let (batches, avg_batch) = if timing.batches > 0 {
+                let avg_batch = timing.elapsed / u32::try_from(timing.batches).unwrap_or(u32::MAX);
+                (timing.batches.to_string(), format!("{avg_batch:.2?}"))
+            } else {
+                (String::new(), String::new())
+            };
+
+            eprintln!(
+                "{:<8}  {:>10}  {:>7}  {batches:>7}  {avg_batch:>10}",
+                phase.name(),
+                format!("{:.2?}", timing.elapsed),
+                timing.nodes,
+            );
+        }
+    }
+}
+
+/// Records the time spent in the wrapped loader, transformer or storage in the timings
+#[derive(Debug)]
+pub(crate) struct Timed<T> {
+    inner: T,
+    phase: Phase,
+    timings: Arc<Timings>,
+}
+
+impl<T> Timed<T> {
+    pub(crate) fn new(phase: Phase, timings: &Arc<Timings>, inner: T) -> Self {
+        Self {
+            inner,
+            phase,
+            timings: Arc::clone(timings),
+        }
+    }
+
+    /// Runs a call of the wrapped step in a span named after the phase, and records how long it
+    /// took in the span and the timings
+    ///
+    /// The path is that of the node, for steps called with a single node.
+    async fn time<R>(
+        &self,
+        nodes: usize,
+        path: Option<&Path>,
+        batch: bool,
+        call: impl Future<Output = R>,
+    ) -> R {
+        let span = tracing::info_span!(
+            "phase",
+            otel.name = self.phase.name(),
+            phase = self.phase.name(),
+            path = path.map(|path| tracing::field::display(path.display())),
+            nodes,
+            latency_ms = tracing::field::Empty
+        );
+
+        let started = Instant::now();
+        let result = call.instrument(span.clone()).await;
+        let elapsed = started.elapsed();
+
+        span.record("latency_ms", elapsed_ms(elapsed));
+        self.timings.record(self.phase, elapsed, nodes, batch);
+
+        result
+    }
+
+    /// What failed if the call for these nodes fails
+    fn failed(&self, nodes: &[&Node]) -> FailedStep {
+        FailedStep {
+            phase: self.phase,
+            paths: nodes.iter().map(|node| node.path.clone()).collect(),
+        }
+    }
+}
+
+/// Adds what failed to the errors in the stream
+fn with_failed_context(nodes: IndexingStream, failed: FailedStep) -> IndexingStream {
+    let nodes: Pin<Box<dyn Stream<Item = Result<Node>> + Send>> =
+        Box::pin(nodes.map(move |node| node.map_err(|err| err.context(failed.clone()))));
+
+    nodes.into()
+}
+
+/// The context of errors in the indexing steps, to report which files failed in which phase
+#[derive(Clone, Debug)]
+pub(crate) struct FailedStep {
+    pub(crate) phase: Phase,
+    pub(crate) paths: Vec<PathBuf>,
+}
+
+impl std::fmt::Display for FailedStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let paths = self
+            .paths
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect::<Vec<_>>();
+        write!(f, "Failed to {} {}", self.phase.name(), paths.join(", "))
+    }
+}
+
+pub(crate) fn elapsed_ms(elapsed: Duration) -> u64 {
+    u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX)
+}
+
+impl<T: Loader> Loader for Timed<T> {
+    fn into_stream(self) -> IndexingStream {
+        let stream: Pin<Box<dyn Stream<Item = Result<Node>> + Send>> = Box::pin(TimedStream {
+            inner: self.inner.into_stream(),
+            phase: self.phase,
+            timings: self.timings,
+        });
+
+        stream.into()
+    }
+}
+
+/// Loaders do their work while the stream is polled, so that is what gets timed
+struct TimedStream {
+    inner: IndexingStream,
+    phase: Phase,
+    timings: Arc<Timings>,
+}
+
+impl Stream for TimedStream {
+    type Item = Result<Node>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let started = Instant::now();
+        let poll = Pin::new(&mut self.inner).poll_next(cx);
+        let nodes = usize::from(matches!(poll, Poll::Ready(Some(_))));
+        self.timings
+            .record(self.phase, started.elapsed(), nodes, false);
+
+        poll
+    }
+}
+
+#[async_trait]
+impl<T: Transformer> Transformer for Timed<T> {
+    async fn transform_node(&self, node: Node) -> Result<Node> {
+        let failed = self.failed(&[&node]);
+        let path = node.path.clone();
+        self.time(1, Some(&path), false, self.inner.transform_node(node))
+            .await
+            .map_err(|err| err.context(failed))
+    }
+
+    fn concurrency(&self) -> Option<usize> {
+        self.inner.concurrency()
+    }
+
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+}
+
+impl<T: WithIndexingDefaults> WithIndexingDefaults for Timed<T> {
+    fn with_indexing_defaults(&mut self, indexing_defaults: IndexingDefaults) {
+        self.inner.with_indexing_defaults(indexing_defaults);
+    }
+}
+
+#[async_trait]
+impl<T: ChunkerTransformer> ChunkerTransformer for Timed<T> {
+    async fn transform_node(&self, node: Node) -> IndexingStream {
+        let failed = self.failed(&[&node]);
+        let path = node.path.clone();
+        let chunks = self
+            .time(1, Some(&path), false, self.inner.transform_node(node))
+            .await;
+
+        with_failed_context(chunks, failed)
+    }
+
+    fn concurrency(&self) -> Option<usize> {
+        self.inner.concurrency()
+    }
+
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+}
+
+#[async_trait]
+impl<T: BatchableTransformer> BatchableTransformer for Timed<T> {
+    async fn batch_transform(&self, nodes: Vec<Node>) -> IndexingStream {
+        let failed = self.failed(&nodes.iter().collect::<Vec<_>>());
+        let nodes = self
+            .time(nodes.len(), None, true, self.inner.batch_transform(nodes))
+            .await;
+
+        with_failed_context(nodes, failed)
+    }
+
+    fn concurrency(&self) -> Option<usize> {
+        self.inner.concurrency()
+    }
+
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+}
+
+impl<T: WithBatchIndexingDefaults> WithBatchIndexingDefaults for Timed<T> {
+    fn with_indexing_defaults(&mut self, indexing_defaults: IndexingDefaults) {
+        self.inner.with_indexing_defaults(indexing_defaults);
+    }
+}
+
+#[async_trait]
+impl<T: Persist> Persist for Timed<T> {
+    async fn setup(&self) -> Result<()> {
+        self.inner.setup().await
+    }
+
+    async fn store(&self, node: Node) -> Result<Node> {
+        let path = node.path.clone();
+        self.time(1, Some(&path), false, self.inner.store(node))
+            .await
+    }
+
+    async fn batch_store(&self, nodes: Vec<Node>) -> IndexingStream {
+        self.time(nodes.len(), None, true, self.inner.batch_store(nodes))
+            .await
+    }
+
+    fn batch_size(&self) -> Option<usize> {
+        self.inner.batch_size()
+    }
+
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+}
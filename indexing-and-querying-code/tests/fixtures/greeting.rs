@@ -0,0 +1,4 @@
+/// Greets someone by their name
+pub fn greet(name: &str) -> String {
+    format!("Hello, {name}! Nice to meet you.")
+}
@@ -0,0 +1,208 @@
+//! Indexes the fixtures into Qdrant, with Redis as the cache, and answers questions about them
+//!
+//! Docker runs Qdrant and Redis, so these only run with `cargo test --features integration`.
+//! The LLM is faked with embeddings of the words in the text, so no API key is needed and the
+//! retrieved chunks are predictable.
+#![cfg(feature = "integration")]
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use indexing_and_querying_code::{
+    index, parse_language, query, resolve_extensions, AnswerFormat, AnswerTemplate,
+    CollectionConfig, EmbedMetadata, FileFilter, IndexOptions, Llm, MarkdownSplit, OnEmpty,
+    PathFilter, QueryContext, QueryMode, Source, StoreConfig, StoreKind, StreamingPrompt,
+    DEFAULT_NUM_SUBQUESTIONS, EMBED_BATCH_SIZE,
+};
+use swiftide::{
+    prompt::Prompt,
+    traits::{EmbeddingModel, SimplePrompt},
+    Embeddings,
+};
+use testcontainers_modules::{
+    redis::{Redis, REDIS_PORT},
+    testcontainers::{
+        core::{IntoContainerPort as _, WaitFor},
+        runners::AsyncRunner as _,
+        GenericImage,
+    },
+};
+
+const VECTOR_SIZE: usize = 256;
+const QDRANT_GRPC_PORT: u16 = 6334;
+
+/// Embeds text as the counts of its words, hashed into the dimensions of the vector
+#[derive(Debug)]
+struct FakeLlm;
+
+fn embed_words(text: &str) -> Vec<f32> {
+    let mut vector = vec![0.; VECTOR_SIZE];
+    for word in text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+    {
+        // FNV-1a, so the dimensions are the same on every run
+        let hash = word
+            .to_lowercase()
+            .bytes()
+            .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+            });
+        vector[(hash % VECTOR_SIZE as u64) as usize] += 1.;
+    }
+    vector
+}
+
+#[async_trait]
+impl EmbeddingModel for FakeLlm {
+    async fn embed(&self, input: Vec<String>) -> Result<Embeddings> {
+        Ok(input.iter().map(|text| embed_words(text)).collect())
+    }
+}
+
+#[async_trait]
+impl SimplePrompt for FakeLlm {
+    async fn prompt(&self, _prompt: Prompt) -> Result<String> {
+        Ok("Fake".to_string())
+    }
+}
+
+#[async_trait]
+impl StreamingPrompt for FakeLlm {
+    async fn prompt_streaming(
+        &self,
+        prompt: Prompt,
+        on_token: &mut (dyn for<'a> FnMut(&'a str) + Send),
+    ) -> Result<String> {
+        let answer = self.prompt(prompt).await?;
+        on_token(&answer);
+        Ok(answer)
+    }
+}
+
+#[tokio::test]
+async fn answers_from_the_indexed_fixtures() -> Result<()> {
+    let qdrant = GenericImage::new("qdrant/qdrant", "v1.9.2")
+        .with_exposed_port(QDRANT_GRPC_PORT.tcp())
+        .with_wait_for(WaitFor::message_on_stdout("gRPC listening"))
+        .start()
+        .await?;
+    let redis = Redis::default().start().await?;
+
+    // Swiftide's Qdrant client connects to the url in the environment
+    std::env::set_var(
+        "QDRANT_URL",
+        format!(
+            "http://localhost:{}",
+            qdrant.get_host_port_ipv4(QDRANT_GRPC_PORT).await?
+        ),
+    );
+    let redis_url = format!(
+        "redis://localhost:{}",
+        redis.get_host_port_ipv4(REDIS_PORT).await?
+    );
+
+    let language = parse_language("rust")?;
+    let fixtures = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+    let options = IndexOptions {
+        language,
+        sources: vec![Source::resolve(fixtures, None).await?],
+        extensions: resolve_extensions(language, &[], &[], None)?,
+        docs_extensions: Vec::new(),
+        filter: FileFilter::new(&[], &[])?,
+        chunk_range: 10..2048,
+        chunk_overlap: 0,
+        code_concurrency: 4,
+        markdown_concurrency: 4,
+        embed_batch_size: EMBED_BATCH_SIZE,
+        export: None,
+        git_metadata: false,
+        timings: false,
+        prune_deleted: false,
+        prune: false,
+        error_report: None,
+        resume_from: None,
+        embed_metadata: EmbedMetadata::Include,
+        strict: false,
+        max_files: None,
+        log_per_file: false,
+        embeddings_parquet: None,
+        follow_symlinks: false,
+        embed_model: None,
+        qa_code_prompt: None,
+        qa_text_prompt: None,
+        language_chunk_ranges: false,
+        markdown_split: MarkdownSplit::Size,
+        markdown_heading_level: 2,
+    };
+    let config = StoreConfig {
+        store: StoreKind::Qdrant,
+        collection_name: "fixtures".to_string(),
+        vector_size: VECTOR_SIZE as u64,
+        collection: CollectionConfig::default(),
+        redis_url: Some(redis_url),
+        embed_model: "fake".to_string(),
+        prompt_model: "fake".to_string(),
+        force_reindex: false,
+        skip_if_indexed: false,
+        preflight: true,
+        show_progress: false,
+        manifest: None,
+    };
+    let llm = Llm {
+        embed: Arc::new(FakeLlm),
+        prompt: Arc::new(FakeLlm),
+        stream: Arc::new(FakeLlm),
+    };
+
+    let (store, stats) = index(&options, &config, &llm).await?;
+    assert_eq!(stats.errors, 0);
+    assert!(stats.points > 0);
+    assert!(store.is_indexed().await?);
+
+    let chunks = store.chunks_of(&format!("{fixtures}/greeting.rs")).await?;
+    assert!(!chunks.is_empty(), "Expected the chunks of greeting.rs");
+
+    let context = QueryContext {
+        llm,
+        prompt_model: "fake".to_string(),
+        stores: vec![store],
+        top_k: 1,
+        language,
+        stream: false,
+        rerank: false,
+        dedup_threshold: 1.,
+        token_budget: None,
+        query_mode: QueryMode::Raw,
+        path_filter: PathFilter::new(None, None)?,
+        answer_template: AnswerTemplate::default(),
+        show_rewrite: false,
+        cohere_rerank: None,
+        explain: false,
+        num_subquestions: DEFAULT_NUM_SUBQUESTIONS,
+        min_score: None,
+        on_empty: OnEmpty::Refuse,
+        query_cache: None,
+        verbatim_code: false,
+        answer_format: AnswerFormat::Prose,
+        max_answer_words: None,
+    };
+
+    let code = query(&context, "How do I greet someone by their name?").await?;
+    assert_eq!(code.answer, "Fake");
+    assert!(
+        code.sources[0].location.contains("greeting.rs"),
+        "Expected the code, got {:?}",
+        code.sources
+    );
+
+    let markdown = query(&context, "How do I install the widgets?").await?;
+    assert!(
+        markdown.sources[0].location.contains("README.md"),
+        "Expected the markdown, got {:?}",
+        markdown.sources
+    );
+
+    Ok(())
+}